@@ -18,13 +18,16 @@ use raytrace_rs::RenderBuffer;
 
 use raytrace_rs::cameras::*;
 use raytrace_rs::integrators::*;
+use raytrace_rs::photon_map::CausticSelector;
 use raytrace_rs::photon_map::DiffuseSelector;
 use raytrace_rs::photon_map::PhotonMap;
 use raytrace_rs::photon_map::Timing;
+use raytrace_rs::scene::IntegratorType;
 use raytrace_rs::scene::Scene;
 use raytrace_rs::scene::SceneSettings;
-use raytrace_rs::wavefront::load_scene;
+use raytrace_rs::loader::load_scene;
 use raytrace_rs::RenderConfiguration;
+use raytrace_rs::AOV;
 use raytrace_rs::vectors::*;
 
 use std::result::Result;
@@ -75,6 +78,7 @@ struct RunSettings {
   scene_settings: SceneSettings,
   interactive: bool,
   output: Option<String>,
+  aovs: Vec<AOV>,
 }
 fn load_settings() -> RunSettings {
   let commandline_yaml = load_yaml!("command_line.yml");
@@ -118,6 +122,48 @@ fn load_settings() -> RunSettings {
     }
     _ => {}
   }
+  match value_t!(matches, "caustic_photon_count", usize) {
+    Ok(value) => {
+      settings.caustic_photon_count = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "caustic_photon_samples", usize) {
+    Ok(value) => {
+      settings.caustic_photon_samples = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "final_gather_rays", usize) {
+    Ok(value) => {
+      settings.final_gather_rays = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "irradiance_cache_rays", usize) {
+    Ok(value) => {
+      settings.irradiance_cache_rays = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "irradiance_cache_accuracy", f32) {
+    Ok(value) => {
+      settings.irradiance_cache_accuracy = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "light_samples", usize) {
+    Ok(value) => {
+      settings.light_samples = value;
+    }
+    _ => {}
+  }
+  match value_t!(matches, "bsdf_samples", usize) {
+    Ok(value) => {
+      settings.bsdf_samples = value;
+    }
+    _ => {}
+  }
 
   match value_t!(matches, "samples_per_pixel", usize) {
     Ok(value) => {
@@ -135,6 +181,9 @@ fn load_settings() -> RunSettings {
   if matches.is_present("multisampling") {
     settings.use_multisampling = true;
   }
+  if matches.is_present("spectral") {
+    settings.spectral = true;
+  }
   match value_t!(matches, "target", VecArg) {
     Ok(value) => settings.camera_direction = (value.as_point() - settings.camera_position).normalize(),
     _ => {}
@@ -147,18 +196,76 @@ fn load_settings() -> RunSettings {
     Ok(value) => settings.gamma = value,
     _ => {}
   }
+  if let Some(integrator) = matches.value_of("integrator") {
+    settings.integrator = integrator.parse::<IntegratorType>().unwrap();
+  }
+  match value_t!(matches, "max_path_depth", usize) {
+    Ok(value) => settings.max_path_depth = value.max(1),
+    _ => {}
+  }
+  match value_t!(matches, "path_samples", usize) {
+    Ok(value) => settings.path_samples = value.max(1),
+    _ => {}
+  }
+  match value_t!(matches, "progressive_passes", usize) {
+    Ok(value) => settings.progressive_passes = value.max(1),
+    _ => {}
+  }
+
+  let aovs = match matches.value_of("aovs") {
+    Some(aovs) => aovs.split(',').map(|aov| aov.parse::<AOV>().unwrap()).collect(),
+    None => vec![],
+  };
 
   return RunSettings {
     scene_settings: settings,
     interactive: matches.is_present("interactive") || output_file.is_none(),
     output: output_file,
+    aovs,
   };
 }
 
 fn lighting_integrator(settings: &SceneSettings, scene: &Arc<Scene>) -> Arc<LightingIntegrator> {
   let lights = scene.get_light_samples(10000);
+  match settings.integrator {
+    IntegratorType::PathTracing => {
+      return Arc::new(PathTracer::new(scene, settings.max_path_depth, settings.path_samples));
+    }
+    IntegratorType::BidirectionalPathTracing | IntegratorType::ProgressivePhotonMapping => {
+      // Bidirectional path tracing and the progressive photon map render
+      // through their own cameras, so the integrator is never asked for
+      // lighting.
+      return Arc::new(DirectLighting::new(scene, None));
+    }
+    IntegratorType::DirectLighting => {}
+  }
+  let caustic_map = if settings.caustic_photon_count != 0 && settings.caustic_photon_samples != 0 {
+    PhotonMap::new(
+      &Arc::new(CausticSelector::new()),
+      scene,
+      &lights,
+      settings.caustic_photon_count,
+      settings.max_leaf_photons,
+      settings.caustic_photon_samples,
+    )
+  } else {
+    None
+  };
+  // The irradiance cache gathers its records from the diffuse map in place of
+  // final gather, and leaves emitters to DirectLighting.
+  let irradiance_cache = settings.irradiance_cache_rays != 0;
+  let final_gather = settings.final_gather_rays != 0 && !irradiance_cache;
+  // Caustics are only combined with the other lighting by DirectLighting.
+  let use_direct_lighting = settings.use_direct_lighting || caustic_map.is_some() || irradiance_cache;
   let photon_map = if settings.photon_count != 0 && settings.photon_samples != 0 {
-    let diffuse_map = Arc::new(DiffuseSelector::new(!settings.use_direct_lighting));
+    // Final gather rays and cache records look the map up away from the
+    // shading point, where neither direct lighting nor the caustic map apply.
+    let gathers_away = final_gather || irradiance_cache;
+    let mut diffuse_selector = DiffuseSelector::new(!use_direct_lighting || gathers_away);
+    if caustic_map.is_some() && !gathers_away {
+      diffuse_selector = diffuse_selector.excluding_caustics();
+    }
+    let diffuse_map = Arc::new(diffuse_selector);
     PhotonMap::new(
       &diffuse_map,
       scene,
@@ -167,18 +274,54 @@ fn lighting_integrator(settings: &SceneSettings, scene: &Arc<Scene>) -> Arc<Ligh
       settings.max_leaf_photons,
       settings.photon_samples,
     )
+    .map(|photon_map| photon_map.with_final_gather(if final_gather { settings.final_gather_rays } else { 0 }))
   } else {
     None
   };
 
-  if !settings.use_direct_lighting && photon_map.is_some() {
+  if !use_direct_lighting && photon_map.is_some() {
     return Arc::new(photon_map.unwrap());
   }
   let indirect_source: Option<Arc<IndirectLightingSource>> = photon_map.map(|p| {
     let p: Arc<IndirectLightingSource> = Arc::new(p);
+    if irradiance_cache {
+      let cache: Arc<IndirectLightingSource> = Arc::new(IrradianceCache::new(
+        scene,
+        p,
+        settings.irradiance_cache_rays,
+        settings.irradiance_cache_accuracy,
+      ));
+      return cache;
+    }
     return p;
   });
-  return Arc::new(DirectLighting::new(scene, lights, indirect_source));
+  let direct_lighting = DirectLighting::new(scene, indirect_source);
+  return match caustic_map {
+    Some(caustic_map) => Arc::new(direct_lighting.with_caustics(Arc::new(caustic_map))),
+    None => Arc::new(direct_lighting),
+  };
+}
+
+fn make_camera(settings: &SceneSettings, camera: PerspectiveCamera) -> Box<Camera> {
+  if settings.integrator == IntegratorType::ProgressivePhotonMapping {
+    let selector = Arc::new(DiffuseSelector::new(true));
+    return Box::new(ProgressivePhotonMap::new(
+      camera,
+      &selector,
+      settings.progressive_passes,
+      settings.photon_count,
+      settings.photon_samples,
+      settings.max_leaf_photons,
+    ));
+  }
+  if settings.integrator == IntegratorType::BidirectionalPathTracing {
+    return Box::new(BidirectionalPathTracer::new(
+      camera,
+      settings.max_path_depth,
+      settings.path_samples,
+    ));
+  }
+  return Box::new(camera);
 }
 
 fn vector_to_orientation(vector: Vector) -> (f32, f32) {
@@ -201,36 +344,44 @@ fn main() -> Result<(), String> {
     return run_interactive(&settings);
   }
 
-  let settings = settings.scene_settings;
-  let scn = Arc::new(load_scene(&settings));
+  let output_file = settings.output.unwrap();
+  let aovs = settings.aovs;
+  let (scn, settings) = load_scene(&settings.scene_settings);
+  let scn = Arc::new(scn);
   let lighting_integrator = lighting_integrator(&settings, &scn);
   let configuration = Arc::new(RenderConfiguration::new(lighting_integrator, scn));
 
-  let camera = Box::new(PerspectiveCamera::new(
-    settings.width as usize,
-    settings.height as usize,
-    settings.camera_position,
-    settings.camera_direction,
-    settings.camera_up,
-    40.,
-    settings.samples_per_pixel,
-    settings.use_multisampling,
-    settings.gamma,
-  ));
+  let camera = make_camera(
+    &settings,
+    PerspectiveCamera::new(
+      settings.width as usize,
+      settings.height as usize,
+      settings.camera_position,
+      settings.camera_direction,
+      settings.camera_up,
+      settings.fov,
+      settings.samples_per_pixel,
+      settings.use_multisampling,
+      settings.gamma,
+    )
+    .with_aovs(&aovs),
+  );
   let output = camera.render(&configuration);
-
-  return Ok(());
+  return output
+    .save(&output_file, settings.gamma)
+    .map_err(|e| format!("Failed to write {}: {}", output_file, e));
 }
 
 fn run_interactive(settings: &RunSettings) -> Result<(), String> {
+  let (scn, scene_settings) = load_scene(&settings.scene_settings);
   let sdl_context = sdl2::init()?;
   let video_subsystem = sdl_context.video()?;
 
   let window = video_subsystem
     .window(
       "rust-sdl2 demo: Window",
-      settings.scene_settings.width as u32,
-      settings.scene_settings.height as u32,
+      scene_settings.width as u32,
+      scene_settings.height as u32,
     )
     .resizable()
     .build()
@@ -250,11 +401,11 @@ fn run_interactive(settings: &RunSettings) -> Result<(), String> {
   let (render_parameter_transmitter, render_parameter_receiver) = mpsc::channel();
   let mut rendering = false;
   let mut should_render = true;
-  let mut gamma = settings.scene_settings.gamma;
+  let mut gamma = scene_settings.gamma;
   {
-    let settings = settings.scene_settings.clone();
+    let settings = scene_settings.clone();
     thread::spawn(move || {
-      let scn = Arc::new(load_scene(&settings));
+      let scn = Arc::new(scn);
       let lighting_integrator = lighting_integrator(&settings, &scn);
       let configuration = Arc::new(RenderConfiguration::new(lighting_integrator, scn));
       while let Ok(Some((camera, gamma))) = render_parameter_receiver.recv() {
@@ -277,10 +428,10 @@ fn run_interactive(settings: &RunSettings) -> Result<(), String> {
   }
 
   let step_size = 0.3;
-  let mut position = settings.scene_settings.camera_position;
+  let mut position = scene_settings.camera_position;
   let mut render_count = 0;
   let mut render_time = 0;
-  let (mut yaw, mut pitch) = vector_to_orientation(settings.scene_settings.camera_direction);
+  let (mut yaw, mut pitch) = vector_to_orientation(scene_settings.camera_direction);
   'running: loop {
     if let Some(event) = event_pump.wait_event_timeout(1000 / 24) {
       match event {
@@ -397,18 +548,21 @@ fn run_interactive(settings: &RunSettings) -> Result<(), String> {
         let window = canvas.window();
         let (width, height) = window.size();
 
-        let camera = Box::new(PerspectiveCamera::new(
-          width as usize,
-          height as usize,
-          position,
-          orientation_to_vector(yaw, pitch),
-          settings.scene_settings.camera_up,
-          40.,
-          settings.scene_settings.samples_per_pixel,
-          settings.scene_settings.use_multisampling,
-          gamma,
-        ));
-        render_parameter_transmitter.send(Some((camera, settings.scene_settings.gamma)));
+        let camera = make_camera(
+          &scene_settings,
+          PerspectiveCamera::new(
+            width as usize,
+            height as usize,
+            position,
+            orientation_to_vector(yaw, pitch),
+            scene_settings.camera_up,
+            scene_settings.fov,
+            scene_settings.samples_per_pixel,
+            scene_settings.use_multisampling,
+            gamma,
+          ),
+        );
+        render_parameter_transmitter.send(Some((camera, gamma)));
         rendering = true;
         should_render = false;
       }
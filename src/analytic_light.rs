@@ -0,0 +1,194 @@
+use crate::light::{Emitter, Light, LightSample};
+use crate::material::EmissionCoefficients;
+use crate::scene::Scene;
+use crate::spectrum::Blackbody;
+use crate::vectors::{Point, Vector, VectorType};
+use std::f32::consts::PI;
+
+// Lights without a surface are always a single sample, and contribute nothing
+// to the legacy ambient and specular terms.
+fn delta_sample(
+  position: Point,
+  direction: Option<Vector>,
+  radiance: Vector,
+  blackbody: Option<Blackbody>,
+  area: f32,
+  emitter: Emitter,
+) -> LightSample {
+  return LightSample {
+    position,
+    direction,
+    ambient: Vector::new(),
+    diffuse: Vector::new(),
+    specular: Vector::new(),
+    emission: EmissionCoefficients {
+      ambient: 0.0,
+      diffuse: 0.0,
+      specular: 0.0,
+      blackbody,
+      colour: None,
+    },
+    radiance,
+    area,
+    weight: 1.0,
+    emitter,
+  };
+}
+
+/// The sample for a light infinitely far away. Photons leave a disc facing the
+/// direction they travel in that covers the whole scene, so the sample is
+/// placed at the centre of the scene, with the area of the disc.
+pub(crate) fn distant_sample(
+  scene: &Scene,
+  direction: Option<Vector>,
+  radiance: Vector,
+  blackbody: Option<Blackbody>,
+  emitter: Emitter,
+) -> LightSample {
+  let bounds = scene.bounds();
+  let radius = (bounds.max - bounds.min).length() * 0.5 + 0.01;
+  return delta_sample(
+    bounds.centroid(),
+    direction,
+    radiance,
+    blackbody,
+    PI * radius * radius,
+    emitter,
+  );
+}
+
+/// A light that emits equally in all directions from a single point.
+#[derive(Debug)]
+pub struct PointLight {
+  position: Point,
+  intensity: Vector,
+  blackbody: Option<Blackbody>,
+}
+
+impl PointLight {
+  pub fn new(position: Point, intensity: Vector) -> Self {
+    return PointLight {
+      position,
+      intensity,
+      blackbody: None,
+    };
+  }
+
+  /// Emits the spectrum of the black body in spectral rendering, if there is
+  /// one, which the colour of the intensity should be that of.
+  pub fn with_blackbody(mut self, blackbody: Option<Blackbody>) -> Self {
+    self.blackbody = blackbody;
+    return self;
+  }
+}
+
+impl Light for PointLight {
+  fn get_area(&self) -> f32 {
+    return 0.0;
+  }
+
+  fn get_samples(&self, _: usize, _: &Scene) -> Vec<LightSample> {
+    return vec![delta_sample(
+      self.position,
+      None,
+      self.intensity,
+      self.blackbody,
+      0.0,
+      Emitter::Point { spot: None },
+    )];
+  }
+}
+
+/// A point light that only emits within a cone, fading out between the inner
+/// and outer angles, which are measured from the cone's axis in degrees.
+#[derive(Debug)]
+pub struct SpotLight {
+  position: Point,
+  direction: Vector,
+  intensity: Vector,
+  blackbody: Option<Blackbody>,
+  cos_inner: f32,
+  cos_outer: f32,
+}
+
+impl SpotLight {
+  pub fn new(position: Point, direction: Vector, intensity: Vector, inner_angle: f32, outer_angle: f32) -> Self {
+    let outer_angle = outer_angle.max(0.0).min(180.0);
+    let inner_angle = inner_angle.max(0.0).min(outer_angle);
+    return SpotLight {
+      position,
+      direction: direction.normalize(),
+      intensity,
+      blackbody: None,
+      cos_inner: (inner_angle * PI / 180.0).cos(),
+      cos_outer: (outer_angle * PI / 180.0).cos(),
+    };
+  }
+
+  /// As for PointLight::with_blackbody.
+  pub fn with_blackbody(mut self, blackbody: Option<Blackbody>) -> Self {
+    self.blackbody = blackbody;
+    return self;
+  }
+}
+
+impl Light for SpotLight {
+  fn get_area(&self) -> f32 {
+    return 0.0;
+  }
+
+  fn get_samples(&self, _: usize, _: &Scene) -> Vec<LightSample> {
+    return vec![delta_sample(
+      self.position,
+      Some(self.direction),
+      self.intensity,
+      self.blackbody,
+      0.0,
+      Emitter::Point {
+        spot: Some((self.cos_inner, self.cos_outer)),
+      },
+    )];
+  }
+}
+
+/// Light from a source so far away that it arrives along a single direction,
+/// such as the sun. The irradiance is that received by a surface facing the
+/// light.
+#[derive(Debug)]
+pub struct DirectionalLight {
+  direction: Vector,
+  irradiance: Vector,
+  blackbody: Option<Blackbody>,
+}
+
+impl DirectionalLight {
+  pub fn new(direction: Vector, irradiance: Vector) -> Self {
+    return DirectionalLight {
+      direction: direction.normalize(),
+      irradiance,
+      blackbody: None,
+    };
+  }
+
+  /// As for PointLight::with_blackbody.
+  pub fn with_blackbody(mut self, blackbody: Option<Blackbody>) -> Self {
+    self.blackbody = blackbody;
+    return self;
+  }
+}
+
+impl Light for DirectionalLight {
+  fn get_area(&self) -> f32 {
+    return 0.0;
+  }
+
+  fn get_samples(&self, _: usize, scene: &Scene) -> Vec<LightSample> {
+    return vec![distant_sample(
+      scene,
+      Some(self.direction),
+      self.irradiance,
+      self.blackbody,
+      Emitter::Directional,
+    )];
+  }
+}
@@ -0,0 +1,85 @@
+use crate::colour::Colour;
+use crate::scene::MaterialIdx;
+use crate::vectors::{Point, Vec2d, Vector, VectorType};
+use std::str::FromStr;
+
+/// Arbitrary output variables: surface properties of the first hit that can
+/// be written out alongside the rendered colour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AOV {
+  Normal,
+  Position,
+  Albedo,
+  UV,
+  MaterialId,
+  ObjectId,
+  NodeCount,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AOVSample {
+  pub normal: Vector,
+  pub position: Point,
+  pub albedo: Colour,
+  pub uv: Vec2d,
+  pub material: MaterialIdx,
+  pub object_id: usize,
+  pub node_count: usize,
+}
+
+impl AOV {
+  /// The EXR channel names used for each component of the AOV.
+  pub fn channel_names(&self) -> &'static [&'static str] {
+    match self {
+      AOV::Normal => &["N.X", "N.Y", "N.Z"],
+      AOV::Position => &["P.X", "P.Y", "P.Z"],
+      AOV::Albedo => &["albedo.R", "albedo.G", "albedo.B"],
+      AOV::UV => &["uv.U", "uv.V"],
+      AOV::MaterialId => &["materialId"],
+      AOV::ObjectId => &["objectId"],
+      AOV::NodeCount => &["nodeCount"],
+    }
+  }
+
+  /// Packs the value of the AOV for a sample into the components of a
+  /// vector. Rays that miss the scene produce zero for every AOV.
+  pub fn value(&self, sample: &Option<AOVSample>) -> Vector {
+    let sample = match sample {
+      Some(sample) => sample,
+      None => return Vector::new(),
+    };
+    match self {
+      AOV::Normal => sample.normal,
+      AOV::Position => Vector::vector(
+        sample.position.x() as f64,
+        sample.position.y() as f64,
+        sample.position.z() as f64,
+      ),
+      AOV::Albedo => Vector::from(sample.albedo),
+      AOV::UV => Vector::vector(sample.uv.0, sample.uv.1, 0.0),
+      AOV::MaterialId => {
+        let MaterialIdx(idx) = sample.material;
+        Vector::vector(idx as f64, 0.0, 0.0)
+      }
+      AOV::ObjectId => Vector::vector(sample.object_id as f64, 0.0, 0.0),
+      AOV::NodeCount => Vector::vector(sample.node_count as f64, 0.0, 0.0),
+    }
+  }
+}
+
+impl FromStr for AOV {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, String> {
+    return match s.trim().to_lowercase().as_str() {
+      "normal" => Ok(AOV::Normal),
+      "position" => Ok(AOV::Position),
+      "albedo" => Ok(AOV::Albedo),
+      "uv" => Ok(AOV::UV),
+      "material" => Ok(AOV::MaterialId),
+      "object" => Ok(AOV::ObjectId),
+      "nodes" => Ok(AOV::NodeCount),
+      other => Err(format!("Unknown AOV '{}'", other)),
+    };
+  }
+}
@@ -0,0 +1,1041 @@
+use crate::bsdf::Bsdf;
+use crate::camera::{Camera, PerspectiveCamera, RenderBuffer};
+use crate::colour::Colour;
+use crate::dispatch_queue::DispatchQueue;
+use crate::fragment::Fragment;
+use crate::light::{Emitter, LightSample};
+use crate::material::{cross_media_boundary, scattered_context, scattered_ray};
+use crate::material::MaterialCollisionInfo;
+use crate::photon_map::{distant_origin, random, Timing};
+use crate::ray::{Ray, RayContext};
+use crate::render_configuration::RenderConfiguration;
+use crate::sampling::{cosine_weighted_hemisphere, random_pair, uniform_cone, uniform_sphere};
+use crate::scene::Scene;
+use crate::spectrum::Wavelengths;
+use crate::vectors::{Point, Vector};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// Paths are never terminated by russian roulette before this many bounces.
+const MIN_ROULETTE_DEPTH: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+  // The pinhole of the camera.
+  Camera,
+  // A point on an emitter. Directional and environment lights are infinitely
+  // far away, so their vertices sit one unit away in their direction, from
+  // the vertex they're connected to.
+  Light(Emitter),
+  Surface,
+  // A point where a medium scatters the subpath.
+  Medium,
+}
+
+// A vertex of a camera or light subpath. As in Veach's thesis, the densities
+// are stored with respect to surface area, or volume for media: `pdf_forward`
+// is the density with which the subpath that owns the vertex generated it,
+// `pdf_reverse` the density with which the other subpath would have. Vertices
+// infinitely far away have densities with respect to solid angle instead.
+#[derive(Clone)]
+struct PathVertex {
+  kind: VertexKind,
+  position: Point,
+  // The normal of surfaces and area lights, and the axis of spot lights.
+  // Area light samples without a direction emit equally in every direction,
+  // and the camera and points in media have no surface.
+  normal: Option<Vector>,
+  // Where light is scattered, and how, so that connections can leave the
+  // vertex on the right side of it.
+  surface: Option<(Fragment, MaterialCollisionInfo)>,
+  // The direction towards the vertex before this one on the subpath.
+  wo: Vector,
+  // The radiance of a light.
+  emission: Vector,
+  // Whether the subpath was continued through a delta lobe, which has no
+  // density.
+  delta: bool,
+  // The throughput of the subpath up to, but not including, this vertex.
+  // For lights and the camera it's their emission or importance over the
+  // density of picking them.
+  beta: Vector,
+  pdf_forward: f32,
+  pdf_reverse: f32,
+  // The context of the ray that reached the vertex.
+  context: RayContext,
+}
+
+impl PathVertex {
+  fn bsdf(&self) -> Option<&Bsdf> {
+    return self.surface.as_ref().map(|(_, surface)| &surface.bsdf);
+  }
+
+  // Whether the subpath has been left with only its hero wavelength by the
+  // time light is scattered at this vertex.
+  fn is_dispersed(&self) -> bool {
+    return self.context.is_dispersed() || self.bsdf().map_or(false, |bsdf| bsdf.disperses());
+  }
+
+  fn is_infinite(&self) -> bool {
+    return match self.kind {
+      VertexKind::Light(Emitter::Directional) | VertexKind::Light(Emitter::Environment) => true,
+      _ => false,
+    };
+  }
+
+  // Whether the light has a single position or direction, so can't be
+  // reached by a camera subpath.
+  fn is_delta_light(&self) -> bool {
+    return match self.kind {
+      VertexKind::Light(Emitter::Point { .. }) | VertexKind::Light(Emitter::Directional) => true,
+      _ => false,
+    };
+  }
+
+  // The cosine that converts between area and solid angle at the vertex.
+  // Point lights, media and the camera have no surface, so it's one for them.
+  fn cosine(&self, direction: Vector) -> f32 {
+    return match (self.kind, self.normal) {
+      (VertexKind::Light(Emitter::Point { .. }), _) | (VertexKind::Medium, _) => 1.0,
+      (_, Some(normal)) => normal.dot(direction).abs(),
+      (_, None) => 1.0,
+    };
+  }
+
+  // Converts a solid angle density for leaving this vertex towards `next`
+  // into an area density at `next`.
+  fn area_density(&self, pdf: f32, next: &PathVertex) -> f32 {
+    if next.is_infinite() {
+      return pdf;
+    }
+    let direction = next.position - self.position;
+    let distance_squared = direction.square_length();
+    if distance_squared == 0.0 {
+      return 0.0;
+    }
+    return pdf * next.cosine(direction.normalize()) / distance_squared;
+  }
+
+  // Whether paths can be joined at the vertex, which needs a non-delta lobe
+  // to evaluate.
+  fn is_connectible(&self) -> bool {
+    return match self.bsdf() {
+      Some(bsdf) => bsdf.has_non_delta(),
+      None => true,
+    };
+  }
+
+  // The direction to use as `wo` for the Bsdf when light arrives from
+  // `previous`, or from the vertex before this one on its own subpath.
+  fn outgoing(&self, previous: Option<&PathVertex>) -> Vector {
+    return match previous {
+      Some(previous) => (previous.position - self.position).normalize(),
+      None => self.wo,
+    };
+  }
+
+  // The reflectance of the vertex towards `next`, for light arriving from
+  // the vertex before this one on its own subpath. The Scattering lobe of a
+  // medium only gives its phase function once multiplied by the cosine to
+  // its normal, which the geometry term leaves out for media.
+  fn reflectance(&self, next: &PathVertex) -> Vector {
+    let direction = (next.position - self.position).normalize();
+    return match self.bsdf() {
+      Some(bsdf) if self.kind == VertexKind::Medium => {
+        Vector::from(bsdf.eval(self.wo, direction)) * bsdf.normal().dot(direction).abs()
+      }
+      Some(bsdf) => Vector::from(bsdf.eval(self.wo, direction)),
+      None => Vector::new(),
+    };
+  }
+
+  // The context of a ray leaving the vertex in `direction`.
+  fn leaving_context(&self, direction: Vector) -> RayContext {
+    return match self.surface {
+      Some((ref fragment, ref surface)) => scattered_context(&self.context, fragment, surface, direction),
+      None => self.context.clone(),
+    };
+  }
+}
+
+/// A bidirectional path tracer. Every sample of a pixel traces a camera
+/// subpath from the camera through the pixel, and a light subpath from one
+/// of the scene's light samples, and connects every pair of their vertices,
+/// with the results combined by multiple importance sampling under the power
+/// heuristic. Light subpath vertices connected straight to the camera land
+/// in whichever pixel they're seen through, which lets it find caustics that
+/// camera subpaths can't.
+///
+/// Area, point, spot, directional and environment lights are all supported,
+/// and media scatter subpaths as surfaces do. Emitters are assumed to be
+/// outside of any medium. AOVs are not produced.
+pub struct BidirectionalPathTracer {
+  camera: PerspectiveCamera,
+  max_depth: usize,
+  paths_per_pixel: usize,
+}
+
+impl BidirectionalPathTracer {
+  pub fn new(camera: PerspectiveCamera, max_depth: usize, paths_per_pixel: usize) -> Self {
+    return BidirectionalPathTracer {
+      camera,
+      max_depth: max_depth.max(1),
+      paths_per_pixel: paths_per_pixel.max(1),
+    };
+  }
+}
+
+// The lights that subpaths start from, and everything else that tracing
+// them needs, shared by the threads of a render.
+struct PathSampler {
+  camera: PerspectiveCamera,
+  lights: Vec<LightSample>,
+  // The density of picking a point on the emissive surfaces, which their
+  // samples cover uniformly, with respect to area.
+  area_pdf: f32,
+  // The chance of picking the environment map.
+  environment_pdf: f32,
+  // The area of the disc covering the scene that light from infinitely far
+  // away leaves from.
+  distant_area: f32,
+  max_depth: usize,
+}
+
+impl PathSampler {
+  fn new(camera: PerspectiveCamera, scene: &Scene, max_depth: usize) -> Self {
+    let lights = scene.get_light_samples(10000);
+    let count = lights.len() as f32;
+    let area_lights: Vec<&LightSample> = lights.iter().filter(|light| light.emitter == Emitter::Area).collect();
+    let total_area: f32 = area_lights.iter().map(|light| light.area).sum();
+    let environments = lights
+      .iter()
+      .filter(|light| light.emitter == Emitter::Environment)
+      .count();
+    let distant_area = lights
+      .iter()
+      .find(|light| light.is_distant())
+      .map_or(0.0, |light| light.area);
+    return PathSampler {
+      camera,
+      area_pdf: if total_area > 0.0 {
+        area_lights.len() as f32 / (count * total_area)
+      } else {
+        0.0
+      },
+      environment_pdf: if count > 0.0 { environments as f32 / count } else { 0.0 },
+      distant_area,
+      lights,
+      max_depth,
+    };
+  }
+
+  // The area density with which a light subpath starting at `light` would
+  // continue to `next`.
+  fn light_pdf(&self, light: &PathVertex, next: &PathVertex) -> f32 {
+    let direction = (next.position - light.position).normalize();
+    // Light from infinitely far away has already picked its direction, and
+    // leaves from a point on a disc facing it.
+    if light.is_infinite() {
+      if self.distant_area <= 0.0 {
+        return 0.0;
+      }
+      return next.cosine(direction) / self.distant_area;
+    }
+    let pdf = match light.kind {
+      VertexKind::Light(Emitter::Area) => match light.normal {
+        Some(normal) => normal.dot(direction).abs() / (2.0 * PI),
+        None => 1.0 / (4.0 * PI),
+      },
+      VertexKind::Light(Emitter::Point { spot: None }) => 1.0 / (4.0 * PI),
+      VertexKind::Light(Emitter::Point {
+        spot: Some((_, cos_outer)),
+      }) => match light.normal {
+        Some(axis) if axis.dot(direction) >= cos_outer => 1.0 / (2.0 * PI * (1.0 - cos_outer)),
+        _ => 0.0,
+      },
+      _ => 0.0,
+    };
+    return light.area_density(pdf, next);
+  }
+
+  // The density of starting a light subpath at `light`, which is with
+  // respect to area, or to solid angle for the environment, seen from
+  // `next`.
+  fn light_origin_pdf(&self, scene: &Scene, light: &PathVertex, next: &PathVertex) -> f32 {
+    return match light.kind {
+      VertexKind::Light(Emitter::Area) => self.area_pdf,
+      VertexKind::Light(Emitter::Environment) => {
+        let towards = (light.position - next.position).normalize();
+        self.environment_pdf * scene.environment().map_or(0.0, |environment| environment.pdf(towards))
+      }
+      VertexKind::Light(_) => 1.0 / self.lights.len() as f32,
+      _ => 0.0,
+    };
+  }
+
+  // The area density with which `vertex` would generate `next`, by sampling
+  // its Bsdf for light arriving from `previous`, by emitting towards it, or
+  // by the camera picking the ray towards it.
+  fn pdf(&self, vertex: &PathVertex, previous: Option<&PathVertex>, next: &PathVertex) -> f32 {
+    let direction = (next.position - vertex.position).normalize();
+    let pdf = match vertex.kind {
+      VertexKind::Camera => self.camera.importance(direction).1,
+      VertexKind::Light(_) => return self.light_pdf(vertex, next),
+      VertexKind::Surface | VertexKind::Medium => match vertex.bsdf() {
+        Some(bsdf) => bsdf.pdf(vertex.outgoing(previous), direction),
+        None => 0.0,
+      },
+    };
+    return vertex.area_density(pdf, next);
+  }
+
+  // Extends a subpath by following `ray`, continuing from each surface by
+  // sampling its Bsdf. Camera subpaths that leave the scene end on the
+  // environment, and ones that reach an emitter end there.
+  fn random_walk(
+    &self,
+    scene: &Scene,
+    mut ray: Ray,
+    mut beta: Vector,
+    mut pdf: f32,
+    vertices: &mut Vec<PathVertex>,
+    is_light_path: bool,
+  ) {
+    // Paths scatter at most max_depth times, as in the path tracer, between
+    // the camera and a light.
+    let max_vertices = if is_light_path {
+      self.max_depth + 1
+    } else {
+      self.max_depth + 2
+    };
+    while vertices.len() < max_vertices {
+      let (collision, shadable) = match scene.intersect(&ray) {
+        None => {
+          if !is_light_path && scene.environment().is_some() {
+            let mut vertex = PathVertex {
+              kind: VertexKind::Light(Emitter::Environment),
+              position: vertices.last().unwrap().position + ray.direction,
+              normal: None,
+              surface: None,
+              wo: -ray.direction,
+              emission: ray.ray_context.upsample(scene.background(ray.direction)),
+              delta: false,
+              beta,
+              pdf_forward: 0.0,
+              pdf_reverse: 0.0,
+              context: ray.ray_context.clone(),
+            };
+            vertex.pdf_forward = vertices.last().unwrap().area_density(pdf, &vertex);
+            vertices.push(vertex);
+          }
+          return;
+        }
+        Some(hit) => hit,
+      };
+      beta = beta * Vector::from(scene.attenuation(&ray, collision.distance));
+      let fragment = shadable.compute_fragment(scene, &ray, &collision);
+      let surface = scene
+        .get_material(fragment.material)
+        .compute_surface_properties(scene, &ray, &fragment)
+        .upsampled(&ray.ray_context);
+      // Connections see through the boundaries of media, so they aren't
+      // vertices of the path either.
+      if let Some(continued_ray) = cross_media_boundary(&ray, &fragment, &surface) {
+        ray = continued_ray;
+        continue;
+      }
+
+      let wo = -fragment.view.normalize();
+      let is_media = shadable.is_media();
+      let mut vertex = PathVertex {
+        kind: if is_media {
+          VertexKind::Medium
+        } else {
+          VertexKind::Surface
+        },
+        position: surface.position,
+        normal: if is_media { None } else { Some(surface.normal) },
+        surface: None,
+        wo,
+        emission: Vector::new(),
+        delta: false,
+        beta,
+        pdf_forward: 0.0,
+        pdf_reverse: 0.0,
+        context: ray.ray_context.clone(),
+      };
+      vertex.pdf_forward = vertices.last().unwrap().area_density(pdf, &vertex);
+
+      if let Some(emission) = surface.emitted_colour() {
+        // Lights do not reflect, so light subpaths simply end here.
+        if !is_light_path {
+          vertex.kind = VertexKind::Light(Emitter::Area);
+          vertex.emission = Vector::from(emission);
+          vertices.push(vertex);
+        }
+        return;
+      }
+
+      let sample = surface.bsdf.sample(wo, random_pair());
+      vertex.delta = sample.map_or(false, |sample| sample.delta);
+      let sample = match sample {
+        Some(sample) => sample,
+        None => {
+          vertex.surface = Some((fragment, surface));
+          vertices.push(vertex);
+          return;
+        }
+      };
+      let reverse_pdf = if sample.delta {
+        0.0
+      } else {
+        surface.bsdf.pdf(sample.direction, wo)
+      };
+      let previous = vertices.last_mut().unwrap();
+      previous.pdf_reverse = vertex.area_density(reverse_pdf, previous);
+      let next_ray = scattered_ray(&ray, &fragment, &surface, sample.direction);
+      vertex.surface = Some((fragment, surface));
+      vertices.push(vertex);
+
+      beta = beta * Vector::from(sample.weight);
+      if vertices.len() > MIN_ROULETTE_DEPTH {
+        let continue_probability = sample.weight.max_value().min(0.95);
+        if random(0.0, 1.0) as f32 >= continue_probability {
+          return;
+        }
+        beta = beta / continue_probability;
+      }
+      pdf = sample.pdf;
+      ray = next_ray;
+    }
+  }
+
+  // The camera subpath through the film at the given coordinates.
+  fn camera_subpath(&self, scene: &Scene, x: f64, y: f64, wavelengths: Option<Wavelengths>) -> Vec<PathVertex> {
+    let mut ray = self.camera.ray_for_coordinate(x, y);
+    ray.ray_context.wavelengths = wavelengths;
+    let mut vertices = vec![PathVertex {
+      kind: VertexKind::Camera,
+      position: self.camera.position(),
+      normal: None,
+      surface: None,
+      wo: Vector::new(),
+      emission: Vector::new(),
+      delta: false,
+      beta: Vector::vector(1.0, 1.0, 1.0),
+      pdf_forward: 1.0,
+      pdf_reverse: 0.0,
+      context: ray.ray_context.clone(),
+    }];
+    let (_, pdf) = self.camera.importance(ray.direction);
+    if pdf > 0.0 {
+      self.random_walk(scene, ray, Vector::vector(1.0, 1.0, 1.0), pdf, &mut vertices, false);
+    }
+    return vertices;
+  }
+
+  // The light subpath carries the same wavelengths as the camera subpath, so
+  // that the two can be connected.
+  fn light_subpath(&self, scene: &Scene, wavelengths: Option<Wavelengths>) -> Vec<PathVertex> {
+    let lights = &self.lights;
+    let mut vertices = vec![];
+    if lights.is_empty() {
+      return vertices;
+    }
+    let light = &lights[random(0.0, lights.len() as f64) as usize];
+    let pick = 1.0 / lights.len() as f32;
+    let mut context = RayContext::new();
+    context.wavelengths = wavelengths;
+    let radiance = context.upsample_emission(light.radiance, light.emission.blackbody);
+    // Where the subpath starts, the direction it leaves in and the radiance
+    // along it, and the densities of picking the start and the direction.
+    let (position, direction, emission, origin_pdf, direction_pdf) = match light.emitter {
+      Emitter::Area => {
+        let (direction, pdf) = match light.direction {
+          Some(normal) => {
+            let direction = cosine_weighted_hemisphere(normal);
+            let direction = if random(0.0, 1.0) < 0.5 { direction } else { -direction };
+            (direction, normal.dot(direction).abs() / (2.0 * PI))
+          }
+          None => (uniform_sphere(), 1.0 / (4.0 * PI)),
+        };
+        (light.position, direction, radiance, pick / light.area, pdf)
+      }
+      Emitter::Point { spot: None } => (light.position, uniform_sphere(), radiance, pick, 1.0 / (4.0 * PI)),
+      Emitter::Point {
+        spot: Some((_, cos_outer)),
+      } => {
+        let direction = uniform_cone(light.direction.unwrap(), cos_outer);
+        let emission = radiance * light.spot_falloff(direction);
+        (
+          light.position,
+          direction,
+          emission,
+          pick,
+          1.0 / (2.0 * PI * (1.0 - cos_outer)),
+        )
+      }
+      Emitter::Directional => {
+        let direction = light.direction.unwrap();
+        (
+          distant_origin(light, direction),
+          direction,
+          radiance,
+          pick / light.area,
+          1.0,
+        )
+      }
+      Emitter::Environment => {
+        let (towards, radiance, pdf) = match scene.environment().and_then(|environment| environment.sample()) {
+          Some(sample) => sample,
+          None => return vertices,
+        };
+        let direction = -towards;
+        let emission = context.upsample(radiance);
+        (
+          distant_origin(light, direction),
+          direction,
+          emission,
+          pick / light.area,
+          pdf,
+        )
+      }
+    };
+    let mut origin = PathVertex {
+      kind: VertexKind::Light(light.emitter),
+      position,
+      normal: if light.is_distant() { None } else { light.direction },
+      surface: None,
+      wo: Vector::new(),
+      emission,
+      delta: false,
+      beta: emission / origin_pdf,
+      pdf_forward: 0.0,
+      pdf_reverse: 0.0,
+      context: context.clone(),
+    };
+    origin.pdf_forward = match light.emitter {
+      Emitter::Area => self.area_pdf,
+      Emitter::Environment => self.environment_pdf * direction_pdf,
+      _ => pick,
+    };
+    let beta = origin.beta * (origin.cosine(direction) / direction_pdf);
+    let ray = Ray::new_bound(position, direction, 0.005, std::f32::INFINITY, Some(context));
+    vertices.push(origin);
+    self.random_walk(scene, ray, beta, direction_pdf, &mut vertices, true);
+    // Light from infinitely far away picks its direction first, so the first
+    // vertex it reaches has the density of the point on the disc it left from.
+    if light.is_distant() && vertices.len() > 1 {
+      vertices[1].pdf_forward = vertices[1].cosine(direction) / light.area;
+    }
+    return vertices;
+  }
+
+  // Picks a point on a light to connect `vertex` to, whose beta is its
+  // emission towards the vertex over the density of picking it.
+  fn sample_light(&self, scene: &Scene, vertex: &PathVertex) -> Option<PathVertex> {
+    let lights = &self.lights;
+    if lights.is_empty() {
+      return None;
+    }
+    let light = &lights[random(0.0, lights.len() as f64) as usize];
+    let count = lights.len() as f32;
+    let context = &vertex.context;
+    let radiance = context.upsample_emission(light.radiance, light.emission.blackbody);
+    let (position, emission, beta, pdf_forward) = match light.emitter {
+      Emitter::Area => (light.position, radiance, radiance * (count * light.area), self.area_pdf),
+      Emitter::Point { .. } => {
+        let emission = radiance * light.spot_falloff((vertex.position - light.position).normalize());
+        (light.position, emission, emission * count, 1.0 / count)
+      }
+      Emitter::Directional => (
+        vertex.position - light.direction.unwrap(),
+        radiance,
+        radiance * count,
+        1.0 / count,
+      ),
+      Emitter::Environment => {
+        let (towards, radiance, pdf) = scene.environment()?.sample()?;
+        let emission = context.upsample(radiance);
+        (
+          vertex.position + towards,
+          emission,
+          emission * (count / pdf),
+          self.environment_pdf * pdf,
+        )
+      }
+    };
+    return Some(PathVertex {
+      kind: VertexKind::Light(light.emitter),
+      position,
+      normal: if light.is_distant() { None } else { light.direction },
+      surface: None,
+      wo: Vector::new(),
+      emission,
+      delta: false,
+      beta,
+      pdf_forward,
+      pdf_reverse: 0.0,
+      context: RayContext::new(),
+    });
+  }
+
+  // The camera vertex that `vertex` is seen from, whose beta is the camera's
+  // importance, and the pixel it's seen in.
+  fn sample_camera(&self, vertex: &PathVertex) -> Option<(PathVertex, usize)> {
+    let direction = (vertex.position - self.camera.position()).normalize();
+    let (x, y) = self.camera.coordinate_for_direction(direction)?;
+    let (importance, _) = self.camera.importance(direction);
+    let (width, _) = self.camera.size();
+    let mut context = RayContext::new();
+    context.wavelengths = vertex.context.wavelengths;
+    let camera = PathVertex {
+      kind: VertexKind::Camera,
+      position: self.camera.position(),
+      normal: None,
+      surface: None,
+      wo: Vector::new(),
+      emission: Vector::new(),
+      delta: false,
+      beta: Vector::vector(1.0, 1.0, 1.0) * importance,
+      pdf_forward: 1.0,
+      pdf_reverse: 0.0,
+      context,
+    };
+    return Some((camera, y as usize * width + x as usize));
+  }
+
+  // The unweighted contribution of joining the camera side vertex `pt` to
+  // the light side vertex `qs`. Either may be a light or the camera picked
+  // for the connection, whose emission or importance is in their beta.
+  fn connect(&self, scene: &Scene, qs: &PathVertex, pt: &PathVertex) -> Vector {
+    let endpoint_reflectance = |vertex: &PathVertex, next: &PathVertex| match vertex.kind {
+      VertexKind::Camera | VertexKind::Light(_) => Vector::vector(1.0, 1.0, 1.0),
+      VertexKind::Surface | VertexKind::Medium => vertex.reflectance(next),
+    };
+    let unoccluded = endpoint_reflectance(pt, qs) * endpoint_reflectance(qs, pt);
+    if unoccluded.length() == 0.0 {
+      return Vector::new();
+    }
+    let direction = qs.position - pt.position;
+    let (distance, direction, geometry) = if qs.is_infinite() {
+      let direction = direction.normalize();
+      (std::f32::INFINITY, direction, pt.cosine(direction))
+    } else {
+      let distance = direction.length();
+      let direction = direction.normalize();
+      let geometry = pt.cosine(direction) * qs.cosine(direction) / (distance * distance);
+      (distance - 0.005, direction, geometry)
+    };
+    if geometry == 0.0 {
+      return Vector::new();
+    }
+    let shadow_test = Ray::new_bound(
+      pt.position,
+      direction,
+      0.005,
+      distance,
+      Some(pt.leaving_context(direction)),
+    );
+    let transmittance = scene.transmittance(&shadow_test);
+    if transmittance.max_value() <= 0.0 {
+      return Vector::new();
+    }
+    // Each subpath that was split up by dispersion carries its hero with the
+    // weight of all three wavelengths, which is only right for one of them.
+    let mut dispersion = 1.0;
+    if qs.is_dispersed() && pt.is_dispersed() {
+      dispersion = 1.0 / 3.0;
+    }
+    return qs.beta * pt.beta * unoccluded * Vector::from(transmittance) * (geometry * dispersion);
+  }
+
+  // The power heuristic weight for the path made of the first s light and
+  // first t camera vertices, relative to every other way of sampling it.
+  // Connections to a light or the camera picked for them pass it as
+  // `sampled`, in place of the subpath's own.
+  fn mis_weight(
+    &self,
+    scene: &Scene,
+    light: &[PathVertex],
+    camera: &[PathVertex],
+    s: usize,
+    t: usize,
+    sampled: Option<&PathVertex>,
+  ) -> f32 {
+    if s + t == 2 {
+      return 1.0;
+    }
+    let mut light = light[..s].to_vec();
+    let mut camera = camera[..t].to_vec();
+    if let Some(sampled) = sampled {
+      if s == 1 {
+        light[0] = sampled.clone();
+      } else if t == 1 {
+        camera[0] = sampled.clone();
+      }
+    }
+
+    // Update the reverse densities of the vertices on either side of the
+    // connection to account for the connection itself.
+    let pt = t - 1;
+    let before_pt = if pt > 0 { Some(&camera[pt - 1]) } else { None };
+    let (pt_reverse, before_pt_reverse, qs_reverse, before_qs_reverse);
+    if s == 0 {
+      // The camera subpath reached a light by itself.
+      pt_reverse = self.light_origin_pdf(scene, &camera[pt], &camera[pt - 1]);
+      before_pt_reverse = Some(self.pdf(&camera[pt], None, &camera[pt - 1]));
+      qs_reverse = None;
+      before_qs_reverse = None;
+    } else {
+      let qs = s - 1;
+      let before_qs = if qs > 0 { Some(&light[qs - 1]) } else { None };
+      pt_reverse = self.pdf(&light[qs], before_qs, &camera[pt]);
+      before_pt_reverse = before_pt.map(|before_pt| self.pdf(&camera[pt], Some(&light[qs]), before_pt));
+      qs_reverse = Some(self.pdf(&camera[pt], before_pt, &light[qs]));
+      before_qs_reverse = before_qs.map(|before_qs| self.pdf(&light[qs], Some(&camera[pt]), before_qs));
+    }
+    camera[pt].pdf_reverse = pt_reverse;
+    if let Some(pdf) = before_pt_reverse {
+      camera[pt - 1].pdf_reverse = pdf;
+    }
+    if let Some(pdf) = qs_reverse {
+      light[s - 1].pdf_reverse = pdf;
+    }
+    if let Some(pdf) = before_qs_reverse {
+      light[s - 2].pdf_reverse = pdf;
+    }
+    // The connection evaluates the non-delta lobes at either end, whichever
+    // lobe the subpaths went on to sample.
+    camera[pt].delta = false;
+    if s > 0 {
+      light[s - 1].delta = false;
+    }
+
+    // Vertices continued through delta lobes have no density, and can never
+    // be connected.
+    let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    // The pinhole of the camera can never be reached by a light subpath.
+    for i in (1..t).rev() {
+      ratio *= remap(camera[i].pdf_reverse) / remap(camera[i].pdf_forward);
+      if !camera[i].delta && !camera[i - 1].delta {
+        sum += ratio * ratio;
+      }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+      ratio *= remap(light[i].pdf_reverse) / remap(light[i].pdf_forward);
+      let previous_is_delta = if i > 0 {
+        light[i - 1].delta
+      } else {
+        light[0].is_delta_light()
+      };
+      if !light[i].delta && !previous_is_delta {
+        sum += ratio * ratio;
+      }
+    }
+    return 1.0 / (1.0 + sum);
+  }
+
+  // Traces a camera subpath through the pixel and a light subpath, returning
+  // the colour they find for the pixel, the distance to the first hit, and
+  // the colours they add to the pixels that light subpath vertices are seen
+  // in.
+  fn sample(&self, scene: &Scene, index: usize) -> (Vector, f32, Vec<(usize, Vector)>) {
+    let (width, _) = self.camera.size();
+    let x = (index % width) as f64 + random(0.0, 1.0);
+    let y = (index / width) as f64 + random(0.0, 1.0);
+    // In spectral mode, both subpaths carry the same set of wavelengths, and
+    // what they find is converted back to RGB.
+    let wavelengths = if scene.is_spectral() {
+      Some(Wavelengths::sample(random(0.0, 1.0) as f32))
+    } else {
+      None
+    };
+    let to_rgb = |value: Vector| match wavelengths {
+      Some(wavelengths) => Vector::from(wavelengths.to_rgb(Colour::from(value))),
+      None => value,
+    };
+    let camera = self.camera_subpath(scene, x, y, wavelengths);
+    let light = self.light_subpath(scene, wavelengths);
+    let depth = match camera.get(1) {
+      Some(vertex) if !vertex.is_infinite() => (vertex.position - camera[0].position).length(),
+      _ => std::f32::INFINITY,
+    };
+
+    let mut result = Vector::new();
+    let mut splats = vec![];
+    for t in 1..=camera.len() {
+      for s in 0..=light.len() {
+        if s + t < 2 || (s == 1 && t == 1) || s + t > self.max_depth + 2 {
+          continue;
+        }
+        let pt = &camera[t - 1];
+        if s == 0 {
+          if let VertexKind::Light(_) = pt.kind {
+            let contribution = pt.beta * pt.emission;
+            result = result + contribution * self.mis_weight(scene, &light, &camera, s, t, None);
+          }
+          continue;
+        }
+        // Lights reached by the camera subpath don't reflect.
+        if let VertexKind::Light(_) = pt.kind {
+          continue;
+        }
+        if t == 1 {
+          let qs = &light[s - 1];
+          if !qs.is_connectible() {
+            continue;
+          }
+          let (sampled, pixel) = match self.sample_camera(qs) {
+            Some(sampled) => sampled,
+            None => continue,
+          };
+          let contribution = self.connect(scene, qs, &sampled);
+          if contribution.length() == 0.0 {
+            continue;
+          }
+          let weight = self.mis_weight(scene, &light, &camera, s, t, Some(&sampled));
+          splats.push((pixel, to_rgb(contribution * weight)));
+          continue;
+        }
+        if !pt.is_connectible() {
+          continue;
+        }
+        let (contribution, sampled) = if s == 1 {
+          let sampled = match self.sample_light(scene, pt) {
+            Some(sampled) => sampled,
+            None => continue,
+          };
+          (self.connect(scene, &sampled, pt), Some(sampled))
+        } else {
+          let qs = &light[s - 1];
+          if !qs.is_connectible() {
+            continue;
+          }
+          (self.connect(scene, qs, pt), None)
+        };
+        if contribution.length() == 0.0 {
+          continue;
+        }
+        result = result + contribution * self.mis_weight(scene, &light, &camera, s, t, sampled.as_ref());
+      }
+    }
+    return (to_rgb(result), depth, splats);
+  }
+}
+
+impl Camera for BidirectionalPathTracer {
+  fn render(&self, configuration: &Arc<RenderConfiguration>) -> RenderBuffer {
+    let scene = configuration.scene();
+    let sampler = Arc::new(PathSampler::new(self.camera.clone(), &scene, self.max_depth));
+    let (width, height) = self.camera.size();
+    let mut colours = vec![Vector::new(); width * height];
+    let mut splats = vec![Vector::new(); width * height];
+    let mut depths = vec![std::f32::INFINITY; width * height];
+
+    for pass in 0..self.paths_per_pixel {
+      let _t = Timing::new(&format!("Bidirectional path tracing pass {}", pass + 1));
+      let mut queue = DispatchQueue::default();
+      for index in 0..width * height {
+        queue.add_task(&index);
+      }
+      let sampler = sampler.clone();
+      let scene = scene.clone();
+      let samples = queue.consume_tasks(&move |index| {
+        let (colour, depth, splats) = sampler.sample(&scene, *index);
+        return (*index, colour, depth, splats);
+      });
+      for (index, colour, depth, pixel_splats) in samples {
+        colours[index] = colours[index] + colour;
+        if pass == 0 {
+          depths[index] = depth;
+        }
+        for (pixel, splat) in pixel_splats {
+          splats[pixel] = splats[pixel] + splat;
+        }
+      }
+    }
+
+    // Every pixel traced a light subpath in each pass, so the light that
+    // reached the camera from them is averaged over the passes too.
+    let mut buffer = RenderBuffer::new(width, height);
+    let passes = self.paths_per_pixel as f32;
+    for index in 0..width * height {
+      let colour = (colours[index] + splats[index]) / passes;
+      buffer.set(
+        index % width,
+        index / width,
+        (colour, self.paths_per_pixel, depths[index] as f64),
+      );
+    }
+    return buffer;
+  }
+}
+
+#[test]
+fn test_camera_importance() {
+  let direction = Vector::vector(0.2, -0.1, -1.0).normalize();
+  let camera = PerspectiveCamera::new(
+    64,
+    48,
+    Vector::point(1.0, 2.0, 3.0),
+    direction,
+    Vector::vector(0.0, 1.0, 0.0),
+    50.0,
+    1,
+    false,
+    1.0,
+  );
+  for &(x, y) in &[(0.5, 0.5), (10.25, 40.75), (63.5, 1.0), (32.0, 24.0)] {
+    let ray = camera.ray_for_coordinate(x, y);
+    let (u, v) = camera.coordinate_for_direction(ray.direction).unwrap();
+    assert!((u - x).abs() < 0.001 && (v - y).abs() < 0.001);
+  }
+  assert!(camera.coordinate_for_direction(-direction).is_none());
+  // The pdf is a density over directions, so it integrates to one over the
+  // sphere.
+  let count = 200000;
+  let mut total = 0.0;
+  for _ in 0..count {
+    let sample = uniform_sphere();
+    let (importance, pdf) = camera.importance(sample);
+    if pdf > 0.0 {
+      assert!((importance * sample.dot(direction) - pdf).abs() < 0.0001 * pdf);
+    }
+    total += pdf * 4.0 * PI / count as f32;
+  }
+  assert!((total - 1.0).abs() < 0.05, "total {}", total);
+}
+
+#[test]
+fn test_mis_weights_sum_to_one() {
+  use crate::either::Either;
+  use crate::material::{DefaultMaterial, EmissiveMaterial};
+  use crate::scene::SceneSettings;
+  use crate::sphere::Sphere;
+
+  // A lamp inside a diffuse room around the camera.
+  let mut settings = SceneSettings::new();
+  settings.scene_file = std::env::temp_dir().to_string_lossy().to_string();
+  let mut scene = Scene::new(&settings);
+  let (wall, _) = scene.get_or_create_material("wall", |_| {
+    Some(Box::new(DefaultMaterial::new(Colour::RGB(0.5, 0.5, 0.5), None)))
+  });
+  let (lamp, _) = scene.get_or_create_material("lamp", |_| {
+    Some(Box::new(EmissiveMaterial::new(Colour::RGB(1.0, 1.0, 1.0), 1.0)))
+  });
+  scene.add_object(Box::new(Sphere::new(Vector::point(0.0, 0.0, 0.0), 5.0, wall)));
+  scene.add_object(Box::new(Sphere::new(Vector::point(2.0, 3.0, -1.0), 0.5, lamp)));
+  scene.finalize();
+  let camera = PerspectiveCamera::new(
+    32,
+    32,
+    Vector::point(0.0, 0.0, 0.0),
+    Vector::vector(0.0, 0.0, -1.0),
+    Vector::vector(0.0, 1.0, 0.0),
+    60.0,
+    1,
+    false,
+    1.0,
+  );
+  let sampler = PathSampler::new(camera, &scene, 10);
+
+  // The vertex where a ray from `from` towards `target` first hits.
+  let hit = |from: Point, target: Point| {
+    let ray = Ray::new_bound(from, (target - from).normalize(), 0.005, std::f32::INFINITY, None);
+    let (collision, shadable) = match scene.intersect(&ray) {
+      Some((collision, Either::Left(shadable))) => (collision, shadable),
+      _ => panic!("The ray should hit a surface"),
+    };
+    let fragment = shadable.compute_fragment(&scene, &ray, &collision);
+    let surface = scene
+      .get_material(fragment.material)
+      .compute_surface_properties(&scene, &ray, &fragment);
+    let mut vertex = PathVertex {
+      kind: VertexKind::Surface,
+      position: surface.position,
+      normal: Some(surface.normal),
+      surface: None,
+      wo: -ray.direction,
+      emission: Vector::new(),
+      delta: false,
+      beta: Vector::vector(1.0, 1.0, 1.0),
+      pdf_forward: 0.0,
+      pdf_reverse: 0.0,
+      context: RayContext::new(),
+    };
+    match surface.emitted_colour() {
+      Some(emission) => {
+        vertex.kind = VertexKind::Light(Emitter::Area);
+        vertex.emission = Vector::from(emission);
+      }
+      None => vertex.surface = Some((fragment, surface)),
+    }
+    return vertex;
+  };
+  let origin = Vector::point(0.0, 0.0, 0.0);
+  let pinhole = PathVertex {
+    kind: VertexKind::Camera,
+    position: origin,
+    normal: None,
+    surface: None,
+    wo: Vector::new(),
+    emission: Vector::new(),
+    delta: false,
+    beta: Vector::vector(1.0, 1.0, 1.0),
+    pdf_forward: 1.0,
+    pdf_reverse: 0.0,
+    context: RayContext::new(),
+  };
+  let first = hit(origin, Vector::point(0.3, -0.2, -1.0));
+  let second = hit(first.position, Vector::point(-1.0, -4.0, 1.0));
+  let third = hit(second.position, Vector::point(-3.0, 1.0, 2.0));
+  let light = hit(third.position, Vector::point(2.0, 3.0, -1.0));
+  assert!(light.kind == VertexKind::Light(Emitter::Area));
+  let path = vec![pinhole, first, second, third, light];
+  let n = path.len();
+
+  // The area densities with which camera and light subpaths generate each
+  // vertex of the path. The pinhole can't be reached by light subpaths.
+  let mut camera_pdfs = vec![1.0];
+  for i in 1..n {
+    let previous = if i > 1 { Some(&path[i - 2]) } else { None };
+    camera_pdfs.push(sampler.pdf(&path[i - 1], previous, &path[i]));
+  }
+  let mut light_pdfs = vec![0.0; n];
+  light_pdfs[n - 1] = sampler.light_origin_pdf(&scene, &path[n - 1], &path[n - 2]);
+  light_pdfs[n - 2] = sampler.pdf(&path[n - 1], None, &path[n - 2]);
+  for i in 1..n - 2 {
+    light_pdfs[i] = sampler.pdf(&path[i + 1], Some(&path[i + 2]), &path[i]);
+  }
+
+  // Every split of the path into light and camera subpaths samples it, and
+  // their weights must sum to one.
+  let mut total = 0.0;
+  for t in 1..=n {
+    let s = n - t;
+    let camera: Vec<PathVertex> = (0..t)
+      .map(|i| {
+        let mut vertex = path[i].clone();
+        vertex.pdf_forward = camera_pdfs[i];
+        vertex.pdf_reverse = light_pdfs[i];
+        return vertex;
+      })
+      .collect();
+    let light: Vec<PathVertex> = (0..s)
+      .map(|j| {
+        let mut vertex = path[n - 1 - j].clone();
+        vertex.pdf_forward = light_pdfs[n - 1 - j];
+        vertex.pdf_reverse = camera_pdfs[n - 1 - j];
+        return vertex;
+      })
+      .collect();
+    let weight = sampler.mis_weight(&scene, &light, &camera, s, t, None);
+    assert!(weight > 0.0);
+    total += weight;
+  }
+  assert!((total - 1.0).abs() < 0.001, "total {}", total);
+}
@@ -0,0 +1,782 @@
+use crate::colour::Colour;
+use crate::photon_map::random;
+use crate::microfacet::{
+  ggx_distribution, ggx_pdf, ggx_reflectance, ggx_shadowing, ggx_visible_normal_pdf, sample_ggx,
+  sample_ggx_visible_normal,
+};
+use crate::sampling::{cosine_weighted_direction, random_pair};
+use crate::spectrum::Wavelengths;
+use crate::vectors::{Vector, VectorType};
+use std::f32::consts::PI;
+
+/// One of the ways a surface scatters light.
+#[derive(Clone, Debug)]
+pub enum Lobe {
+  /// Lambertian reflection on the side of the normal.
+  Diffuse(Colour),
+  /// Lambertian scattering to both sides of the normal, each with half of
+  /// the colour. This is the phase function of media, see Media.
+  Scattering(Colour),
+  /// GGX microfacet reflection with the given width, see microfacet.rs. The
+  /// colour stands in for the Fresnel term.
+  Glossy(Colour, f32),
+  /// GGX microfacet reflection with the given width, whose colour is
+  /// Schlick's approximation of the Fresnel term. It reflects `f0` head on,
+  /// rising to white at grazing angles, all scaled by `weight`.
+  SchlickGlossy { f0: Colour, weight: f32, alpha: f32 },
+  /// The retroreflective sheen of cloth, which grows towards grazing angles
+  /// between the directions, as in the Disney BRDF.
+  Sheen(Colour),
+  /// Perfect mirror reflection.
+  Mirror(Colour),
+  /// A smooth boundary between dielectrics, which reflects in proportion to
+  /// the Fresnel term and refracts the rest.
+  /// Index matched boundaries pass light straight through.
+  Dielectric {
+    reflection: Colour,
+    transmission: Colour,
+    inside_ior: f32,
+    outside_ior: f32,
+  },
+  /// A boundary between dielectrics that is rough, so it reflects and
+  /// refracts through GGX microfacets of the given width, following Walter
+  /// et al. 2007. As with Dielectric, transmitted light isn't scaled by the
+  /// ratio of the indices, so the two agree as the surface gets smoother.
+  RoughDielectric {
+    reflection: Colour,
+    transmission: Colour,
+    inside_ior: f32,
+    outside_ior: f32,
+    alpha: f32,
+  },
+  /// A smooth metal, which reflects as much of each channel as the Fresnel
+  /// equations give for its complex index of refraction, eta + ik, relative
+  /// to what's outside of it.
+  Conductor { eta: Colour, k: Colour },
+  /// A metal with GGX microfacets of the given width, each of which reflects
+  /// as Conductor does.
+  RoughConductor { eta: Colour, k: Colour, alpha: f32 },
+}
+
+impl Lobe {
+  /// Whether the lobe scatters into single directions, so that it can only
+  /// be sampled and never evaluated.
+  pub fn is_delta(&self) -> bool {
+    return match self {
+      Lobe::Mirror(_) | Lobe::Dielectric { .. } | Lobe::Conductor { .. } => true,
+      Lobe::Diffuse(_)
+      | Lobe::Scattering(_)
+      | Lobe::Glossy(..)
+      | Lobe::SchlickGlossy { .. }
+      | Lobe::Sheen(_)
+      | Lobe::RoughDielectric { .. }
+      | Lobe::RoughConductor { .. } => false,
+    };
+  }
+
+  // How often the lobe is sampled relative to the others.
+  fn selection_weight(&self) -> f32 {
+    return match self {
+      Lobe::Diffuse(colour)
+      | Lobe::Scattering(colour)
+      | Lobe::Glossy(colour, _)
+      | Lobe::Sheen(colour)
+      | Lobe::Mirror(colour) => colour.max_value(),
+      Lobe::Dielectric {
+        reflection,
+        transmission,
+        ..
+      }
+      | Lobe::RoughDielectric {
+        reflection,
+        transmission,
+        ..
+      } => reflection.max_value().max(transmission.max_value()),
+      // Metals reflect more towards grazing angles, but are weighted by
+      // what they reflect head on.
+      Lobe::Conductor { eta, k } | Lobe::RoughConductor { eta, k, .. } => fresnel_conductor(1.0, *eta, *k).max_value(),
+      Lobe::SchlickGlossy { f0, weight, .. } => f0.max_value() * weight,
+    };
+  }
+
+  // The same lobe with each of its colours passed through `f`.
+  fn map_colours(&self, f: &Fn(Colour) -> Colour) -> Lobe {
+    return match *self {
+      Lobe::Diffuse(colour) => Lobe::Diffuse(f(colour)),
+      Lobe::Scattering(colour) => Lobe::Scattering(f(colour)),
+      Lobe::Glossy(colour, alpha) => Lobe::Glossy(f(colour), alpha),
+      Lobe::SchlickGlossy { f0, weight, alpha } => Lobe::SchlickGlossy {
+        f0: f(f0),
+        weight,
+        alpha,
+      },
+      Lobe::Sheen(colour) => Lobe::Sheen(f(colour)),
+      Lobe::Mirror(colour) => Lobe::Mirror(f(colour)),
+      Lobe::Dielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+      } => Lobe::Dielectric {
+        reflection: f(reflection),
+        transmission: f(transmission),
+        inside_ior,
+        outside_ior,
+      },
+      Lobe::RoughDielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+        alpha,
+      } => Lobe::RoughDielectric {
+        reflection: f(reflection),
+        transmission: f(transmission),
+        inside_ior,
+        outside_ior,
+        alpha,
+      },
+      Lobe::Conductor { eta, k } => Lobe::Conductor { eta: f(eta), k: f(k) },
+      Lobe::RoughConductor { eta, k, alpha } => Lobe::RoughConductor {
+        eta: f(eta),
+        k: f(k),
+        alpha,
+      },
+    };
+  }
+}
+
+/// A direction picked by Bsdf::sample.
+#[derive(Clone, Copy, Debug)]
+pub struct BsdfSample {
+  pub direction: Vector,
+  /// The scattering function times the cosine, divided by the density of
+  /// the sample, which is what the light arriving along the direction is
+  /// scaled by.
+  pub weight: Colour,
+  /// The solid angle density of the sample, or zero for delta lobes.
+  pub pdf: f32,
+  pub delta: bool,
+}
+
+/// How light arriving at a point on a surface is scattered, as a sum of
+/// lobes. Directions all point away from the surface: `wo` towards the viewer
+/// and `wi` towards the light.
+#[derive(Clone, Debug)]
+pub struct Bsdf {
+  normal: Vector,
+  // The geometric normal, which decides whether a dielectric is being
+  // entered or left, as for Fragment::true_normal.
+  true_normal: Vector,
+  lobes: Vec<Lobe>,
+  // Whether the indices of the dielectric lobes are those of the hero
+  // wavelength, see with_dispersion.
+  dispersive: bool,
+}
+
+impl Bsdf {
+  /// A surface that scatters nothing, until lobes are added.
+  pub fn new(normal: Vector, true_normal: Vector) -> Bsdf {
+    return Bsdf {
+      normal,
+      true_normal,
+      lobes: vec![],
+      dispersive: false,
+    };
+  }
+
+  /// Marks the indices of the dielectric lobes as depending on wavelength,
+  /// see disperses.
+  pub fn with_dispersion(mut self) -> Self {
+    self.dispersive = true;
+    return self;
+  }
+
+  /// Whether the surface splits light up by wavelength. The first time a
+  /// path scatters off such a surface, it's left with only its hero
+  /// wavelength, as the others would have gone elsewhere, see
+  /// scattered_context.
+  pub fn disperses(&self) -> bool {
+    return self.dispersive
+      && self.lobes.iter().any(|lobe| match lobe {
+        Lobe::Dielectric {
+          inside_ior,
+          outside_ior,
+          ..
+        }
+        | Lobe::RoughDielectric {
+          inside_ior,
+          outside_ior,
+          ..
+        } => inside_ior != outside_ior,
+        _ => false,
+      });
+  }
+
+  /// The same surface, with its colours converted to the spectrum at
+  /// `wavelengths`.
+  pub fn upsampled(&self, wavelengths: &Wavelengths) -> Bsdf {
+    let mut bsdf = self.clone();
+    let hero_only = self.disperses() && !wavelengths.is_dispersed();
+    for lobe in bsdf.lobes.iter_mut() {
+      *lobe = lobe.map_colours(&|colour| wavelengths.upsample(colour));
+      if let Lobe::Dielectric { .. } | Lobe::RoughDielectric { .. } = lobe {
+        if hero_only {
+          *lobe = lobe.map_colours(&Wavelengths::hero_only);
+        }
+      }
+    }
+    bsdf.lobes.retain(|lobe| lobe.selection_weight() > 0.0);
+    return bsdf;
+  }
+
+  /// Adds a lobe. Lobes that would scatter nothing are left out.
+  pub fn with_lobe(mut self, lobe: Lobe) -> Self {
+    if lobe.selection_weight() > 0.0 {
+      self.lobes.push(lobe);
+    }
+    return self;
+  }
+
+  pub fn normal(&self) -> Vector {
+    return self.normal;
+  }
+
+  pub fn lobes(&self) -> &[Lobe] {
+    return &self.lobes;
+  }
+
+  /// Whether any lobe can be evaluated, so that the surface can be lit.
+  pub fn has_non_delta(&self) -> bool {
+    return self.lobes.iter().any(|lobe| !lobe.is_delta());
+  }
+
+  pub fn has_delta(&self) -> bool {
+    return self.lobes.iter().any(|lobe| lobe.is_delta());
+  }
+
+  /// Whether a non-delta lobe scatters light through the surface, so that
+  /// lights behind it can contribute.
+  pub fn transmits(&self) -> bool {
+    return self.lobes.iter().any(|lobe| match lobe {
+      Lobe::Scattering(_) | Lobe::RoughDielectric { .. } => true,
+      _ => false,
+    });
+  }
+
+  /// Whether the surface scatters to both sides of its normal, as the
+  /// scattering points of media do.
+  pub fn is_two_sided(&self) -> bool {
+    return self.lobes.iter().any(|lobe| match lobe {
+      Lobe::Scattering(_) => true,
+      _ => false,
+    });
+  }
+
+  /// Whether light passes straight through the surface untouched, as it
+  /// does through the boundary of a fog, see Fog.
+  pub fn is_invisible(&self) -> bool {
+    return match self.lobes.as_slice() {
+      [Lobe::Dielectric {
+        reflection,
+        inside_ior,
+        outside_ior,
+        ..
+      }] => reflection.max_value() <= 0.0 && inside_ior == outside_ior,
+      _ => false,
+    };
+  }
+
+  /// The total colour of the Lambertian lobes, which is what estimates of the
+  /// irradiance such as photon maps are scaled by.
+  pub fn diffuse_colour(&self) -> Colour {
+    let mut result = Colour::new();
+    for lobe in &self.lobes {
+      match lobe {
+        Lobe::Diffuse(colour) | Lobe::Scattering(colour) => result = result + *colour,
+        _ => {}
+      }
+    }
+    return result;
+  }
+
+  /// The probability that sample picks a delta lobe.
+  pub fn delta_probability(&self) -> f32 {
+    let total = self.total_weight();
+    if total <= 0.0 {
+      return 0.0;
+    }
+    let delta: f32 = self
+      .lobes
+      .iter()
+      .filter(|lobe| lobe.is_delta())
+      .map(|lobe| lobe.selection_weight())
+      .sum();
+    return delta / total;
+  }
+
+  fn total_weight(&self) -> f32 {
+    return self.lobes.iter().map(|lobe| lobe.selection_weight()).sum();
+  }
+
+  /// The scattering function of the non-delta lobes, without the cosine.
+  pub fn eval(&self, wo: Vector, wi: Vector) -> Colour {
+    let mut result = Colour::new();
+    for lobe in &self.lobes {
+      result = result + self.eval_lobe(lobe, wo, wi);
+    }
+    return result;
+  }
+
+  /// The solid angle density with which sample picks `wi` from the non-delta
+  /// lobes.
+  pub fn pdf(&self, wo: Vector, wi: Vector) -> f32 {
+    let total = self.total_weight();
+    if total <= 0.0 {
+      return 0.0;
+    }
+    let mut result = 0.0;
+    for lobe in &self.lobes {
+      result += lobe.selection_weight() / total * self.lobe_pdf(lobe, wo, wi);
+    }
+    return result;
+  }
+
+  /// Picks a direction for light to arrive from, using the uniform sample
+  /// `u`. Lobes are picked in proportion to their colour, and non-delta
+  /// samples are weighted by all of the non-delta lobes, so that they match
+  /// eval and pdf.
+  pub fn sample(&self, wo: Vector, u: (f32, f32)) -> Option<BsdfSample> {
+    let total = self.total_weight();
+    if total <= 0.0 {
+      return None;
+    }
+    // Pick a lobe, and stretch the part of the sample that picked it back
+    // over [0, 1) for the lobe to use.
+    let mut selection = u.0 * total;
+    let mut picked = self.lobes.len() - 1;
+    for (index, lobe) in self.lobes.iter().enumerate() {
+      if selection < lobe.selection_weight() || index == self.lobes.len() - 1 {
+        picked = index;
+        break;
+      }
+      selection -= lobe.selection_weight();
+    }
+    let lobe = &self.lobes[picked];
+    let probability = lobe.selection_weight() / total;
+    let u = ((selection / lobe.selection_weight()).max(0.0).min(0.999_999), u.1);
+
+    let direction = match lobe {
+      Lobe::Mirror(colour) => {
+        return Some(BsdfSample {
+          direction: self.reflect(wo),
+          weight: *colour * (1.0 / probability),
+          pdf: 0.0,
+          delta: true,
+        });
+      }
+      Lobe::Conductor { eta, k } => {
+        return Some(BsdfSample {
+          direction: self.reflect(wo),
+          weight: fresnel_conductor(wo.dot(self.normal), *eta, *k) * (1.0 / probability),
+          pdf: 0.0,
+          delta: true,
+        });
+      }
+      Lobe::Dielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+      } => {
+        let (direction, colour) = match self.refract(wo, *inside_ior, *outside_ior) {
+          None => (self.reflect(wo), *transmission),
+          Some((refracted, fresnel)) if u.0 >= fresnel => (refracted, *transmission),
+          Some(_) => (self.reflect(wo), *reflection),
+        };
+        return Some(BsdfSample {
+          direction,
+          weight: colour * (1.0 / probability),
+          pdf: 0.0,
+          delta: true,
+        });
+      }
+      Lobe::Diffuse(_) | Lobe::Sheen(_) => cosine_weighted_direction(self.normal, u),
+      Lobe::Scattering(_) => {
+        if u.0 < 0.5 {
+          cosine_weighted_direction(self.normal, (u.0 * 2.0, u.1))
+        } else {
+          cosine_weighted_direction(-self.normal, (u.0 * 2.0 - 1.0, u.1))
+        }
+      }
+      Lobe::Glossy(_, alpha) | Lobe::SchlickGlossy { alpha, .. } | Lobe::RoughConductor { alpha, .. } => {
+        sample_ggx(self.normal, wo, *alpha, u)?
+      }
+      Lobe::RoughDielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+        alpha,
+      } => {
+        let (ni, nt) = self.indices(wo, *inside_ior, *outside_ior);
+        let half = sample_ggx_visible_normal(self.normal, wo, *alpha, u);
+        let o_dot_h = wo.dot(half);
+        if o_dot_h <= 0.0 {
+          return None;
+        }
+        // The sample has been used up picking the microfacet, so reflection
+        // and refraction are picked with a fresh random number.
+        let reflect = (random(0.0, 1.0) as f32) < reflect_probability(o_dot_h, *reflection, *transmission, ni, nt);
+        if reflect {
+          (half * (2.0 * o_dot_h) - wo).normalize()
+        } else {
+          let eta = ni / nt;
+          let inner = 1.0 - eta * eta * (1.0 - o_dot_h * o_dot_h);
+          if inner < 0.0 {
+            return None;
+          }
+          ((eta * o_dot_h - inner.sqrt()) * half - eta * wo).normalize()
+        }
+      }
+    };
+    let pdf = self.pdf(wo, direction);
+    if !(pdf > 0.0) {
+      return None;
+    }
+    let cosine = direction.dot(self.normal).abs();
+    return Some(BsdfSample {
+      direction,
+      weight: self.eval(wo, direction) * (cosine / pdf),
+      pdf,
+      delta: false,
+    });
+  }
+
+  /// Every direction that the delta lobes scatter `wo` into, with the colour
+  /// each is scaled by.
+  pub fn delta_directions(&self, wo: Vector) -> Vec<(Vector, Colour)> {
+    let mut result = vec![];
+    for lobe in &self.lobes {
+      match lobe {
+        Lobe::Mirror(colour) => result.push((self.reflect(wo), *colour)),
+        Lobe::Conductor { eta, k } => {
+          result.push((self.reflect(wo), fresnel_conductor(wo.dot(self.normal), *eta, *k)));
+        }
+        Lobe::Dielectric {
+          reflection,
+          transmission,
+          inside_ior,
+          outside_ior,
+        } => match self.refract(wo, *inside_ior, *outside_ior) {
+          None => result.push((self.reflect(wo), *transmission)),
+          Some((refracted, fresnel)) => {
+            if fresnel > 0.0 {
+              result.push((self.reflect(wo), *reflection * fresnel));
+            }
+            result.push((refracted, *transmission * (1.0 - fresnel)));
+          }
+        },
+        _ => {}
+      }
+    }
+    return result;
+  }
+
+  fn eval_lobe(&self, lobe: &Lobe, wo: Vector, wi: Vector) -> Colour {
+    return match lobe {
+      Lobe::Diffuse(colour) if wi.dot(self.normal) > 0.0 => *colour * (1.0 / PI),
+      Lobe::Scattering(colour) => *colour * (0.5 / PI),
+      Lobe::Glossy(colour, alpha) => *colour * ggx_reflectance(self.normal, wo, wi, *alpha),
+      Lobe::SchlickGlossy { f0, weight, alpha } => {
+        let fresnel = fresnel_schlick(wo.dot((wo + wi).normalize()), *f0);
+        fresnel * (weight * ggx_reflectance(self.normal, wo, wi, *alpha))
+      }
+      Lobe::RoughConductor { eta, k, alpha } => {
+        let fresnel = fresnel_conductor(wo.dot((wo + wi).normalize()), *eta, *k);
+        fresnel * ggx_reflectance(self.normal, wo, wi, *alpha)
+      }
+      Lobe::Sheen(colour) if wi.dot(self.normal) > 0.0 && wo.dot(self.normal) > 0.0 => {
+        let one_minus_cos = 1.0 - wi.dot((wo + wi).normalize()).max(0.0).min(1.0);
+        let squared = one_minus_cos * one_minus_cos;
+        *colour * (squared * squared * one_minus_cos)
+      }
+      Lobe::RoughDielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+        alpha,
+      } => {
+        let (ni, nt) = self.indices(wo, *inside_ior, *outside_ior);
+        let cos_o = wo.dot(self.normal);
+        let cos_i = wi.dot(self.normal);
+        if cos_o <= 0.0 || cos_i == 0.0 {
+          return Colour::new();
+        }
+        let shadowing = ggx_shadowing(self.normal, wo, wi, *alpha);
+        if cos_i > 0.0 {
+          let half = (wo + wi).normalize();
+          let fresnel = fresnel_dielectric(wo.dot(half), ni, nt);
+          let distribution = ggx_distribution(self.normal, half, *alpha);
+          return *reflection * (fresnel * distribution * shadowing / (4.0 * cos_o * cos_i));
+        }
+        let half = match self.refraction_half(wo, wi, ni, nt) {
+          Some(half) => half,
+          None => return Colour::new(),
+        };
+        let (o_dot_h, i_dot_h) = (wo.dot(half), wi.dot(half));
+        let fresnel = fresnel_dielectric(o_dot_h, ni, nt);
+        let denominator = ni * o_dot_h + nt * i_dot_h;
+        let distribution = ggx_distribution(self.normal, half, *alpha);
+        *transmission
+          * ((1.0 - fresnel) * distribution * shadowing * o_dot_h * -i_dot_h * nt * nt
+            / (cos_o * -cos_i * denominator * denominator))
+      }
+      _ => Colour::new(),
+    };
+  }
+
+  fn lobe_pdf(&self, lobe: &Lobe, wo: Vector, wi: Vector) -> f32 {
+    return match lobe {
+      Lobe::Diffuse(_) | Lobe::Sheen(_) => wi.dot(self.normal).max(0.0) / PI,
+      Lobe::Scattering(_) => wi.dot(self.normal).abs() / (2.0 * PI),
+      Lobe::Glossy(_, alpha) | Lobe::SchlickGlossy { alpha, .. } | Lobe::RoughConductor { alpha, .. } => {
+        ggx_pdf(self.normal, wo, wi, *alpha)
+      }
+      Lobe::RoughDielectric {
+        reflection,
+        transmission,
+        inside_ior,
+        outside_ior,
+        alpha,
+      } => {
+        let (ni, nt) = self.indices(wo, *inside_ior, *outside_ior);
+        if wi.dot(self.normal) > 0.0 {
+          let half = (wo + wi).normalize();
+          let o_dot_h = wo.dot(half);
+          let probability = reflect_probability(o_dot_h, *reflection, *transmission, ni, nt);
+          return probability * ggx_visible_normal_pdf(self.normal, wo, half, *alpha) / (4.0 * o_dot_h);
+        }
+        let half = match self.refraction_half(wo, wi, ni, nt) {
+          Some(half) => half,
+          None => return 0.0,
+        };
+        let (o_dot_h, i_dot_h) = (wo.dot(half), wi.dot(half));
+        let probability = 1.0 - reflect_probability(o_dot_h, *reflection, *transmission, ni, nt);
+        let denominator = ni * o_dot_h + nt * i_dot_h;
+        probability * ggx_visible_normal_pdf(self.normal, wo, half, *alpha) * nt * nt * -i_dot_h
+          / (denominator * denominator)
+      }
+      _ => 0.0,
+    };
+  }
+
+  // The indices of refraction on the side of `wo` and on the far side. Rays
+  // arrive from inside when `wo` is on the side the true normal points to.
+  fn indices(&self, wo: Vector, inside_ior: f32, outside_ior: f32) -> (f32, f32) {
+    if wo.dot(self.true_normal) > 0.0 {
+      return (inside_ior, outside_ior);
+    }
+    return (outside_ior, inside_ior);
+  }
+
+  // The microfacet normal that refracts `wo` into `wi`, facing the side of
+  // the shading normal, if there is one.
+  fn refraction_half(&self, wo: Vector, wi: Vector, ni: f32, nt: f32) -> Option<Vector> {
+    if ni == nt {
+      return None;
+    }
+    let mut half = (wo * -ni - wi * nt).normalize();
+    if half.dot(self.normal) < 0.0 {
+      half = -half;
+    }
+    if wo.dot(half) <= 0.0 || wi.dot(half) >= 0.0 {
+      return None;
+    }
+    return Some(half);
+  }
+
+  fn reflect(&self, wo: Vector) -> Vector {
+    return (self.normal * (2.0 * wo.dot(self.normal)) - wo).normalize();
+  }
+
+  // The refracted direction and the fraction of light that is reflected
+  // instead, or None on total internal reflection.
+  fn refract(&self, wo: Vector, inside_ior: f32, outside_ior: f32) -> Option<(Vector, f32)> {
+    let (ni, nt) = self.indices(wo, inside_ior, outside_ior);
+    let nr = ni / nt;
+    let n_dot_v = self.normal.dot(wo);
+    let inner = 1.0 - nr * nr * (1.0 - n_dot_v * n_dot_v);
+    if inner < 0.0 {
+      return None;
+    }
+    let refracted = ((nr * n_dot_v - inner.sqrt()) * self.normal - nr * wo).normalize();
+    let fresnel = fresnel_dielectric(n_dot_v, ni, nt);
+    // Index matched boundaries, such as the edge of a medium, reflect
+    // nothing, and weak reflections are dropped.
+    if fresnel <= 0.02 || ni == nt {
+      return Some((refracted, 0.0));
+    }
+    return Some((refracted, fresnel));
+  }
+}
+
+/// Schlick's approximation of the Fresnel term, for light arriving at the
+/// given cosine to a surface that reflects `f0` head on.
+pub fn fresnel_schlick(cos_i: f32, f0: Colour) -> Colour {
+  let m = 1.0 - cos_i.abs().min(1.0);
+  let m2 = m * m;
+  return f0 + (Colour::RGB(1.0, 1.0, 1.0) - f0) * (m2 * m2 * m);
+}
+
+// The fraction of unpolarised light arriving at the given cosine from the
+// side with index `ni` that is reflected, which is all of it past the
+// critical angle.
+fn fresnel_dielectric(cos_i: f32, ni: f32, nt: f32) -> f32 {
+  let cos_i = cos_i.abs().min(1.0);
+  let sin_t2 = (ni / nt) * (ni / nt) * (1.0 - cos_i * cos_i);
+  if sin_t2 >= 1.0 {
+    return 1.0;
+  }
+  let cos_t = (1.0 - sin_t2).sqrt();
+  let perpendicular = (ni * cos_i - nt * cos_t) / (ni * cos_i + nt * cos_t);
+  let parallel = (nt * cos_i - ni * cos_t) / (nt * cos_i + ni * cos_t);
+  return (perpendicular * perpendicular + parallel * parallel) / 2.0;
+}
+
+// The fraction of each channel of unpolarised light arriving at the given
+// cosine that a metal with the complex index of refraction eta + ik
+// reflects.
+fn fresnel_conductor(cos_i: f32, eta: Colour, k: Colour) -> Colour {
+  let cos2 = cos_i.abs().min(1.0) * cos_i.abs().min(1.0);
+  let sin2 = 1.0 - cos2;
+  let channel = |eta: f32, k: f32| {
+    let (eta2, k2) = (eta * eta, k * k);
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    return (0.5 * (perpendicular + parallel)).max(0.0).min(1.0);
+  };
+  return Colour::RGB(
+    channel(eta.r(), k.r()),
+    channel(eta.g(), k.g()),
+    channel(eta.b(), k.b()),
+  );
+}
+
+// How often a rough dielectric reflects off a microfacet rather than
+// refracting through it.
+fn reflect_probability(o_dot_h: f32, reflection: Colour, transmission: Colour, ni: f32, nt: f32) -> f32 {
+  let fresnel = fresnel_dielectric(o_dot_h, ni, nt);
+  let reflected = fresnel * reflection.max_value();
+  let transmitted = (1.0 - fresnel) * transmission.max_value();
+  if reflected + transmitted <= 0.0 {
+    return 0.0;
+  }
+  return reflected / (reflected + transmitted);
+}
+
+#[test]
+fn test_bsdf_sampling() {
+  let normal = Vector::vector(0.0, 0.0, 1.0);
+  let bsdf = Bsdf::new(normal, normal)
+    .with_lobe(Lobe::Diffuse(Colour::RGB(0.5, 0.5, 0.5)))
+    .with_lobe(Lobe::Glossy(Colour::RGB(0.2, 0.2, 0.2), 0.3))
+    .with_lobe(Lobe::Mirror(Colour::RGB(0.3, 0.3, 0.3)));
+  let wo = Vector::vector(0.6, 0.0, 0.8);
+  // Averaging the sample weights estimates the albedo, which is the sum of
+  // the colours less what the glossy lobe loses to shadowing.
+  let count = 20000;
+  let mut albedo = 0.0;
+  let mut delta_count = 0;
+  for index in 0..count {
+    let u = (
+      (index as f32 + 0.5) / count as f32,
+      ((index * 7919) % count) as f32 / count as f32,
+    );
+    let sample = match bsdf.sample(wo, u) {
+      Some(sample) => sample,
+      None => continue,
+    };
+    if sample.delta {
+      delta_count += 1;
+    } else {
+      // Non-delta samples agree with eval and pdf.
+      assert!((sample.pdf - bsdf.pdf(wo, sample.direction)).abs() < 1e-4 * sample.pdf);
+    }
+    albedo += sample.weight.r();
+  }
+  albedo /= count as f32;
+  assert!(albedo > 0.95 && albedo <= 1.0, "albedo {}", albedo);
+  let expected_delta = bsdf.delta_probability() * count as f32;
+  assert!((delta_count as f32 - expected_delta).abs() < 0.01 * count as f32);
+  assert_eq!(bsdf.delta_directions(wo).len(), 1);
+  assert_eq!(bsdf.eval(wo, Vector::vector(0.0, 0.0, -1.0)).max_value(), 0.0);
+}
+
+#[test]
+fn test_rough_dielectric() {
+  let normal = Vector::vector(0.0, 0.0, 1.0);
+  let white = Colour::RGB(1.0, 1.0, 1.0);
+  let lobe = Lobe::RoughDielectric {
+    reflection: white,
+    transmission: white,
+    inside_ior: 1.5,
+    outside_ior: 1.0,
+    alpha: 0.2,
+  };
+  let wo = Vector::vector(0.8, 0.0, 0.6);
+  // Light arrives from outside when the true normal faces away from it, and
+  // from inside when it doesn't.
+  for true_normal in &[-normal, normal] {
+    let bsdf = Bsdf::new(normal, *true_normal).with_lobe(lobe.clone());
+    let count = 20000;
+    let (mut albedo, mut transmitted) = (0.0, 0);
+    for _ in 0..count {
+      let sample = match bsdf.sample(wo, random_pair()) {
+        Some(sample) => sample,
+        None => continue,
+      };
+      assert!(!sample.delta);
+      assert!((sample.pdf - bsdf.pdf(wo, sample.direction)).abs() < 1e-3 * sample.pdf);
+      if sample.direction.dot(normal) < 0.0 {
+        transmitted += 1;
+      }
+      albedo += sample.weight.r();
+    }
+    albedo /= count as f32;
+    // Little is lost to shadowing at this roughness, and most light passes
+    // in, but from inside this is past the critical angle, so only light
+    // that meets steep microfacets gets out.
+    assert!(albedo > 0.85 && albedo <= 1.01, "albedo {}", albedo);
+    if *true_normal == -normal {
+      assert!(transmitted > count / 2);
+    } else {
+      assert!(transmitted < count / 4);
+    }
+  }
+}
+
+#[test]
+fn test_fresnel_conductor() {
+  let eta = Colour::RGB(0.2, 1.0, 1.5);
+  let k = Colour::RGB(3.9, 0.0, 0.0);
+  // Head on, the equations reduce to ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2).
+  let normal = fresnel_conductor(1.0, eta, k);
+  assert!((normal.r() - (0.64 + 3.9 * 3.9) / (1.44 + 3.9 * 3.9)).abs() < 1e-4);
+  // Without absorption, they match those of a dielectric.
+  assert!(normal.g().abs() < 1e-6);
+  for &cos in &[0.9f32, 0.5, 0.1] {
+    let conductor = fresnel_conductor(cos, eta, k);
+    assert!((conductor.b() - fresnel_dielectric(cos, 1.0, 1.5)).abs() < 1e-4);
+  }
+  // Everything is reflected at grazing angles.
+  assert!(fresnel_conductor(0.0, eta, k).r() > 0.999);
+}
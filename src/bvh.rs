@@ -71,7 +71,8 @@ fn intersect_primitives<'a, T: Intersectable + 'a>(
     let element = &primitives[*index];
     match element.intersect(ray, hit_mode, min, closest) {
       None => continue,
-      Some((collision, object)) => {
+      Some((mut collision, object)) => {
+        collision.object_index = *index;
         if collision.distance < closest {
           closest = collision.distance;
           result = Some((collision, object));
@@ -162,6 +163,7 @@ fn intersect<'a, T: Intersectable>(
         uv: c.uv,
         intersection_count: primitive_count,
         node_count: node_count,
+        object_index: c.object_index,
       }),
       object,
     ));
@@ -7,6 +7,10 @@ use crate::ray::Ray;
 use crate::vectors::{Point, Vector, VectorType};
 use crate::dispatch_queue::DispatchQueue;
 use crate::photon_map::Timing;
+use crate::image_output;
+use crate::exr::ExrCompression;
+use crate::aov::{AOVSample, AOV};
+use std::path::Path;
 
 pub trait Camera: Sync + Send {
   fn render(&self, configuration: &Arc<RenderConfiguration>) -> RenderBuffer;
@@ -26,16 +30,57 @@ pub struct PerspectiveCamera {
   view_origin: Point,
   do_multisampling: bool,
   gamma: f32,
+  aovs: Vec<AOV>,
 }
 
 const DELTA: f32 = 0.1;
 const MAX_DEPTH: u32 = 2;
 
 impl PerspectiveCamera {
-  fn ray_for_coordinate(&self, x: f64, y: f64) -> Ray {
+  // The render buffer holds linear radiance, but adaptive multisampling should
+  // be driven by differences that are visible in the final image.
+  fn display_colour(&self, colour: Vector) -> Vector {
+    return colour.powf(self.gamma).clamp(Vector::splat(0.0), Vector::splat(1.0));
+  }
+  pub(crate) fn size(&self) -> (usize, usize) {
+    return (self._width, self._height);
+  }
+  pub(crate) fn ray_for_coordinate(&self, x: f64, y: f64) -> Ray {
     let view_target = self.view_origin + (self.x_delta * x) - (self.y_delta * y);
     Ray::new(self.position, (view_target - self.position).normalize(), None)
   }
+  pub(crate) fn position(&self) -> Point {
+    return self.position;
+  }
+  /// The coordinates that ray_for_coordinate takes to give a ray in
+  /// `direction`, if it passes through the film, which covers coordinates
+  /// from zero up to the width and height.
+  pub(crate) fn coordinate_for_direction(&self, direction: Vector) -> Option<(f64, f64)> {
+    let cosine = direction.dot(self._direction);
+    if cosine <= 0.0 {
+      return None;
+    }
+    let offset = direction * (1.0 / cosine) - (self.view_origin - self.position);
+    let x = (offset.dot(self.x_delta) / self.x_delta.square_length()) as f64;
+    let y = (-offset.dot(self.y_delta) / self.y_delta.square_length()) as f64;
+    if x < 0.0 || y < 0.0 || x >= self._width as f64 || y >= self._height as f64 {
+      return None;
+    }
+    return Some((x, y));
+  }
+  /// The importance of a ray leaving the camera in `direction`, and the
+  /// density with respect to solid angle of picking it by choosing a point
+  /// on the film uniformly. The film sits at a distance of one, as for a
+  /// pinhole camera. Both are zero for rays that miss the film.
+  pub(crate) fn importance(&self, direction: Vector) -> (f32, f32) {
+    if self.coordinate_for_direction(direction).is_none() {
+      return (0.0, 0.0);
+    }
+    let film_area = self.x_delta.length() * self._width as f32 * self.y_delta.length() * self._height as f32;
+    let cosine = direction.dot(self._direction);
+    let pdf = 1.0 / (film_area * cosine * cosine * cosine);
+    return (pdf / cosine, pdf);
+  }
   fn multisample(
     &self,
     configuration: &RenderConfiguration,
@@ -45,7 +90,7 @@ impl PerspectiveCamera {
     depth: u32,
   ) -> (Vector, f32, usize) {
     let sample_radius = radius / 4.0;
-    let noise_radius = sample_radius / 2.0;;
+    let noise_radius = sample_radius / 2.0;
     let positions = [
       (x - sample_radius, y - sample_radius),
       (x - sample_radius, y + sample_radius),
@@ -73,7 +118,7 @@ impl PerspectiveCamera {
       .iter()
       .map(|((x, y), r)| {
         let (c, d) = configuration.scene().colour_and_depth_for_ray(configuration, r);
-        return ((*x, *y), (c.powf(self.gamma), d));
+        return ((*x, *y), (c, d));
       })
       .collect();
     let (average_colour, average_distance): (Vector, f32) = samples.iter().fold(
@@ -88,13 +133,12 @@ impl PerspectiveCamera {
     return samples.iter().fold(
       (Vector::new(), 0.0f32, 0),
       |(current_value, current_max_distance, current_count), ((x, y), (a, distance))| {
-        let (value, distance, count) = if ((*a - average_colour).length() > DELTA && depth < MAX_DEPTH)
+        let colour_difference = (self.display_colour(*a) - self.display_colour(average_colour)).length();
+        let (value, distance, count) = if (colour_difference > DELTA && depth < MAX_DEPTH)
           || ((average_distance - distance).abs() > DELTA && depth < 2)
         {
           let (v, distance, count) = self.multisample(configuration, *x, *y, radius / 2.0, depth + 1);
-          let one = Vector::splat(1.0);
-          let mask = v.lt(one);
-          (mask.select(v, one), distance.max(current_max_distance), count)
+          (v, distance.max(current_max_distance), count)
         } else {
           (*a, *distance, subsample_count)
         };
@@ -146,23 +190,46 @@ impl PerspectiveCamera {
       samples_per_pixel,
       do_multisampling,
       gamma,
+      aovs: vec![],
     };
   }
+
+  /// Requests that render also fills the given AOVs from the first hit of
+  /// each pixel's primary ray.
+  pub fn with_aovs(mut self, aovs: &[AOV]) -> PerspectiveCamera {
+    self.aovs = aovs.to_vec();
+    return self;
+  }
 }
 
+/// Per pixel linear colour, sample count and depth. Gamma correction is only
+/// applied when the buffer is converted for display or saved to an 8 bit format.
 pub struct RenderBuffer {
   data: Vec<(Vector, usize, f64)>,
+  aovs: Vec<(AOV, Vec<Vector>)>,
   pub width: usize,
   pub height: usize,
 }
 
 impl RenderBuffer {
   pub fn new(width: usize, height: usize) -> Self {
+    return Self::with_aovs(width, height, &[]);
+  }
+  pub fn with_aovs(width: usize, height: usize, aovs: &[AOV]) -> Self {
     let mut data = Vec::with_capacity(width * height);
     for _ in 0..width * height {
       data.push((Vector::new(), 0, std::f64::INFINITY));
     }
-    return RenderBuffer { width, height, data };
+    let aovs = aovs
+      .iter()
+      .map(|aov| (*aov, vec![Vector::new(); width * height]))
+      .collect();
+    return RenderBuffer {
+      width,
+      height,
+      data,
+      aovs,
+    };
   }
   pub fn get(&self, x: usize, y: usize) -> (Vector, usize, f64) {
     return self.data[y * self.width + x];
@@ -170,6 +237,22 @@ impl RenderBuffer {
   pub fn set(&mut self, x: usize, y: usize, sample: (Vector, usize, f64)) {
     self.data[y * self.width + x] = sample;
   }
+  pub fn aovs(&self) -> Vec<AOV> {
+    return self.aovs.iter().map(|(aov, _)| *aov).collect();
+  }
+  pub fn get_aov(&self, aov: AOV, x: usize, y: usize) -> Option<Vector> {
+    return self
+      .aovs
+      .iter()
+      .find(|(buffer_aov, _)| *buffer_aov == aov)
+      .map(|(_, data)| data[y * self.width + x]);
+  }
+  pub fn set_aovs(&mut self, x: usize, y: usize, sample: &Option<AOVSample>) {
+    let index = y * self.width + x;
+    for (aov, data) in self.aovs.iter_mut() {
+      data[index] = aov.value(sample);
+    }
+  }
   pub fn to_pixel_array(&self, gamma: f32) -> Vec<u8> {
     let stride = 3;
     let pitch = stride * self.width;
@@ -188,11 +271,25 @@ impl RenderBuffer {
     }
     return result;
   }
+
+  /// Saves the buffer to `path`, choosing the format from the extension.
+  /// PNG and JPEG output is gamma corrected, PFM and Radiance HDR output
+  /// holds the linear colour values. EXR output additionally holds the depth
+  /// and sample count channels.
+  pub fn save<P: AsRef<Path>>(&self, path: P, gamma: f32) -> std::io::Result<()> {
+    return image_output::save(self, path.as_ref(), gamma);
+  }
+
+  /// Saves the linear colour, depth (Z), sample count (samples) and any AOVs
+  /// as float channels of a single OpenEXR file.
+  pub fn save_exr<P: AsRef<Path>>(&self, path: P, compression: ExrCompression) -> std::io::Result<()> {
+    return image_output::save_exr(self, path.as_ref(), compression);
+  }
 }
 
 impl Camera for PerspectiveCamera {
   fn render(&self, configuration: &Arc<RenderConfiguration>) -> RenderBuffer {
-    let mut buffer = RenderBuffer::new(self._width, self._height);
+    let mut buffer = RenderBuffer::with_aovs(self._width, self._height, &self.aovs);
     let mut first_sample_queue = DispatchQueue::default();
     {
       let _t = Timing::new("Generating first sample set");
@@ -208,24 +305,23 @@ impl Camera for PerspectiveCamera {
       let result = {
         let _t = Timing::new("First render pass");
         let configuration = configuration.clone();
+        let need_aovs = !self.aovs.is_empty();
         first_sample_queue.consume_tasks(&move |v| {
           let (x, y, ray) = v;
-          let (colour, depth) = configuration.scene().colour_and_depth_for_ray(&configuration, &ray);
-          return (*x, *y, (colour, 1, depth));
+          let scene = configuration.scene();
+          if need_aovs {
+            let (colour, depth, first_hit) = scene.colour_depth_and_aovs_for_ray(&configuration, &ray);
+            return (*x, *y, (colour, 1, depth), first_hit);
+          }
+          let (colour, depth) = scene.colour_and_depth_for_ray(&configuration, &ray);
+          return (*x, *y, (colour, 1, depth), None);
         })
       };
 
       let _t = Timing::new("Copy first render results");
-      for (x, y, (v, i, f)) in result {
-        buffer.set(
-          x,
-          y,
-          (
-            v.powf(self.gamma).clamp(Vector::splat(0.0), Vector::splat(1.0)),
-            i,
-            f as f64,
-          ),
-        );
+      for (x, y, (v, i, f), first_hit) in result {
+        buffer.set(x, y, (v, i, f as f64));
+        buffer.set_aovs(x, y, &first_hit);
       }
     }
 
@@ -248,7 +344,7 @@ impl Camera for PerspectiveCamera {
                   continue;
                 }
                 let (colour, _, distance) = buffer.get((x as i32 + i) as usize, (y as i32 + j) as usize);
-                if (colour - sample_colour).length() > DELTA
+                if (self.display_colour(colour) - self.display_colour(sample_colour)).length() > DELTA
                   || (sample_distance - distance).abs().sqrt() > 4.0 * DELTA as f64
                 {
                   multisample_queue.add_task(&(x, y));
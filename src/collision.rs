@@ -6,6 +6,9 @@ pub struct Collision {
   pub uv: Vec2d,
   pub intersection_count: usize,
   pub node_count: usize,
+  // The index of the element that was hit in the outermost BVH, i.e. the
+  // object that was added to the scene.
+  pub object_index: usize,
 }
 
 impl Collision {
@@ -15,6 +18,7 @@ impl Collision {
       uv,
       intersection_count: 0,
       node_count: 0,
+      object_index: 0,
     }
   }
 }
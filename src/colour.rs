@@ -20,6 +20,11 @@ impl Colour {
     let Colour::RGB(r, g, b) = self;
     return r.max(*g).max(*b);
   }
+  /// The luminance of a linear sRGB colour.
+  pub fn luminance(&self) -> f32 {
+    let Colour::RGB(r, g, b) = *self;
+    return 0.2126 * r + 0.7152 * g + 0.0722 * b;
+  }
   pub fn r(&self) -> f32 {
     let Colour::RGB(r, _, _) = *self;
     return r;
@@ -1,14 +1,16 @@
-use crate::photon_map::random;
 use crate::scene::Scene;
-use crate::light::LightSample;
-use crate::material::MaterialCollisionInfo;
+use crate::light::Emitter;
+use crate::light_tree::LightTree;
+use crate::material::{cross_media_boundary, scattered_context, MaterialCollisionInfo};
 use std::sync::Arc;
 use crate::render_configuration::SampleLighting;
 use crate::colour::Colour;
+use crate::either::Either;
 use crate::fragment::Fragment;
 use crate::render_configuration::LightingIntegrator;
 use crate::ray::Ray;
-use crate::vectors::Vector;
+use crate::sampling::{power_heuristic, random_pair};
+use crate::vectors::{Point, Vector};
 
 pub trait IndirectLightingSource: Sync + Send {
   fn lighting_and_shadow(
@@ -19,60 +21,397 @@ pub trait IndirectLightingSource: Sync + Send {
   ) -> (Option<Colour>, Option<bool>);
 }
 
+// Looks up the indirect lighting of the surface, on both sides of it if it
+// scatters to both, see MaterialCollisionInfo::back_face. Shadow rays can
+// only be skipped if neither side needs them.
+fn two_sided_lighting(
+  source: &IndirectLightingSource,
+  scene: &Scene,
+  fragment: &Fragment,
+  surface: &MaterialCollisionInfo,
+) -> (Option<Colour>, Option<bool>) {
+  let (lighting, had_shadow) = source.lighting_and_shadow(scene, fragment, surface);
+  let back = match surface.back_face() {
+    Some(back) => back,
+    None => return (lighting, had_shadow),
+  };
+  let (back_lighting, back_had_shadow) = source.lighting_and_shadow(scene, fragment, &back);
+  let lighting = match (lighting, back_lighting) {
+    (None, None) => None,
+    (front, back) => Some((front.unwrap_or(Colour::new()) + back.unwrap_or(Colour::new())) * 0.5),
+  };
+  let had_shadow = match (had_shadow, back_had_shadow) {
+    (Some(front), Some(back)) => Some(front || back),
+    _ => None,
+  };
+  return (lighting, had_shadow);
+}
+
 pub struct DirectLighting {
   indirect_lighting: Option<Arc<IndirectLightingSource>>,
-  lights: Arc<Vec<LightSample>>,
+  caustics: Option<Arc<IndirectLightingSource>>,
+  light_tree: Arc<LightTree>,
+  // The light for the environment map, if the scene has one.
+  environment_light: Option<usize>,
+  // The total area of the emissive surfaces, which share the legacy ambient
+  // term between them.
+  emitter_area: f32,
+  light_samples: usize,
+  bsdf_samples: usize,
 }
 
 impl DirectLighting {
-  pub fn new(_: &Arc<Scene>, lights: Vec<LightSample>, indirect_lighting: Option<Arc<IndirectLightingSource>>) -> Self {
+  /// Lights the scene with the number of light and BSDF samples from its
+  /// settings.
+  pub fn new(scene: &Arc<Scene>, indirect_lighting: Option<Arc<IndirectLightingSource>>) -> Self {
+    let emitter_area = (0..scene.light_count())
+      .map(|index| scene.light(index))
+      .filter(|(_, bounds)| bounds.is_some())
+      .map(|(light, _)| light.get_area())
+      .sum();
+    let settings = scene.settings();
     return DirectLighting {
       indirect_lighting,
-      lights: Arc::new(lights),
+      caustics: None,
+      light_tree: Arc::new(LightTree::for_scene(scene)),
+      environment_light: scene.environment_light(),
+      emitter_area,
+      light_samples: settings.light_samples,
+      bsdf_samples: settings.bsdf_samples,
     };
   }
-}
 
-impl LightingIntegrator for DirectLighting {
-  fn lighting(&self, scene: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> SampleLighting {
-    let (photon_lighting, had_shadow) = if let Some(ref photon_map) = self.indirect_lighting {
-      photon_map.lighting_and_shadow(scene, fragment, surface)
-    } else {
-      (None, None)
+  /// Sets how many light samples and BSDF samples are taken at each shading
+  /// point. The two are combined with multiple importance sampling, so BSDF
+  /// samples help with small bright emitters that light sampling rarely
+  /// picks.
+  pub fn with_samples(mut self, light_samples: usize, bsdf_samples: usize) -> Self {
+    self.light_samples = light_samples;
+    self.bsdf_samples = bsdf_samples;
+    return self;
+  }
+
+  // The normal that light tree sampling ignores the lights behind, unless
+  // the surface scatters light through itself.
+  fn light_tree_normal(surface: &MaterialCollisionInfo) -> Option<Vector> {
+    if surface.bsdf.transmits() {
+      return None;
+    }
+    return Some(surface.normal);
+  }
+
+  // A shadow ray from the shading point, which starts in the medium on the
+  // side of the surface that it leaves from.
+  fn shadow_ray(fragment: &Fragment, surface: &MaterialCollisionInfo, direction: Vector, max: f32) -> Ray {
+    let context = scattered_context(&fragment.ray_context, fragment, surface, direction);
+    return Ray::new_bound(surface.position, direction, 0.005, max, Some(context));
+  }
+
+  // The pdf, with respect to solid angle, of light sampling picking `point`
+  // on the light with the given index, whose normal there is `light_normal`.
+  // Surfaces are picked as a whole and then sampled uniformly, so the pdf
+  // with respect to surface area is the probability of the pick over the
+  // area of the light.
+  fn light_pdf(
+    &self,
+    scene: &Scene,
+    surface: &MaterialCollisionInfo,
+    index: usize,
+    point: Point,
+    light_normal: Option<Vector>,
+  ) -> f32 {
+    let offset = point - surface.position;
+    let distance = offset.length();
+    let light_cosine = match light_normal {
+      Some(normal) => normal.dot(offset / distance).abs(),
+      None => 1.0,
     };
+    if light_cosine <= 0.0 {
+      return 0.0;
+    }
+    let tree_normal = DirectLighting::light_tree_normal(surface);
+    let probability = self.light_tree.probability(surface.position, tree_normal, index);
+    return probability * distance * distance / (light_cosine * scene.light(index).0.get_area());
+  }
 
-    let light_samples = 50;
-    let lights = &*self.lights;
-    let light_scale = lights.len() as f32 / light_samples as f32;
-    let mut diffuse_lighting = Vector::new();
+  // Samples the emitters through the light tree, at a fresh point on each
+  // light that it picks. Returns the light reflected by the BSDF, and the
+  // legacy ambient term of the lights that were not in shadow.
+  fn sample_lights(
+    &self,
+    scene: &Scene,
+    fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+    had_shadow: Option<bool>,
+  ) -> (Vector, Vector) {
+    let mut reflected_lighting = Vector::new();
     let mut ambient_lighting = Vector::new();
-    let mut _specular_lighting = Vector::new();
-
-    for _ in 0..light_samples {
-      let light = &lights[random(0.0, lights.len() as f64) as usize];
-      let mut ldir = light.position - surface.position;
-      let ldir_len = ldir.length();
-      ldir = ldir.normalize();
-      if false && ldir.dot(surface.normal) < 0.0 {
+    if scene.light_count() == 0 || self.light_samples == 0 {
+      return (reflected_lighting, ambient_lighting);
+    }
+    let wo = -fragment.view.normalize();
+    let tree_normal = DirectLighting::light_tree_normal(surface);
+    // The lights are described in RGB, and converted to the spectrum of the
+    // path if it carries wavelengths.
+    let context = &fragment.ray_context;
+    for _ in 0..self.light_samples {
+      let (index, probability) = match self.light_tree.sample(surface.position, tree_normal) {
+        Some(sample) => sample,
+        None => continue,
+      };
+      let (light, bounds) = scene.light(index);
+      let light = match light.get_samples(1, scene).pop() {
+        Some(light) => light,
+        None => continue,
+      };
+      if light.emitter == Emitter::Environment {
+        reflected_lighting =
+          reflected_lighting + self.sample_environment(scene, fragment, surface, probability, had_shadow);
         continue;
       }
+      let (ldir, distance) = light.direction_from(surface.position);
+      let mut transmittance = Colour::RGB(1.0, 1.0, 1.0);
       if had_shadow.unwrap_or(true) {
-        let shadow_test = Ray::new_bound(surface.position, ldir, 0.005, ldir_len - 0.001, None);
-        if scene.has_intersection(&shadow_test) {
+        let shadow_test = DirectLighting::shadow_ray(fragment, surface, ldir, distance - 0.001);
+        transmittance = scene.transmittance(&shadow_test);
+        if transmittance.max_value() <= 0.0 {
           continue;
         }
       }
+      let transmittance = Vector::from(transmittance);
+      let light_scale = 1.0 / (probability * self.light_samples as f32);
 
-      let diffuse_intensity = light_scale * light.weight * ldir.dot(surface.normal).max(0.0);
-      let ambient_intensity = light_scale * light.weight * light.ambient;
-      diffuse_lighting = diffuse_lighting + light.diffuse * diffuse_intensity;
-      ambient_lighting = ambient_lighting + light.ambient * ambient_intensity;
+      // Emissive surfaces share the ambient term by their area.
+      let ambient_weight = match bounds {
+        Some(_) => light.area / self.emitter_area,
+        None => light.weight,
+      };
+      let ambient_intensity = light_scale * ambient_weight * light.ambient;
+      ambient_lighting = ambient_lighting
+        + context.upsample_emission(light.ambient, light.emission.blackbody) * ambient_intensity * transmittance;
+
+      let reflectance = Vector::from(surface.bsdf.eval(wo, ldir));
+      let surface_cosine = ldir.dot(surface.bsdf.normal()).abs();
+      if light.is_delta() {
+        // BSDF samples can never reach point or directional lights, so light
+        // sampling gets all of the weight.
+        let irradiance = context.upsample_emission(light.delta_irradiance(ldir, distance), light.emission.blackbody)
+          * (surface_cosine * light_scale);
+        reflected_lighting = reflected_lighting + irradiance * reflectance * transmittance;
+        continue;
+      }
+      let light_pdf = self.light_pdf(scene, surface, index, light.position, light.direction);
+      if light_pdf <= 0.0 {
+        continue;
+      }
+      let bsdf_pdf = surface.bsdf.pdf(wo, ldir);
+      let weight = power_heuristic(self.light_samples, light_pdf, self.bsdf_samples, bsdf_pdf);
+      let scale = weight * surface_cosine / (light_pdf * self.light_samples as f32);
+      reflected_lighting = reflected_lighting
+        + context.upsample_emission(light.radiance, light.emission.blackbody) * reflectance * transmittance * scale;
     }
+    return (reflected_lighting, ambient_lighting);
+  }
+
+  // A light sample for the environment map, which was picked with the given
+  // probability, and then picks a direction from the map.
+  fn sample_environment(
+    &self,
+    scene: &Scene,
+    fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+    probability: f32,
+    had_shadow: Option<bool>,
+  ) -> Vector {
+    let (direction, radiance, pdf) = match scene.environment().and_then(|environment| environment.sample()) {
+      Some(sample) => sample,
+      None => return Vector::new(),
+    };
+    let wo = -fragment.view.normalize();
+    let reflectance = Vector::from(surface.bsdf.eval(wo, direction));
+    if reflectance.length() <= 0.0 {
+      return Vector::new();
+    }
+    let mut transmittance = Colour::RGB(1.0, 1.0, 1.0);
+    if had_shadow.unwrap_or(true) {
+      let shadow_test = DirectLighting::shadow_ray(fragment, surface, direction, std::f32::INFINITY);
+      transmittance = scene.transmittance(&shadow_test);
+      if transmittance.max_value() <= 0.0 {
+        return Vector::new();
+      }
+    }
+    let light_pdf = probability * pdf;
+    let surface_cosine = direction.dot(surface.bsdf.normal()).abs();
+    let bsdf_pdf = surface.bsdf.pdf(wo, direction);
+    let weight = power_heuristic(self.light_samples, light_pdf, self.bsdf_samples, bsdf_pdf);
+    let scale = weight * surface_cosine / (light_pdf * self.light_samples as f32);
+    return fragment.ray_context.upsample(radiance) * reflectance * Vector::from(transmittance) * scale;
+  }
+
+  // Samples the BSDF, counting the emitters that the rays reach. Delta lobes
+  // are followed by Scene::intersect_ray, so samples of them count for
+  // nothing here.
+  fn sample_bsdf(&self, scene: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> Vector {
+    let wo = -fragment.view.normalize();
+    let mut reflected_lighting = Vector::new();
+    for _ in 0..self.bsdf_samples {
+      let sample = match surface.bsdf.sample(wo, random_pair()) {
+        Some(sample) if !sample.delta => sample,
+        _ => continue,
+      };
+      let ray = DirectLighting::shadow_ray(fragment, surface, sample.direction, std::f32::INFINITY);
+      let emission = self.bsdf_sample_emission(scene, surface, ray, sample.pdf);
+      reflected_lighting = reflected_lighting + emission * Vector::from(sample.weight);
+    }
+    return reflected_lighting / self.bsdf_samples.max(1) as f32;
+  }
+
+  // The emission reached by a single BSDF sample, weighted against light
+  // sampling. Like shadow rays, the ray passes through the boundaries of
+  // media, and it sees nothing if a medium scatters it on the way.
+  fn bsdf_sample_emission(
+    &self,
+    scene: &Scene,
+    surface: &MaterialCollisionInfo,
+    mut ray: Ray,
+    bsdf_pdf: f32,
+  ) -> Vector {
+    let direction = ray.direction;
+    let mut attenuation = Vector::vector(1.0, 1.0, 1.0);
+    loop {
+      let (collision, shadable) = match scene.intersect(&ray) {
+        None => {
+          // Rays that leave the scene see the environment.
+          let environment = match scene.environment() {
+            Some(environment) => environment,
+            None => return Vector::new(),
+          };
+          let light_pdf = match self.environment_light {
+            Some(index) => {
+              let tree_normal = DirectLighting::light_tree_normal(surface);
+              self.light_tree.probability(surface.position, tree_normal, index) * environment.pdf(direction)
+            }
+            None => 0.0,
+          };
+          let weight = power_heuristic(self.bsdf_samples, bsdf_pdf, self.light_samples, light_pdf);
+          return ray.ray_context.upsample(environment.radiance(direction)) * weight;
+        }
+        Some((_, Either::Right(_))) => return Vector::new(),
+        Some((c, Either::Left(e))) => (c, e),
+      };
+      attenuation = attenuation * Vector::from(scene.attenuation(&ray, collision.distance));
+      let fragment = shadable.compute_fragment(scene, &ray, &collision);
+      let hit = scene
+        .get_material(fragment.material)
+        .compute_surface_properties(scene, &ray, &fragment)
+        .upsampled(&ray.ray_context);
+      if let Some(continued_ray) = cross_media_boundary(&ray, &fragment, &hit) {
+        ray = continued_ray;
+        continue;
+      }
+      let emission = match hit.emitted_colour() {
+        Some(emission) => emission,
+        None => return Vector::new(),
+      };
+      // Light sampling could only have reached the point if the emitter is
+      // one of the scene's lights.
+      let light_pdf = match scene.emitter_index(shadable) {
+        Some(index) => self.light_pdf(scene, surface, index, hit.position, Some(hit.normal)),
+        None => 0.0,
+      };
+      let weight = power_heuristic(self.bsdf_samples, bsdf_pdf, self.light_samples, light_pdf);
+      return Vector::from(emission) * attenuation * weight;
+    }
+  }
+
+  /// Adds the light focused onto diffuse surfaces by specular ones. The
+  /// caustics must not also be present in the indirect lighting, see
+  /// DiffuseSelector::excluding_caustics.
+  pub fn with_caustics(mut self, caustics: Arc<IndirectLightingSource>) -> Self {
+    self.caustics = Some(caustics);
+    return self;
+  }
+}
+
+impl LightingIntegrator for DirectLighting {
+  fn lighting(&self, scene: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> SampleLighting {
+    let (photon_lighting, had_shadow) = if let Some(ref photon_map) = self.indirect_lighting {
+      two_sided_lighting(&**photon_map, scene, fragment, surface)
+    } else {
+      (None, None)
+    };
+    // Caustic maps hold no shadow photons, so only the indirect lighting can
+    // tell us whether shadow rays are needed.
+    let caustic_lighting = match self.caustics {
+      Some(ref caustic_map) => two_sided_lighting(&**caustic_map, scene, fragment, surface).0,
+      None => None,
+    };
+    // Indirect lighting sources work in RGB.
+    let photon_lighting = photon_lighting.map(|lighting| fragment.ray_context.upsample(lighting));
+    let caustic_lighting = caustic_lighting.map(|lighting| fragment.ray_context.upsample(lighting));
+
+    let (light_lighting, ambient_lighting) = self.sample_lights(scene, fragment, surface, had_shadow);
+    let reflected_lighting = light_lighting + self.sample_bsdf(scene, fragment, surface);
 
     return SampleLighting {
-      diffuse: Colour::from(diffuse_lighting),
+      diffuse: caustic_lighting.unwrap_or(Colour::new()),
       ambient: photon_lighting.unwrap_or(Colour::from(ambient_lighting)),
-      specular: Colour::new(),
+      reflected: Colour::from(reflected_lighting),
     };
   }
 }
+
+#[test]
+fn test_single_emitter_weights() {
+  use crate::bsdf::{Bsdf, Lobe};
+  use crate::material::EmissiveMaterial;
+  use crate::scene::SceneSettings;
+  use crate::sphere::Sphere;
+  use crate::vectors::VectorType;
+
+  let mut settings = SceneSettings::new();
+  settings.scene_file = std::env::temp_dir().to_string_lossy().to_string();
+  let mut scene = Scene::new(&settings);
+  let (material, _) = scene.get_or_create_material("lamp", |_| {
+    Some(Box::new(EmissiveMaterial::new(Colour::RGB(1.0, 1.0, 1.0), 1.0)))
+  });
+  scene.add_object(Box::new(Sphere::new(Vector::point(0.5, 2.0, 0.0), 0.5, material)));
+  scene.finalize();
+  let scene = Arc::new(scene);
+  let lighting = DirectLighting::new(&scene, None);
+  assert_eq!((lighting.light_samples, lighting.bsdf_samples), (50, 4));
+
+  let normal = Vector::vector(0.0, 1.0, 0.0);
+  let albedo = Colour::RGB(0.5, 0.5, 0.5);
+  let surface = MaterialCollisionInfo {
+    ambient_colour: albedo,
+    diffuse_colour: albedo,
+    specular_colour: albedo,
+    emissive_colour: None,
+    position: Vector::point(0.0, 0.0, 0.0),
+    normal,
+    interior: None,
+    bsdf: Bsdf::new(normal, normal).with_lobe(Lobe::Diffuse(albedo)),
+  };
+  let mut checked = 0;
+  for _ in 0..200 {
+    // A point that light sampling picks, which a BSDF sample in the same
+    // direction reaches unless it's on the far side of the sphere.
+    let (index, _) = lighting.light_tree.sample(surface.position, Some(normal)).unwrap();
+    let light = scene.light(index).0.get_samples(1, &scene).pop().unwrap();
+    let (direction, distance) = light.direction_from(surface.position);
+    let ray = Ray::new(surface.position, direction, None);
+    let (collision, _) = scene.intersect(&ray).unwrap();
+    if (collision.distance - distance).abs() > 0.001 {
+      continue;
+    }
+    let light_pdf = lighting.light_pdf(&scene, &surface, index, light.position, light.direction);
+    let bsdf_pdf = surface.bsdf.pdf(normal, direction);
+    let light_weight = power_heuristic(lighting.light_samples, light_pdf, lighting.bsdf_samples, bsdf_pdf);
+    // The emission is one, so the BSDF sample sees just its weight.
+    let bsdf_weight = lighting.bsdf_sample_emission(&scene, &surface, ray, bsdf_pdf).x();
+    assert!((light_weight + bsdf_weight - 1.0).abs() < 0.001);
+    checked += 1;
+  }
+  assert!(checked > 0);
+}
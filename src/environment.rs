@@ -0,0 +1,361 @@
+use crate::analytic_light::distant_sample;
+use crate::light::{Emitter, Light, LightSample};
+use crate::photon_map::random;
+use crate::scene::Scene;
+use crate::vectors::{Vector, VectorType};
+use image::hdr::HDRDecoder;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+fn luminance(colour: Vector) -> f32 {
+  return 0.2126 * colour.x() + 0.7152 * colour.y() + 0.0722 * colour.z();
+}
+
+// Turns the weights into a cumulative distribution with one more entry than
+// there are weights, returning it with the total weight. A distribution with
+// no weight at all is left uniform.
+fn cumulative(weights: &[f32]) -> (Vec<f32>, f32) {
+  let mut cdf = Vec::with_capacity(weights.len() + 1);
+  let mut total = 0.0;
+  cdf.push(0.0);
+  for weight in weights {
+    total += weight;
+    cdf.push(total);
+  }
+  for (index, value) in cdf.iter_mut().enumerate() {
+    *value = if total > 0.0 {
+      *value / total
+    } else {
+      index as f32 / weights.len() as f32
+    };
+  }
+  return (cdf, total);
+}
+
+// The entry of the cumulative distribution that `value` falls in. Entries
+// with no weight are never picked, as their end is the start of the next.
+fn find_interval(cdf: &[f32], value: f32) -> usize {
+  let mut low = 0;
+  let mut high = cdf.len() - 1;
+  while high - low > 1 {
+    let middle = (low + high) / 2;
+    if cdf[middle] <= value {
+      low = middle;
+    } else {
+      high = middle;
+    }
+  }
+  return low;
+}
+
+fn read_hdr<R: BufRead>(reader: R) -> Result<(usize, usize, Vec<Vector>), String> {
+  let decoder = HDRDecoder::new(reader).map_err(|msg| msg.to_string())?;
+  let metadata = decoder.metadata();
+  let pixels = decoder.read_image_hdr().map_err(|msg| msg.to_string())?;
+  let pixels = pixels
+    .iter()
+    .map(|pixel| Vector::vector(pixel.data[0] as f64, pixel.data[1] as f64, pixel.data[2] as f64))
+    .collect();
+  return Ok((metadata.width as usize, metadata.height as usize, pixels));
+}
+
+// Reads a colour or greyscale PFM, the format image_output::write_pfm writes.
+// The header is four whitespace separated fields followed by a single
+// whitespace character, and the sign of the scale gives the byte order.
+fn read_pfm<R: Read>(mut reader: R) -> Result<(usize, usize, Vec<Vector>), String> {
+  let mut data = vec![];
+  reader.read_to_end(&mut data).map_err(|msg| msg.to_string())?;
+  let mut fields = vec![];
+  let mut offset = 0;
+  while fields.len() < 4 {
+    while offset < data.len() && data[offset].is_ascii_whitespace() {
+      offset += 1;
+    }
+    let start = offset;
+    while offset < data.len() && !data[offset].is_ascii_whitespace() {
+      offset += 1;
+    }
+    if start == offset {
+      return Err("Truncated PFM header".to_string());
+    }
+    fields.push(String::from_utf8_lossy(&data[start..offset]).to_string());
+  }
+  offset += 1;
+
+  let channels = match fields[0].as_str() {
+    "PF" => 3,
+    "Pf" => 1,
+    other => return Err(format!("Unknown PFM type '{}'", other)),
+  };
+  let bad_field = |field: &String| format!("Bad PFM header field '{}'", field);
+  let width: usize = fields[1].parse().map_err(|_| bad_field(&fields[1]))?;
+  let height: usize = fields[2].parse().map_err(|_| bad_field(&fields[2]))?;
+  let scale: f32 = fields[3].parse().map_err(|_| bad_field(&fields[3]))?;
+  if data.len() < offset + width * height * channels * 4 {
+    return Err("Truncated PFM data".to_string());
+  }
+
+  let value = |index: usize| {
+    let start = offset + index * 4;
+    let bytes = [data[start], data[start + 1], data[start + 2], data[start + 3]];
+    let bits = if scale < 0.0 {
+      u32::from_le_bytes(bytes)
+    } else {
+      u32::from_be_bytes(bytes)
+    };
+    return f32::from_bits(bits) as f64;
+  };
+  let mut pixels = Vec::with_capacity(width * height);
+  for y in (0..height).rev() {
+    for x in 0..width {
+      let index = (y * width + x) * channels;
+      pixels.push(if channels == 3 {
+        Vector::vector(value(index), value(index + 1), value(index + 2))
+      } else {
+        Vector::vector(value(index), value(index), value(index))
+      });
+    }
+  }
+  return Ok((width, height, pixels));
+}
+
+/// Light arriving from infinitely far away in every direction, from an
+/// equirectangular image. The top of the image looks straight up along +y,
+/// and the centre looks along -z, before the map is rotated around the y
+/// axis.
+///
+/// Directions are importance sampled in proportion to the luminance of the
+/// image, so that small bright features such as the sun are found by light
+/// sampling.
+#[derive(Debug)]
+pub struct EnvironmentMap {
+  width: usize,
+  height: usize,
+  // The rows of the image from the top.
+  pixels: Vec<Vector>,
+  rotation: f32,
+  // Pixels are picked in proportion to their luminance times the sine of
+  // their row's angle from the pole, which is proportional to the solid angle
+  // they cover. Each row has its own distribution, and `marginal_cdf` picks
+  // the row.
+  row_cdfs: Vec<Vec<f32>>,
+  marginal_cdf: Vec<f32>,
+  total_weight: f32,
+  // The radiance integrated over the sphere of directions.
+  power: Vector,
+}
+
+impl EnvironmentMap {
+  pub fn new(width: usize, height: usize, pixels: Vec<Vector>) -> Self {
+    assert!(width > 0 && height > 0);
+    assert_eq!(pixels.len(), width * height);
+    let mut row_cdfs = Vec::with_capacity(height);
+    let mut row_weights = Vec::with_capacity(height);
+    let mut power = Vector::new();
+    for y in 0..height {
+      let row = &pixels[y * width..(y + 1) * width];
+      let top = PI * y as f32 / height as f32;
+      let bottom = PI * (y + 1) as f32 / height as f32;
+      let solid_angle = 2.0 * PI / width as f32 * (top.cos() - bottom.cos());
+      let sin_theta = Self::row_sin_theta(y, height);
+      let weights: Vec<f32> = row.iter().map(|pixel| luminance(*pixel).max(0.0) * sin_theta).collect();
+      for pixel in row {
+        power = power + *pixel * solid_angle;
+      }
+      let (cdf, total) = cumulative(&weights);
+      row_cdfs.push(cdf);
+      row_weights.push(total);
+    }
+    let (marginal_cdf, total_weight) = cumulative(&row_weights);
+    return EnvironmentMap {
+      width,
+      height,
+      pixels,
+      rotation: 0.0,
+      row_cdfs,
+      marginal_cdf,
+      total_weight,
+      power,
+    };
+  }
+
+  /// Loads a Radiance `.hdr` or `.pfm` image, scaling its radiance by
+  /// `intensity`.
+  pub fn load(path: &Path, intensity: f32) -> Self {
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(msg) => panic!("Fopen({:?}) failed with {}", path, msg),
+    };
+    let reader = BufReader::new(file);
+    let extension = path.extension().map(|e| e.to_str().unwrap().to_lowercase());
+    let result = match extension.as_ref().map(|e| e.as_str()) {
+      Some("hdr") => read_hdr(reader),
+      Some("pfm") => read_pfm(reader),
+      _ => panic!("Unsupported environment map {:?}", path),
+    };
+    let (width, height, pixels) = match result {
+      Ok(image) => image,
+      Err(msg) => panic!("Failed to open {:?} with error: {}", path, msg),
+    };
+    return EnvironmentMap::new(width, height, pixels.iter().map(|pixel| *pixel * intensity).collect());
+  }
+
+  /// Rotates the map around the y axis by the angle in degrees.
+  pub fn with_rotation(mut self, degrees: f32) -> Self {
+    self.rotation = degrees * PI / 180.0;
+    return self;
+  }
+
+  fn row_sin_theta(y: usize, height: usize) -> f32 {
+    return (PI * (y as f32 + 0.5) / height as f32).sin();
+  }
+
+  // The position in the image, from 0 to 1 on each axis, that looks along the
+  // direction.
+  fn coordinates(&self, direction: Vector) -> (f32, f32) {
+    let direction = direction.normalize();
+    // acos loses too much precision near the poles.
+    let theta = (direction.x() * direction.x() + direction.z() * direction.z())
+      .sqrt()
+      .atan2(direction.y());
+    let phi = direction.x().atan2(-direction.z()) - self.rotation;
+    return ((phi / (2.0 * PI) + 0.5).rem_euclid(1.0), theta / PI);
+  }
+
+  fn direction(&self, u: f32, v: f32) -> Vector {
+    return Self::direction_for_coordinates(u + self.rotation / (2.0 * PI), v);
+  }
+
+  /// The direction that the position in an unrotated map, from 0 to 1 on
+  /// each axis, looks along.
+  pub fn direction_for_coordinates(u: f32, v: f32) -> Vector {
+    let theta = v * PI;
+    let phi = (u - 0.5) * 2.0 * PI;
+    return Vector::vector(
+      (theta.sin() * phi.sin()) as f64,
+      theta.cos() as f64,
+      (-theta.sin() * phi.cos()) as f64,
+    );
+  }
+
+  fn pixel(&self, u: f32, v: f32) -> (usize, usize) {
+    return (
+      ((u * self.width as f32) as usize).min(self.width - 1),
+      ((v * self.height as f32) as usize).min(self.height - 1),
+    );
+  }
+
+  // A pixel is picked with probability weight / total_weight, and a point
+  // within it uniformly, so the density over the image is that times the
+  // number of pixels. The image spans 2 pi by pi radians, and a unit of
+  // image area covers sin(theta) of solid angle.
+  fn pixel_pdf(&self, x: usize, y: usize, v: f32) -> f32 {
+    let sin_theta = (v * PI).sin();
+    if !(self.total_weight > 0.0) || sin_theta <= 0.0 {
+      return 0.0;
+    }
+    let weight = luminance(self.pixels[y * self.width + x]).max(0.0) * Self::row_sin_theta(y, self.height);
+    let pixel_count = (self.width * self.height) as f32;
+    return weight / self.total_weight * pixel_count / (2.0 * PI * PI * sin_theta);
+  }
+
+  /// The radiance arriving from the direction.
+  pub fn radiance(&self, direction: Vector) -> Vector {
+    let (u, v) = self.coordinates(direction);
+    let (x, y) = self.pixel(u, v);
+    return self.pixels[y * self.width + x];
+  }
+
+  /// Picks a direction towards the environment in proportion to its
+  /// luminance, returning the direction, the radiance arriving along it and
+  /// the pdf with respect to solid angle.
+  pub fn sample(&self) -> Option<(Vector, Vector, f32)> {
+    if !(self.total_weight > 0.0) {
+      return None;
+    }
+    let y = find_interval(&self.marginal_cdf, random(0.0, 1.0) as f32);
+    let x = find_interval(&self.row_cdfs[y], random(0.0, 1.0) as f32);
+    let u = (x as f32 + random(0.0, 1.0) as f32) / self.width as f32;
+    let v = (y as f32 + random(0.0, 1.0) as f32) / self.height as f32;
+    // Points on the edge of a pixel can round into its neighbour, so the
+    // radiance and pdf are looked up from the direction, to always agree
+    // with `radiance` and `pdf`.
+    let direction = self.direction(u, v);
+    let pdf = self.pdf(direction);
+    if !(pdf > 0.0) {
+      return None;
+    }
+    return Some((direction, self.radiance(direction), pdf));
+  }
+
+  /// The pdf of `sample` picking the direction.
+  pub fn pdf(&self, direction: Vector) -> f32 {
+    let (u, v) = self.coordinates(direction);
+    let (x, y) = self.pixel(u, v);
+    return self.pixel_pdf(x, y, v);
+  }
+}
+
+impl Light for EnvironmentMap {
+  fn get_area(&self) -> f32 {
+    return 0.0;
+  }
+
+  fn get_samples(&self, _: usize, scene: &Scene) -> Vec<LightSample> {
+    if !(self.total_weight > 0.0) {
+      return vec![];
+    }
+    return vec![distant_sample(scene, None, self.power, None, Emitter::Environment)];
+  }
+}
+
+#[test]
+fn test_environment_sampling() {
+  let (width, height) = (64, 32);
+  let uniform = EnvironmentMap::new(width, height, vec![Vector::vector(1.0, 1.0, 1.0); width * height]);
+  assert!((uniform.power.x() - 4.0 * PI).abs() < 0.001);
+  let pdf = uniform.pdf(Vector::vector(1.0, 0.0, 0.0));
+  assert!((pdf * 4.0 * PI - 1.0).abs() < 0.01);
+  // The centre of the image looks along -z.
+  let (u, v) = uniform.coordinates(Vector::vector(0.0, 0.0, -1.0));
+  assert_eq!(uniform.pixel(u, v), uniform.pixel(0.5, 0.5));
+
+  // A single bright pixel gets most of the samples, and weighting the
+  // samples by their pdf recovers the radiance over the sphere.
+  let mut pixels = vec![Vector::vector(0.1, 0.1, 0.1); width * height];
+  pixels[10 * width + 40] = Vector::vector(1000.0, 800.0, 600.0);
+  let map = EnvironmentMap::new(width, height, pixels).with_rotation(30.0);
+  let sample_count = 10000;
+  let mut bright = 0;
+  let mut power = Vector::new();
+  for _ in 0..sample_count {
+    let (_, radiance, pdf) = map.sample().unwrap();
+    power = power + radiance * (1.0 / (pdf * sample_count as f32));
+    if radiance.x() > 1.0 {
+      bright += 1;
+    }
+  }
+  // The bright pixel has about 85% of the weight.
+  assert!(bright > sample_count * 3 / 4);
+  assert!((power.x() / map.power.x() - 1.0).abs() < 0.02);
+}
+
+#[test]
+fn test_read_pfm() {
+  use crate::camera::RenderBuffer;
+  use crate::image_output::write_pfm;
+  let mut buffer = RenderBuffer::new(3, 2);
+  for y in 0..2 {
+    for x in 0..3 {
+      buffer.set(x, y, (Vector::vector(x as f64, y as f64, 0.5), 1, 1.0));
+    }
+  }
+  let mut data = vec![];
+  write_pfm(&buffer, &mut data).unwrap();
+  let (width, height, pixels) = read_pfm(&data[..]).unwrap();
+  assert_eq!((width, height), (3, 2));
+  // The last pixel of the second row from the top.
+  assert_eq!(pixels[5], Vector::vector(2.0, 1.0, 0.5));
+}
@@ -0,0 +1,230 @@
+use crate::aov::AOV;
+use crate::camera::RenderBuffer;
+use crate::vectors::VectorType;
+use std::io::{Result, Write};
+
+// A minimal single part, scanline OpenEXR writer. Every channel is stored as
+// 32 bit float, which keeps the encoder trivial at the cost of file size.
+// See "The OpenEXR File Layout" for the details of the format.
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExrCompression {
+  None,
+  Zip,
+}
+
+impl ExrCompression {
+  fn id(&self) -> u8 {
+    match self {
+      ExrCompression::None => 0,
+      ExrCompression::Zip => 3,
+    }
+  }
+
+  fn scanlines_per_chunk(&self) -> usize {
+    match self {
+      ExrCompression::None => 1,
+      ExrCompression::Zip => 16,
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum ChannelSource {
+  Colour(usize),
+  Depth,
+  SampleCount,
+  AOV(AOV, usize),
+}
+
+// Channels must be written in alphabetical order, in the header and in the pixel data.
+fn channels(buffer: &RenderBuffer) -> Vec<(&'static str, ChannelSource)> {
+  let mut result = vec![
+    ("R", ChannelSource::Colour(0)),
+    ("G", ChannelSource::Colour(1)),
+    ("B", ChannelSource::Colour(2)),
+    ("Z", ChannelSource::Depth),
+    ("samples", ChannelSource::SampleCount),
+  ];
+  for aov in buffer.aovs() {
+    for (component, name) in aov.channel_names().iter().enumerate() {
+      result.push((name, ChannelSource::AOV(aov, component)));
+    }
+  }
+  result.sort_by(|(a, _), (b, _)| a.cmp(b));
+  return result;
+}
+
+fn channel_value(buffer: &RenderBuffer, channel: ChannelSource, x: usize, y: usize) -> f32 {
+  return match channel {
+    ChannelSource::Colour(component) => buffer.get(x, y).0.axis(component),
+    ChannelSource::Depth => buffer.get(x, y).2 as f32,
+    ChannelSource::SampleCount => buffer.get(x, y).1 as f32,
+    ChannelSource::AOV(aov, component) => buffer.get_aov(aov, x, y).unwrap().axis(component),
+  };
+}
+
+fn write_attribute<W: Write>(output: &mut W, name: &str, attribute_type: &str, value: &[u8]) -> Result<()> {
+  output.write_all(name.as_bytes())?;
+  output.write_all(&[0])?;
+  output.write_all(attribute_type.as_bytes())?;
+  output.write_all(&[0])?;
+  output.write_all(&(value.len() as i32).to_le_bytes())?;
+  return output.write_all(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+  let mut result = vec![];
+  for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+    result.extend_from_slice(&value.to_le_bytes());
+  }
+  return result;
+}
+
+fn write_header<W: Write>(output: &mut W, buffer: &RenderBuffer, compression: ExrCompression) -> Result<()> {
+  output.write_all(&MAGIC)?;
+  output.write_all(&VERSION)?;
+
+  let mut channel_list = vec![];
+  for (channel, _) in channels(buffer) {
+    channel_list.extend_from_slice(channel.as_bytes());
+    channel_list.push(0);
+    channel_list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+    // pLinear and three reserved bytes
+    channel_list.extend_from_slice(&[0, 0, 0, 0]);
+    // x and y sampling
+    channel_list.extend_from_slice(&1i32.to_le_bytes());
+    channel_list.extend_from_slice(&1i32.to_le_bytes());
+  }
+  channel_list.push(0);
+
+  let window = box2i(buffer.width, buffer.height);
+  write_attribute(output, "channels", "chlist", &channel_list)?;
+  write_attribute(output, "compression", "compression", &[compression.id()])?;
+  write_attribute(output, "dataWindow", "box2i", &window)?;
+  write_attribute(output, "displayWindow", "box2i", &window)?;
+  write_attribute(output, "lineOrder", "lineOrder", &[0])?;
+  write_attribute(output, "pixelAspectRatio", "float", &1.0f32.to_bits().to_le_bytes())?;
+  write_attribute(output, "screenWindowCenter", "v2f", &[0; 8])?;
+  write_attribute(output, "screenWindowWidth", "float", &1.0f32.to_bits().to_le_bytes())?;
+  return output.write_all(&[0]);
+}
+
+fn raw_chunk(buffer: &RenderBuffer, first_line: usize, last_line: usize) -> Vec<u8> {
+  let channels = channels(buffer);
+  let mut result = Vec::with_capacity((last_line - first_line) * buffer.width * channels.len() * 4);
+  for y in first_line..last_line {
+    for (_, channel) in &channels {
+      for x in 0..buffer.width {
+        result.extend_from_slice(&channel_value(buffer, *channel, x, y).to_bits().to_le_bytes());
+      }
+    }
+  }
+  return result;
+}
+
+// Before deflating, ZIP compression splits the even and odd bytes into two
+// halves and then delta encodes the result.
+fn zip_predictor(raw: &[u8]) -> Vec<u8> {
+  let mut result: Vec<u8> = Vec::with_capacity(raw.len());
+  result.extend(raw.iter().step_by(2));
+  result.extend(raw.iter().skip(1).step_by(2));
+  let mut previous = result[0];
+  for value in result.iter_mut().skip(1) {
+    let current = *value;
+    *value = current.wrapping_sub(previous).wrapping_add(128);
+    previous = current;
+  }
+  return result;
+}
+
+fn compress_chunk(raw: Vec<u8>, compression: ExrCompression) -> Vec<u8> {
+  return match compression {
+    ExrCompression::None => raw,
+    ExrCompression::Zip => {
+      let compressed = deflate::deflate_bytes_zlib(&zip_predictor(&raw));
+      // Readers treat a chunk that is no smaller than the raw data as uncompressed.
+      if compressed.len() < raw.len() {
+        compressed
+      } else {
+        raw
+      }
+    }
+  };
+}
+
+pub fn write_exr<W: Write>(buffer: &RenderBuffer, output: &mut W, compression: ExrCompression) -> Result<()> {
+  let mut header = vec![];
+  write_header(&mut header, buffer, compression)?;
+
+  let lines_per_chunk = compression.scanlines_per_chunk();
+  let mut chunks = vec![];
+  let mut first_line = 0;
+  while first_line < buffer.height {
+    let last_line = (first_line + lines_per_chunk).min(buffer.height);
+    chunks.push((
+      first_line,
+      compress_chunk(raw_chunk(buffer, first_line, last_line), compression),
+    ));
+    first_line = last_line;
+  }
+
+  output.write_all(&header)?;
+  let mut offset = (header.len() + chunks.len() * 8) as u64;
+  for (_, data) in &chunks {
+    output.write_all(&offset.to_le_bytes())?;
+    offset += 8 + data.len() as u64;
+  }
+  for (first_line, data) in &chunks {
+    output.write_all(&(*first_line as i32).to_le_bytes())?;
+    output.write_all(&(data.len() as i32).to_le_bytes())?;
+    output.write_all(data)?;
+  }
+  return output.flush();
+}
+
+#[test]
+fn test_uncompressed_exr_layout() {
+  use crate::vectors::Vector;
+  let mut buffer = RenderBuffer::new(2, 3);
+  buffer.set(1, 2, (Vector::vector(0.25, 0.5, 0.75), 4, 2.0));
+  let mut output = vec![];
+  write_exr(&buffer, &mut output, ExrCompression::None).unwrap();
+  assert_eq!(&output[0..4], &MAGIC);
+
+  let read_u64 = |at: usize| {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&output[at..at + 8]);
+    u64::from_le_bytes(bytes) as usize
+  };
+  let read_i32 = |at: usize| {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&output[at..at + 4]);
+    i32::from_le_bytes(bytes)
+  };
+  let read_f32 = |at: usize| f32::from_bits(read_i32(at) as u32);
+
+  let chunk_size = 2 * 5 * 4;
+  let first_offset = read_u64(output.len() - 3 * (8 + chunk_size) - 3 * 8);
+  assert_eq!(first_offset, output.len() - 3 * (8 + chunk_size));
+  let last_chunk = read_u64(first_offset - 8);
+  assert_eq!(read_i32(last_chunk), 2);
+  assert_eq!(read_i32(last_chunk + 4) as usize, chunk_size);
+  // B, G, R, Z, samples for each pixel of the row in turn.
+  let data = last_chunk + 8;
+  assert_eq!(read_f32(data + 4), 0.75);
+  assert_eq!(read_f32(data + 12), 0.5);
+  assert_eq!(read_f32(data + 20), 0.25);
+  assert_eq!(read_f32(data + 28), 2.0);
+  assert_eq!(read_f32(data + 36), 4.0);
+  assert_eq!(read_f32(data + 32), 0.0);
+}
+
+#[test]
+fn test_zip_predictor() {
+  assert_eq!(zip_predictor(&[1, 10, 2, 20, 4]), vec![1, 129, 130, 134, 138]);
+}
@@ -1,7 +1,9 @@
+use crate::ray::RayContext;
 use crate::scene::MaterialIdx;
 use crate::vectors::Vec2d;
 use crate::vectors::*;
 
+#[derive(Clone)]
 pub struct Fragment {
   pub position: Point,
   pub normal: Vector,
@@ -14,4 +16,7 @@ pub struct Fragment {
   pub view: Vector, // Camera -> Point
 
   pub material: MaterialIdx,
+  // The context of the ray that reached the fragment, so that rays leaving
+  // it on the same side start in the same medium.
+  pub ray_context: RayContext,
 }
@@ -0,0 +1,89 @@
+use crate::camera::RenderBuffer;
+use crate::exr;
+use crate::exr::ExrCompression;
+use crate::vectors::VectorType;
+use image::hdr::HDREncoder;
+use image::Rgb;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+pub fn save(buffer: &RenderBuffer, path: &Path, gamma: f32) -> Result<()> {
+  let extension = match path.extension() {
+    Some(extension) => extension.to_str().unwrap().to_lowercase(),
+    None => String::new(),
+  };
+  match extension.as_str() {
+    "png" | "jpg" | "jpeg" => image::save_buffer(
+      path,
+      &buffer.to_pixel_array(gamma),
+      buffer.width as u32,
+      buffer.height as u32,
+      image::RGB(8),
+    ),
+    "pfm" => write_pfm(buffer, &mut BufWriter::new(File::create(path)?)),
+    "hdr" => write_hdr(buffer, BufWriter::new(File::create(path)?)),
+    "exr" => save_exr(buffer, path, ExrCompression::Zip),
+    _ => Err(Error::new(
+      ErrorKind::InvalidInput,
+      format!("Unsupported output format {:?}", path),
+    )),
+  }
+}
+
+pub fn save_exr(buffer: &RenderBuffer, path: &Path, compression: ExrCompression) -> Result<()> {
+  return exr::write_exr(buffer, &mut BufWriter::new(File::create(path)?), compression);
+}
+
+/// Writes the linear colour values as a little endian colour PFM. PFM stores
+/// its scanlines bottom to top.
+pub fn write_pfm<W: Write>(buffer: &RenderBuffer, output: &mut W) -> Result<()> {
+  write!(output, "PF\n{} {}\n-1.0\n", buffer.width, buffer.height)?;
+  for y in (0..buffer.height).rev() {
+    for x in 0..buffer.width {
+      let (colour, _, _) = buffer.get(x, y);
+      for value in &[colour.x(), colour.y(), colour.z()] {
+        output.write_all(&value.to_bits().to_le_bytes())?;
+      }
+    }
+  }
+  return output.flush();
+}
+
+pub fn write_hdr<W: Write>(buffer: &RenderBuffer, output: W) -> Result<()> {
+  let mut pixels = Vec::with_capacity(buffer.width * buffer.height);
+  for y in 0..buffer.height {
+    for x in 0..buffer.width {
+      let (colour, _, _) = buffer.get(x, y);
+      pixels.push(Rgb {
+        data: [colour.x().max(0.0), colour.y().max(0.0), colour.z().max(0.0)],
+      });
+    }
+  }
+  return HDREncoder::new(output).encode(&pixels, buffer.width, buffer.height);
+}
+
+#[test]
+fn test_pfm() {
+  use crate::vectors::Vector;
+  let mut buffer = RenderBuffer::new(2, 2);
+  buffer.set(0, 0, (Vector::vector(1.0, 2.0, 3.0), 1, 0.0));
+  buffer.set(1, 1, (Vector::vector(0.5, 0.25, 0.125), 1, 0.0));
+  let mut output = vec![];
+  write_pfm(&buffer, &mut output).unwrap();
+  let header = b"PF\n2 2\n-1.0\n";
+  assert_eq!(&output[..header.len()], &header[..]);
+  assert_eq!(output.len(), header.len() + 2 * 2 * 3 * 4);
+  let value = |index: usize| {
+    let start = header.len() + index * 4;
+    f32::from_bits(u32::from_le_bytes([
+      output[start],
+      output[start + 1],
+      output[start + 2],
+      output[start + 3],
+    ]))
+  };
+  // The bottom row is written first.
+  assert_eq!((value(3), value(4), value(5)), (0.5, 0.25, 0.125));
+  assert_eq!((value(6), value(7), value(8)), (1.0, 2.0, 3.0));
+}
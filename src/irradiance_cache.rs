@@ -0,0 +1,428 @@
+use crate::colour::Colour;
+use crate::direct_lighting::IndirectLightingSource;
+use crate::fragment::Fragment;
+use crate::material::MaterialCollisionInfo;
+use crate::photon_map::random;
+use crate::ray::Ray;
+use crate::sampling::tangent_frame;
+use crate::scene::Scene;
+use crate::vectors::{Point, Vector, VectorType};
+use std::f32::consts::PI;
+use std::sync::{Arc, RwLock};
+
+// Records are never stored deeper than this, however small they are.
+const MAX_OCTREE_DEPTH: usize = 16;
+// Records next to other geometry would otherwise get a radius of zero, and
+// never be reused.
+const MIN_RECORD_RADIUS: f32 = 0.001;
+
+// A cached lighting value, in the same units as the source it was gathered
+// from, i.e. scaled so that multiplying by the diffuse colour gives the
+// reflected radiance.
+#[derive(Clone)]
+struct IrradianceRecord {
+  position: Point,
+  normal: Vector,
+  irradiance: Vector,
+  // The harmonic mean distance to the surfaces seen from the record, limited
+  // by the gradients. This sets how far away the record can be reused.
+  radius: f32,
+  // One gradient per colour channel.
+  rotational_gradient: [Vector; 3],
+  translational_gradient: [Vector; 3],
+}
+
+impl IrradianceRecord {
+  // Ward's weight for reusing the record at the given point.
+  fn weight(&self, position: Point, normal: Vector) -> f32 {
+    let distance = (position - self.position).length() / self.radius;
+    let normal_difference = (1.0 - normal.dot(self.normal).min(1.0)).max(0.0).sqrt();
+    return 1.0 / (distance + normal_difference).max(1e-6);
+  }
+
+  // Points behind the record's surface would see different lighting, as
+  // they're in a crease or under an overhang.
+  fn is_in_front(&self, position: Point, normal: Vector) -> bool {
+    return (position - self.position).dot((normal + self.normal) * 0.5f32) < -0.01 * self.radius;
+  }
+
+  // The record's value moved to the given point using the gradients.
+  fn extrapolate(&self, position: Point, normal: Vector) -> Vector {
+    let rotation = self.normal.cross(normal);
+    let translation = position - self.position;
+    let change: Vec<f32> = (0..3)
+      .map(|channel| {
+        self.rotational_gradient[channel].dot(rotation) + self.translational_gradient[channel].dot(translation)
+      })
+      .collect();
+    return self.irradiance + Vector::vector(change[0] as f64, change[1] as f64, change[2] as f64);
+  }
+}
+
+struct OctreeNode {
+  // Indices into Octree::records.
+  records: Vec<usize>,
+  children: [Option<Box<OctreeNode>>; 8],
+}
+
+impl OctreeNode {
+  fn new() -> Self {
+    return OctreeNode {
+      records: vec![],
+      children: Default::default(),
+    };
+  }
+}
+
+// Children are numbered by which side of the centre they're on, with x in
+// the first bit, y in the second and z in the third.
+fn child_centre(centre: Point, half_size: f32, child: usize) -> Point {
+  let offset = |bit: usize| if child & bit != 0 { 0.5 } else { -0.5 };
+  return centre + Vector::vector(offset(1), offset(2), offset(4)) * half_size;
+}
+
+fn child_containing(centre: Point, position: Point) -> usize {
+  let mut child = 0;
+  for axis in 0..3 {
+    if position.axis(axis) >= centre.axis(axis) {
+      child |= 1 << axis;
+    }
+  }
+  return child;
+}
+
+// An octree of records, where each record is stored in every node that
+// overlaps the sphere it can be reused within, at the depth where the nodes
+// are about the size of that sphere. Looking up a point only has to walk the
+// nodes that contain it.
+struct Octree {
+  centre: Point,
+  half_size: f32,
+  root: OctreeNode,
+  records: Vec<IrradianceRecord>,
+}
+
+impl Octree {
+  fn insert(&mut self, record: IrradianceRecord, influence: f32) {
+    let index = self.records.len();
+    let position = record.position;
+    self.records.push(record);
+    Octree::insert_into(
+      &mut self.root,
+      self.centre,
+      self.half_size,
+      0,
+      index,
+      position,
+      influence,
+    );
+  }
+
+  fn insert_into(
+    node: &mut OctreeNode,
+    centre: Point,
+    half_size: f32,
+    depth: usize,
+    index: usize,
+    position: Point,
+    influence: f32,
+  ) {
+    // Stop once the children would be smaller than the sphere of influence.
+    if depth == MAX_OCTREE_DEPTH || half_size < 2.0 * influence {
+      node.records.push(index);
+      return;
+    }
+    let child_half_size = half_size * 0.5;
+    for child in 0..8 {
+      let child_centre = child_centre(centre, half_size, child);
+      let distance = position - child_centre;
+      let overlaps = (0..3).all(|axis| distance.axis(axis).abs() <= child_half_size + influence);
+      if !overlaps {
+        continue;
+      }
+      let child_node = node.children[child].get_or_insert_with(|| Box::new(OctreeNode::new()));
+      Octree::insert_into(
+        child_node,
+        child_centre,
+        child_half_size,
+        depth + 1,
+        index,
+        position,
+        influence,
+      );
+    }
+  }
+
+  fn lookup<F: FnMut(&IrradianceRecord)>(&self, position: Point, callback: &mut F) {
+    let mut node = &self.root;
+    let mut centre = self.centre;
+    let mut half_size = self.half_size;
+    loop {
+      for index in &node.records {
+        callback(&self.records[*index]);
+      }
+      let child = child_containing(centre, position);
+      node = match node.children[child] {
+        Some(ref child_node) => child_node,
+        None => return,
+      };
+      centre = child_centre(centre, half_size, child);
+      half_size *= 0.5;
+    }
+  }
+}
+
+// Gathers a new record by stratified sampling of the hemisphere, with `rays`
+// split into M rings of N cells each, where N is about pi * M. The `sample`
+// callback returns the radiance along a direction and the distance to the
+// surface it came from.
+//
+// The gradients are those of Ward and Heckbert's "Irradiance Gradients",
+// divided by pi to match the scale of the cached values.
+fn compute_record<F: FnMut(Vector) -> (Vector, f32)>(
+  position: Point,
+  normal: Vector,
+  rays: usize,
+  mut sample: F,
+) -> IrradianceRecord {
+  let rings = ((rays as f32 / PI).sqrt().round() as usize).max(1);
+  let cells = ((rays as f32 / rings as f32).round() as usize).max(1);
+  let (tangent, bitangent) = tangent_frame(normal);
+  let ring_sin = |j: f32| (j / rings as f32).min(1.0).sqrt();
+  let direction_at = |sin_theta: f32, phi: f32| {
+    let cos_theta = (1.0 - sin_theta * sin_theta).max(0.0).sqrt();
+    return tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + normal * cos_theta;
+  };
+
+  let mut radiance = vec![Vector::new(); rings * cells];
+  let mut distances = vec![std::f32::INFINITY; rings * cells];
+  for j in 0..rings {
+    for k in 0..cells {
+      let sin_theta = ring_sin(j as f32 + random(0.0, 1.0) as f32);
+      let phi = 2.0 * PI * (k as f32 + random(0.0, 1.0) as f32) / cells as f32;
+      let (value, distance) = sample(direction_at(sin_theta, phi));
+      radiance[j * cells + k] = value;
+      distances[j * cells + k] = distance;
+    }
+  }
+
+  let count = (rings * cells) as f32;
+  let irradiance = radiance.iter().fold(Vector::new(), |sum, value| sum + *value) / count;
+  let inverse_distances: f32 = distances.iter().map(|distance| 1.0 / distance).sum();
+  let mut radius = count / inverse_distances;
+
+  // Each gradient is accumulated per colour channel.
+  let add = |gradient: &mut [Vector; 3], direction: Vector, value: Vector| {
+    for channel in 0..3 {
+      gradient[channel] = gradient[channel] + direction * value.axis(channel);
+    }
+  };
+
+  // The sign of the rotational gradient matches extrapolating along the
+  // record's normal crossed with the new one.
+  let mut rotational_gradient = [Vector::new(); 3];
+  for k in 0..cells {
+    let phi = 2.0 * PI * (k as f32 + 0.5) / cells as f32;
+    let v = bitangent * phi.cos() - tangent * phi.sin();
+    let mut ring_sum = Vector::new();
+    for j in 0..rings {
+      let sin_theta = ring_sin(j as f32 + 0.5);
+      let tan_theta = sin_theta / (1.0 - sin_theta * sin_theta).max(1e-6).sqrt();
+      ring_sum = ring_sum + radiance[j * cells + k] * tan_theta;
+    }
+    add(&mut rotational_gradient, v, ring_sum / count);
+  }
+
+  // The translational gradient comes from the change in the solid angle of
+  // each cell as the position moves, across both ring and cell boundaries.
+  let mut translational_gradient = [Vector::new(); 3];
+  let cell_at = |j: usize, k: usize| j * cells + (k % cells);
+  for k in 0..cells {
+    let phi = 2.0 * PI * (k as f32 + 0.5) / cells as f32;
+    let u = tangent * phi.cos() + bitangent * phi.sin();
+    let mut ring_sum = Vector::new();
+    for j in 1..rings {
+      let sin_theta = ring_sin(j as f32);
+      let cos_squared = 1.0 - sin_theta * sin_theta;
+      let distance = distances[cell_at(j, k)].min(distances[cell_at(j - 1, k)]);
+      let difference = radiance[cell_at(j, k)] - radiance[cell_at(j - 1, k)];
+      ring_sum = ring_sum + difference * (sin_theta * cos_squared / distance);
+    }
+    add(&mut translational_gradient, u, ring_sum * (2.0 / cells as f32));
+
+    let phi = 2.0 * PI * k as f32 / cells as f32;
+    let v = bitangent * phi.cos() - tangent * phi.sin();
+    let mut cell_sum = Vector::new();
+    for j in 0..rings {
+      let cos_lower = (1.0 - ring_sin(j as f32).powi(2)).max(0.0).sqrt();
+      let cos_upper = (1.0 - ring_sin(j as f32 + 1.0).powi(2)).max(0.0).sqrt();
+      let sin_middle = ring_sin(j as f32 + 0.5);
+      let distance = distances[cell_at(j, k)].min(distances[cell_at(j, k + cells - 1)]);
+      let difference = radiance[cell_at(j, k)] - radiance[cell_at(j, k + cells - 1)];
+      cell_sum = cell_sum + difference * ((cos_lower - cos_upper) / (sin_middle * distance));
+    }
+    add(&mut translational_gradient, v, cell_sum / PI);
+  }
+
+  // Keep the record from being reused beyond where the translational
+  // gradient says it would have changed completely.
+  for channel in 0..3 {
+    let gradient_length = translational_gradient[channel].length();
+    if gradient_length > 0.0 {
+      radius = radius.min(irradiance.axis(channel) / gradient_length);
+    }
+  }
+
+  return IrradianceRecord {
+    position,
+    normal,
+    irradiance,
+    radius: radius.max(MIN_RECORD_RADIUS),
+    rotational_gradient,
+    translational_gradient,
+  };
+}
+
+/// A Ward style irradiance cache in front of another indirect lighting
+/// source. The source is only sampled when no cached record is close enough
+/// to interpolate from, in which case a new record is gathered by tracing
+/// `rays` rays over the hemisphere and looking up the source where they land.
+///
+/// Records are interpolated using their rotational and translational
+/// gradients, and the `accuracy` sets how far they're reused: smaller values
+/// create more records. The cache is shared between all of the threads
+/// rendering the image, so records made by one are used by the others.
+pub struct IrradianceCache {
+  source: Arc<IndirectLightingSource>,
+  rays: usize,
+  accuracy: f32,
+  octree: RwLock<Octree>,
+}
+
+impl IrradianceCache {
+  pub fn new(scene: &Arc<Scene>, source: Arc<IndirectLightingSource>, rays: usize, accuracy: f32) -> Self {
+    let bounds = scene.bounds();
+    let size = bounds.max - bounds.min;
+    let half_size = size.x().max(size.y()).max(size.z()) * 0.5 + 0.01;
+    return IrradianceCache {
+      source,
+      rays: rays.max(1),
+      accuracy: accuracy.max(0.01),
+      octree: RwLock::new(Octree {
+        centre: bounds.centroid(),
+        half_size,
+        root: OctreeNode::new(),
+        records: vec![],
+      }),
+    };
+  }
+
+  fn interpolate(&self, position: Point, normal: Vector) -> Option<Vector> {
+    let mut total = Vector::new();
+    let mut total_weight = 0.0;
+    let octree = self.octree.read().unwrap();
+    octree.lookup(position, &mut |record| {
+      let weight = record.weight(position, normal);
+      if weight <= 1.0 / self.accuracy || record.is_in_front(position, normal) {
+        return;
+      }
+      total = total + record.extrapolate(position, normal) * weight;
+      total_weight += weight;
+    });
+    if total_weight == 0.0 {
+      return None;
+    }
+    return Some((total / total_weight).max(Vector::new()));
+  }
+
+  // The lighting reflected towards the record by the surface along the
+  // direction. Emitters are left out, as they're handled by direct lighting.
+  fn sample(&self, scene: &Scene, position: Point, direction: Vector) -> (Vector, f32) {
+    let ray = Ray::new_bound(position, direction, 0.005, std::f32::INFINITY, None);
+    let (collision, shadable) = match scene.intersect(&ray) {
+      None => return (Vector::new(), std::f32::INFINITY),
+      Some(hit) => hit,
+    };
+    let fragment = shadable.compute_fragment(scene, &ray, &collision);
+    let hit = scene
+      .get_material(fragment.material)
+      .compute_surface_properties(scene, &ray, &fragment);
+    if hit.emitted_colour().is_some() {
+      return (Vector::new(), collision.distance);
+    }
+    let diffuse_colour = hit.bsdf.diffuse_colour();
+    if diffuse_colour.max_value() <= 0.0 {
+      return (Vector::new(), collision.distance);
+    }
+    let lighting = match self.source.lighting_and_shadow(scene, &fragment, &hit) {
+      (Some(lighting), _) => lighting,
+      (None, _) => return (Vector::new(), collision.distance),
+    };
+    return (Vector::from(diffuse_colour * lighting), collision.distance);
+  }
+}
+
+impl IndirectLightingSource for IrradianceCache {
+  fn lighting_and_shadow(
+    &self,
+    scene: &Scene,
+    _fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+  ) -> (Option<Colour>, Option<bool>) {
+    if let Some(irradiance) = self.interpolate(surface.position, surface.normal) {
+      return (Some(Colour::from(irradiance)), None);
+    }
+    let record = compute_record(surface.position, surface.normal, self.rays, |direction| {
+      self.sample(scene, surface.position, direction)
+    });
+    let irradiance = record.irradiance;
+    let mut octree = self.octree.write().unwrap();
+    let mut record = record;
+    record.radius = record.radius.min(octree.half_size);
+    let influence = self.accuracy * record.radius;
+    octree.insert(record, influence);
+    return (Some(Colour::from(irradiance)), None);
+  }
+}
+
+#[test]
+fn test_octree_lookup() {
+  let record = |x: f64, radius: f32| IrradianceRecord {
+    position: Vector::point(x, 0.0, 0.0),
+    normal: Vector::vector(0.0, 1.0, 0.0),
+    irradiance: Vector::vector(1.0, 1.0, 1.0),
+    radius,
+    rotational_gradient: [Vector::new(); 3],
+    translational_gradient: [Vector::new(); 3],
+  };
+  let mut octree = Octree {
+    centre: Vector::point(0.0, 0.0, 0.0),
+    half_size: 10.0,
+    root: OctreeNode::new(),
+    records: vec![],
+  };
+  octree.insert(record(-5.0, 0.1), 0.1);
+  octree.insert(record(5.0, 0.1), 0.1);
+  octree.insert(record(0.0, 20.0), 20.0);
+
+  let mut found = vec![];
+  octree.lookup(Vector::point(5.05, 0.0, 0.0), &mut |record| {
+    found.push(record.position.x())
+  });
+  found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  assert_eq!(found, vec![0.0, 5.0]);
+}
+
+#[test]
+fn test_constant_lighting_has_no_gradient() {
+  let normal = Vector::vector(0.0, 0.0, 1.0);
+  let record = compute_record(Vector::point(0.0, 0.0, 0.0), normal, 200, |_| {
+    (Vector::vector(0.5, 0.5, 0.5), 2.0)
+  });
+  assert!((record.irradiance.x() - 0.5).abs() < 0.0001);
+  assert!((record.radius - 2.0).abs() < 0.0001);
+  for channel in 0..3 {
+    assert!(record.rotational_gradient[channel].length() < 0.0001);
+    assert!(record.translational_gradient[channel].length() < 0.0001);
+  }
+}
@@ -164,6 +164,36 @@ impl<T: Copy + HasPosition> KDTreeNode<T> {
   }
 }
 
+impl<T: Copy + HasPosition> KDTreeNode<T> {
+  fn within<F: FnMut(&T, f32)>(&self, position: Point, radius: f32, callback: &mut F) {
+    let extent = Vector::splat(radius);
+    let mut stack = vec![self];
+    while let Some(node) = stack.pop() {
+      let bounds = match node {
+        KDTreeNode::Leaf(_, bounds) => bounds,
+        KDTreeNode::Node(node) => &node.bounds,
+      };
+      if position.lt(bounds.min - extent).any() || position.gt(bounds.max + extent).any() {
+        continue;
+      }
+      match node {
+        KDTreeNode::Leaf(elements, _) => {
+          for element in elements {
+            let distance = (element.get_position() - position).length();
+            if distance <= radius {
+              callback(element, distance);
+            }
+          }
+        }
+        KDTreeNode::Node(node) => {
+          stack.push(&node.children[1]);
+          stack.push(&node.children[0]);
+        }
+      }
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct KDTree<T: Copy + HasPosition> {
   root: KDTreeNode<T>,
@@ -250,4 +280,41 @@ impl<T: Copy + HasBoundingBox + HasPosition> KDTree<T> {
 
     return (result, distance);
   }
+
+  /// Calls `callback` with every element within `radius` of `position`, and
+  /// its distance from `position`.
+  pub fn within<F: FnMut(&T, f32)>(&self, position: Point, radius: f32, callback: &mut F) {
+    self.root.within(position, radius, callback);
+  }
+}
+
+#[test]
+fn test_within() {
+  #[derive(Clone, Copy)]
+  struct TestPoint(Point);
+  impl HasPosition for TestPoint {
+    fn get_position(&self) -> Point {
+      return self.0;
+    }
+  }
+  impl HasBoundingBox for TestPoint {
+    fn bounds(&self) -> BoundingBox {
+      return BoundingBox::new_from_point(self.0);
+    }
+  }
+
+  let mut points = vec![];
+  for x in 0..10 {
+    for y in 0..10 {
+      points.push(TestPoint(Vector::point(x as f64, y as f64, 0.0)));
+    }
+  }
+  let tree = KDTree::new(&mut points, 4);
+  let mut found = vec![];
+  tree.within(Vector::point(4.0, 4.0, 0.0), 1.5, &mut |point, distance| {
+    assert!(distance <= 1.5);
+    found.push(point.0);
+  });
+  // The point itself and its eight neighbours.
+  assert_eq!(found.len(), 9);
 }
@@ -1,7 +1,11 @@
 #![feature(stdsimd, async_await, futures_api, await_macro, drain_filter, box_syntax)]
 #![allow(unused)]
 
+mod analytic_light;
+mod aov;
+mod bidirectional;
 mod bounding_box;
+mod bsdf;
 mod bvh;
 mod camera;
 mod casefopen;
@@ -11,19 +15,30 @@ mod compound_object;
 mod direct_lighting;
 mod dispatch_queue;
 mod either;
+mod environment;
+mod exr;
 mod fragment;
 mod heap;
+mod image_output;
 mod intersectable;
+mod irradiance_cache;
 mod kdtree;
 mod light;
+mod light_tree;
 mod material;
 mod media;
 mod mesh;
+mod microfacet;
 mod objects;
+mod path_tracer;
+mod progressive_photon_map;
 mod ray;
 mod render_configuration;
+mod sampling;
 mod scene_loader;
 mod shader;
+mod sky;
+mod spectrum;
 mod sphere;
 mod texture;
 mod triangle;
@@ -39,15 +54,26 @@ pub mod wavefront {
   pub use crate::wavefront_material::load_scene;
 }
 
+pub mod loader {
+  pub use crate::scene_loader::load_scene;
+}
+
 pub use crate::camera::*;
+pub use crate::exr::ExrCompression;
+pub use crate::aov::AOV;
 
 pub mod integrators {
+  pub use crate::bidirectional::BidirectionalPathTracer;
   pub use crate::direct_lighting::DirectLighting;
   pub use crate::direct_lighting::IndirectLightingSource;
+  pub use crate::irradiance_cache::IrradianceCache;
+  pub use crate::path_tracer::PathTracer;
   pub use crate::render_configuration::LightingIntegrator;
 }
 
 pub mod cameras {
+  pub use crate::bidirectional::BidirectionalPathTracer;
   pub use crate::camera::Camera;
   pub use crate::camera::PerspectiveCamera;
+  pub use crate::progressive_photon_map::ProgressivePhotonMap;
 }
@@ -1,9 +1,34 @@
+use crate::bounding_box::BoundingBox;
+use crate::colour::Colour;
 use crate::material::EmissionCoefficients;
-use crate::vectors::Vector;
+use crate::vectors::{Vector, VectorType};
 use crate::vectors::Point;
 use crate::scene::Scene;
+use std::fmt::Debug;
 
-#[derive(Debug)]
+/// How a light sample emits its light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emitter {
+  /// A point on an emissive surface, standing in for `area` of it, with the
+  /// surface normal in `direction`.
+  Area,
+  /// A point light, with its intensity in `radiance`. Spot lights only emit
+  /// within the cone around `direction` with the given cosine, fading out
+  /// from the cone with the first cosine.
+  Point { spot: Option<(f32, f32)> },
+  /// Light from infinitely far away, travelling along `direction`, with the
+  /// irradiance it delivers to a surface facing it in `radiance`. The
+  /// `position` and `area` describe the disc facing the light that covers
+  /// the scene, which photons are emitted from.
+  Directional,
+  /// Light from the scene's environment map, Scene::environment, which
+  /// directions are sampled from. The `radiance` is the map's radiance
+  /// integrated over the sphere, and `position` and `area` describe the disc
+  /// covering the scene as for directional lights.
+  Environment,
+}
+
+#[derive(Debug, Clone)]
 pub struct LightSample {
   pub position: Point,
   pub direction: Option<Vector>,
@@ -11,16 +36,114 @@ pub struct LightSample {
   pub diffuse: Vector,
   pub specular: Vector,
   pub emission: EmissionCoefficients,
+  // The emitted radiance at the sample, and the area of the emitter that the
+  // sample stands in for.
+  pub radiance: Vector,
+  pub area: f32,
   pub weight: f32,
-  pub power: f32,
+  pub emitter: Emitter,
 }
 
-pub trait Light {
+pub trait Light: Debug + Sync + Send {
   fn get_area(&self) -> f32;
   fn get_samples(&self, count: usize, scene: &Scene) -> Vec<LightSample>;
+  /// For lights that are the surfaces of objects, a copy of the surface and
+  /// its bounds, which the scene keeps apart from the objects so that fresh
+  /// points can be sampled on it, see Scene::light.
+  fn copy_surface(&self) -> Option<(Box<Light>, BoundingBox)> {
+    return None;
+  }
+}
+
+// The smooth fade from the outer cone of a spot light to the inner one.
+fn spot_falloff(cos_inner: f32, cos_outer: f32, cosine: f32) -> f32 {
+  if cosine >= cos_inner {
+    return 1.0;
+  }
+  if cosine <= cos_outer {
+    return 0.0;
+  }
+  let t = (cosine - cos_outer) / (cos_inner - cos_outer);
+  return t * t * (3.0 - 2.0 * t);
 }
+
 impl LightSample {
+  /// The power the sample emits, divided by pi to match the scale of the
+  /// photon map. Emissive surfaces keep the scale photon maps have always
+  /// used for them, from the emission coefficients and the sample's share of
+  /// the samples. For spot lights this covers the whole outer cone, and
+  /// photons are scaled down by the falloff as they're emitted.
+  pub fn photon_power(&self) -> Vector {
+    return match self.emitter {
+      Emitter::Area => {
+        (self.diffuse * self.emission.diffuse
+          + self.ambient * self.emission.ambient
+          + self.specular * self.emission.specular
+          + Vector::from(self.emission.colour.unwrap_or(Colour::new())))
+          * self.weight
+      }
+      Emitter::Point { spot: None } => self.radiance * 4.0,
+      Emitter::Point {
+        spot: Some((_, cos_outer)),
+      } => self.radiance * (2.0 * (1.0 - cos_outer)),
+      Emitter::Directional | Emitter::Environment => self.radiance * (self.area / std::f32::consts::PI),
+    };
+  }
+
   pub fn output(&self) -> f32 {
-    self.power * self.weight
+    let power = self.photon_power();
+    return (power.x() + power.y() + power.z()) / 3.0;
+  }
+
+  /// Whether the light is a point or direction, so can only be reached by
+  /// sampling it.
+  pub fn is_delta(&self) -> bool {
+    return match self.emitter {
+      Emitter::Point { .. } | Emitter::Directional => true,
+      Emitter::Area | Emitter::Environment => false,
+    };
+  }
+
+  /// Whether the light is infinitely far away, so has no position.
+  pub fn is_distant(&self) -> bool {
+    return self.emitter == Emitter::Directional || self.emitter == Emitter::Environment;
+  }
+
+  /// The direction from `position` towards the light, and the distance to it.
+  /// Environment lights have no single direction, so they're sampled through
+  /// the map instead.
+  pub fn direction_from(&self, position: Point) -> (Vector, f32) {
+    if self.emitter == Emitter::Directional {
+      return (self.direction.unwrap() * -1.0, std::f32::INFINITY);
+    }
+    let offset = self.position - position;
+    let distance = offset.length();
+    return (offset * (1.0 / distance), distance);
+  }
+
+  /// The irradiance that a point or directional light delivers to a surface
+  /// facing it, from the given direction and distance to the light.
+  pub fn delta_irradiance(&self, direction: Vector, distance: f32) -> Vector {
+    return match self.emitter {
+      Emitter::Area | Emitter::Environment => Vector::new(),
+      Emitter::Point { spot: None } => self.radiance * (1.0 / (distance * distance)),
+      Emitter::Point {
+        spot: Some((cos_inner, cos_outer)),
+      } => {
+        let cosine = self.direction.unwrap().dot(direction * -1.0);
+        self.radiance * (spot_falloff(cos_inner, cos_outer, cosine) / (distance * distance))
+      }
+      Emitter::Directional => self.radiance,
+    };
+  }
+
+  /// The fraction of a spot light's intensity emitted in the direction.
+  pub fn spot_falloff(&self, direction: Vector) -> f32 {
+    return match self.emitter {
+      Emitter::Point {
+        spot: Some((cos_inner, cos_outer)),
+      } => spot_falloff(cos_inner, cos_outer, self.direction.unwrap().dot(direction)),
+      _ => 1.0,
+    };
   }
 }
@@ -0,0 +1,387 @@
+use crate::bounding_box::{BoundingBox, HasBoundingBox};
+use crate::light::{Emitter, LightSample};
+use crate::photon_map::random;
+use crate::scene::Scene;
+use crate::vectors::{Point, Vector, VectorType};
+use std::f32::consts::PI;
+
+// Area lights emit over the hemisphere around their normal.
+const EMISSION_ANGLE: f32 = PI / 2.0;
+
+// The normals of a set of emitters, as the cone of directions within `angle`
+// of `axis`. Lights emit from both sides, so a normal and its opposite are
+// treated as the same.
+#[derive(Clone, Copy, Debug)]
+struct NormalCone {
+  axis: Vector,
+  angle: f32,
+}
+
+impl NormalCone {
+  fn for_light(light: &TreeLight) -> NormalCone {
+    // A whole surface may face many ways, as spheres do.
+    if light.bounds.is_some() {
+      return NormalCone {
+        axis: Vector::vector(0.0, 0.0, 1.0),
+        angle: PI,
+      };
+    }
+    let light = &light.sample;
+    return match (light.emitter, light.direction) {
+      (Emitter::Area, Some(direction)) => NormalCone {
+        axis: direction,
+        angle: 0.0,
+      },
+      // The spot light's cone is wider than it needs to be, as it only emits
+      // from one side, but that only costs a few wasted samples.
+      (
+        Emitter::Point {
+          spot: Some((_, cos_outer)),
+        },
+        Some(direction),
+      ) => NormalCone {
+        axis: direction,
+        angle: cos_outer.max(-1.0).min(1.0).acos(),
+      },
+      _ => NormalCone {
+        axis: Vector::vector(0.0, 0.0, 1.0),
+        angle: PI,
+      },
+    };
+  }
+
+  // The smallest cone containing both, following "Importance Sampling of
+  // Many Lights with Adaptive Tree Splitting" by Conty Estevez and Kulla.
+  fn merge(&self, other: &NormalCone) -> NormalCone {
+    let mut other_axis = other.axis;
+    if self.axis.dot(other_axis) < 0.0 {
+      other_axis = other_axis * -1.0;
+    }
+    let between = self.axis.dot(other_axis).max(-1.0).min(1.0).acos();
+    if (between + other.angle).min(PI) <= self.angle {
+      return *self;
+    }
+    if (between + self.angle).min(PI) <= other.angle {
+      return NormalCone {
+        axis: other_axis,
+        angle: other.angle,
+      };
+    }
+    let angle = (self.angle + between + other.angle) * 0.5;
+    let rotation_axis = self.axis.cross(other_axis);
+    if angle >= PI || rotation_axis.length() < 1e-6 {
+      return NormalCone {
+        axis: self.axis,
+        angle: PI,
+      };
+    }
+    // Rotate our axis towards the other one, so the new cone just reaches
+    // the far edge of each.
+    let rotation = angle - self.angle;
+    let perpendicular = rotation_axis.normalize().cross(self.axis);
+    return NormalCone {
+      axis: (self.axis * rotation.cos() + perpendicular * rotation.sin()).normalize(),
+      angle,
+    };
+  }
+}
+
+struct LightTreeNode {
+  bounds: BoundingBox,
+  cone: NormalCone,
+  power: f32,
+  parent: Option<usize>,
+  // The two children of an inner node, or the light of a leaf.
+  children: Option<(usize, usize)>,
+  light: usize,
+}
+
+// A light that the tree picks from: its index, a sample of it, and the
+// bounds of its surface, for lights that are whole surfaces rather than
+// single samples of them.
+struct TreeLight {
+  index: usize,
+  sample: LightSample,
+  bounds: Option<BoundingBox>,
+}
+
+impl TreeLight {
+  fn bounds(&self) -> BoundingBox {
+    return self.bounds.unwrap_or(BoundingBox::new_from_point(self.sample.position));
+  }
+}
+
+/// A bounding volume hierarchy over the lights, where every node
+/// knows the power of the lights below it and the cone their normals lie in.
+/// Lights are picked by walking down the tree, choosing each child in
+/// proportion to an estimate of how much it could light the shading point,
+/// so that distant, dim or facing-away lights are rarely sampled.
+///
+/// Directional and environment lights have no position, so they're kept out
+/// of the tree, and are each picked as often as the tree as a whole.
+pub struct LightTree {
+  nodes: Vec<LightTreeNode>,
+  leaf_for_light: Vec<Option<usize>>,
+  distant_lights: Vec<usize>,
+}
+
+impl LightTree {
+  pub fn new(lights: &[LightSample]) -> Self {
+    let tree_lights: Vec<TreeLight> = lights
+      .iter()
+      .enumerate()
+      .map(|(index, sample)| TreeLight {
+        index,
+        sample: sample.clone(),
+        bounds: None,
+      })
+      .collect();
+    return LightTree::from_lights(&tree_lights, lights.len());
+  }
+
+  /// A tree over the lights of the scene, see Scene::light. Surfaces are
+  /// picked as a whole, and the indices are those of Scene::light.
+  pub fn for_scene(scene: &Scene) -> Self {
+    let mut tree_lights = vec![];
+    for index in 0..scene.light_count() {
+      let (light, bounds) = scene.light(index);
+      // A sample anywhere on the light tells us its power.
+      if let Some(sample) = light.get_samples(1, scene).pop() {
+        tree_lights.push(TreeLight { index, sample, bounds });
+      }
+    }
+    return LightTree::from_lights(&tree_lights, scene.light_count());
+  }
+
+  // Builds the tree over `lights`, whose indices are below `count`.
+  fn from_lights(lights: &[TreeLight], count: usize) -> Self {
+    let mut tree = LightTree {
+      nodes: vec![],
+      leaf_for_light: vec![None; count],
+      distant_lights: vec![],
+    };
+    let (distant, mut indices): (Vec<usize>, Vec<usize>) =
+      (0..lights.len()).partition(|index| lights[*index].sample.is_distant());
+    tree.distant_lights = distant.iter().map(|index| lights[*index].index).collect();
+    if !indices.is_empty() {
+      tree.build(lights, &mut indices, None);
+    }
+    return tree;
+  }
+
+  fn build(&mut self, lights: &[TreeLight], indices: &mut [usize], parent: Option<usize>) -> usize {
+    let node_index = self.nodes.len();
+    if indices.len() == 1 {
+      let light = &lights[indices[0]];
+      self.nodes.push(LightTreeNode {
+        bounds: light.bounds(),
+        cone: NormalCone::for_light(light),
+        power: light.sample.output(),
+        parent,
+        children: None,
+        light: light.index,
+      });
+      self.leaf_for_light[light.index] = Some(node_index);
+      return node_index;
+    }
+
+    // Split at the middle of the longest axis of the lights' centres, or at
+    // the median if that leaves one side empty.
+    let centre = |index: &usize| lights[*index].bounds().centroid();
+    let centres = indices.iter().fold(BoundingBox::new(), |bounds, index| {
+      bounds.merge_with_point(centre(index))
+    });
+    let axis = centres.max_axis();
+    let middle = centres.centroid().axis(axis);
+    indices.sort_by(|a, b| {
+      let a = centre(a).axis(axis);
+      let b = centre(b).axis(axis);
+      return a.partial_cmp(&b).unwrap();
+    });
+    let mut split = indices
+      .iter()
+      .position(|index| centre(index).axis(axis) >= middle)
+      .unwrap_or(0);
+    if split == 0 || split == indices.len() {
+      split = indices.len() / 2;
+    }
+
+    let bounds = indices.iter().fold(BoundingBox::new(), |bounds, index| {
+      bounds.merge_with_bbox(lights[*index].bounds())
+    });
+    self.nodes.push(LightTreeNode {
+      bounds,
+      cone: NormalCone {
+        axis: Vector::vector(0.0, 0.0, 1.0),
+        angle: 0.0,
+      },
+      power: 0.0,
+      parent,
+      children: None,
+      light: 0,
+    });
+    let (left_indices, right_indices) = indices.split_at_mut(split);
+    let left = self.build(lights, left_indices, Some(node_index));
+    let right = self.build(lights, right_indices, Some(node_index));
+    let cone = self.nodes[left].cone.merge(&self.nodes[right].cone);
+    let power = self.nodes[left].power + self.nodes[right].power;
+    let node = &mut self.nodes[node_index];
+    node.cone = cone;
+    node.power = power;
+    node.children = Some((left, right));
+    return node_index;
+  }
+
+  // An estimate of the light the node's emitters could send to the shading
+  // point: their power, over the squared distance, reduced by the smallest
+  // angles the emitters and the surface could be facing each other at.
+  // Shading points without a normal are lit from every direction.
+  fn importance(&self, node: &LightTreeNode, position: Point, normal: Option<Vector>) -> f32 {
+    if node.power <= 0.0 {
+      return 0.0;
+    }
+    let centre = node.bounds.centroid();
+    let radius = (node.bounds.max - node.bounds.min).length() * 0.5;
+    let to_point = position - centre;
+    let distance_squared = to_point.square_length();
+    if distance_squared <= radius * radius {
+      return node.power / (radius * radius).max(1e-6);
+    }
+    let distance = distance_squared.sqrt();
+    let direction = to_point * (1.0 / distance);
+    // The angle the bounds cover as seen from the shading point.
+    let bounds_angle = (radius / distance).min(1.0).asin();
+
+    let emitter_angle = node.cone.axis.dot(direction).abs().min(1.0).acos();
+    let emitter_angle = (emitter_angle - node.cone.angle - bounds_angle).max(0.0);
+    if emitter_angle >= EMISSION_ANGLE {
+      return 0.0;
+    }
+    let surface_cosine = match normal {
+      Some(normal) => {
+        let surface_angle = (normal.dot(direction * -1.0)).max(-1.0).min(1.0).acos();
+        let surface_angle = (surface_angle - bounds_angle).max(0.0);
+        if surface_angle >= PI / 2.0 {
+          return 0.0;
+        }
+        surface_angle.cos()
+      }
+      None => 1.0,
+    };
+    return node.power * emitter_angle.cos() * surface_cosine / distance_squared;
+  }
+
+  fn child_importance(&self, children: (usize, usize), position: Point, normal: Option<Vector>) -> (f32, f32) {
+    return (
+      self.importance(&self.nodes[children.0], position, normal),
+      self.importance(&self.nodes[children.1], position, normal),
+    );
+  }
+
+  // The number of choices made before walking the tree: each distant light,
+  // and the tree itself.
+  fn choice_count(&self) -> usize {
+    return self.distant_lights.len() + if self.nodes.is_empty() { 0 } else { 1 };
+  }
+
+  /// Picks a light for the shading point, returning its index and the
+  /// probability it was picked with. Returns None if no light can reach it.
+  /// Lights behind the normal are never picked, so surfaces that scatter
+  /// light through themselves pass None.
+  pub fn sample(&self, position: Point, normal: Option<Vector>) -> Option<(usize, f32)> {
+    let choices = self.choice_count();
+    if choices == 0 {
+      return None;
+    }
+    let choice = (random(0.0, choices as f64) as usize).min(choices - 1);
+    if choice < self.distant_lights.len() {
+      return Some((self.distant_lights[choice], 1.0 / choices as f32));
+    }
+    if self.importance(&self.nodes[0], position, normal) <= 0.0 {
+      return None;
+    }
+    let mut node = &self.nodes[0];
+    let mut probability = 1.0 / choices as f32;
+    while let Some(children) = node.children {
+      let (left, right) = self.child_importance(children, position, normal);
+      let total = left + right;
+      if !(total > 0.0) {
+        return None;
+      }
+      let left_probability = left / total;
+      if (random(0.0, 1.0) as f32) < left_probability {
+        probability *= left_probability;
+        node = &self.nodes[children.0];
+      } else {
+        probability *= 1.0 - left_probability;
+        node = &self.nodes[children.1];
+      }
+    }
+    return Some((node.light, probability));
+  }
+
+  /// The probability that `sample` picks the given light.
+  pub fn probability(&self, position: Point, normal: Option<Vector>, light: usize) -> f32 {
+    let mut node_index = match self.leaf_for_light[light] {
+      Some(leaf) => leaf,
+      None => return 1.0 / self.choice_count() as f32,
+    };
+    if self.importance(&self.nodes[0], position, normal) <= 0.0 {
+      return 0.0;
+    }
+    let mut probability = 1.0 / self.choice_count() as f32;
+    while let Some(parent) = self.nodes[node_index].parent {
+      let children = self.nodes[parent].children.unwrap();
+      let (left, right) = self.child_importance(children, position, normal);
+      let importance = if children.0 == node_index { left } else { right };
+      if !(left + right > 0.0) {
+        return 0.0;
+      }
+      probability *= importance / (left + right);
+      node_index = parent;
+    }
+    return probability;
+  }
+}
+
+#[test]
+fn test_light_tree_probabilities() {
+  use crate::material::EmissionCoefficients;
+  let light = |x: f64, y: f64| LightSample {
+    position: Vector::point(x, y, 0.0),
+    direction: Some(Vector::vector(0.0, -1.0, 0.0)),
+    ambient: Vector::new(),
+    diffuse: Vector::vector(1.0, 1.0, 1.0),
+    specular: Vector::new(),
+    emission: EmissionCoefficients {
+      ambient: 0.0,
+      diffuse: 1.0,
+      specular: 0.0,
+      blackbody: None,
+      colour: None,
+    },
+    radiance: Vector::vector(1.0, 1.0, 1.0),
+    area: 1.0,
+    weight: 0.25,
+    emitter: Emitter::Area,
+  };
+  let lights = vec![light(-1.0, 1.0), light(1.0, 1.0), light(5.0, 1.0), light(1.0, -1.0)];
+  let tree = LightTree::new(&lights);
+  let position = Vector::point(0.0, 0.0, 0.0);
+  let normal = Some(Vector::vector(0.0, 1.0, 0.0));
+
+  let total: f32 = (0..lights.len())
+    .map(|light| tree.probability(position, normal, light))
+    .sum();
+  assert!((total - 1.0).abs() < 0.0001);
+  // The light below the surface can't reach it, and the distant one is less
+  // likely than the near ones.
+  assert_eq!(tree.probability(position, normal, 3), 0.0);
+  assert!(tree.probability(position, normal, 2) < tree.probability(position, normal, 1));
+  for _ in 0..100 {
+    let (light, probability) = tree.sample(position, normal).unwrap();
+    assert!((probability - tree.probability(position, normal, light)).abs() < 0.0001);
+  }
+  // Without a normal, the light below can be picked too.
+  assert!(tree.probability(position, None, 3) > 0.0);
+}
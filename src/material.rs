@@ -1,11 +1,13 @@
 use crate::scene::MediaIdx;
 use std::sync::Arc;
 use crate::media::Media;
-use crate::ray::RayContext;
+use crate::ray::{Interior, RayContext};
+use crate::bsdf::{Bsdf, Lobe};
 use crate::colour::Colour;
 use crate::fragment::Fragment;
 use crate::ray::Ray;
 use crate::scene::Scene;
+use crate::spectrum::{Blackbody, Dispersion, D_LINE};
 use std::fmt::Debug;
 use crate::vectors::*;
 
@@ -21,11 +23,18 @@ pub struct EmissionCoefficients {
   pub ambient: f32,
   pub diffuse: f32,
   pub specular: f32,
+  // The spectrum of the emitted light in spectral rendering, when it comes
+  // from a black body rather than the surface's colours.
+  pub blackbody: Option<Blackbody>,
+  // Radiance emitted in a colour of its own rather than as a multiple of the
+  // surface's colours, as by PrincipledMaterial.
+  pub colour: Option<Colour>,
 }
 
 impl EmissionCoefficients {
   pub fn max_value(&self) -> f32 {
-    return self.ambient.max(self.diffuse).max(self.specular);
+    let colour = self.colour.map_or(0.0, |colour| colour.max_value());
+    return self.ambient.max(self.diffuse).max(self.specular).max(colour);
   }
 }
 
@@ -35,18 +44,56 @@ pub struct MaterialCollisionInfo {
   pub diffuse_colour: Colour,
   pub specular_colour: Colour,
   pub emissive_colour: Option<EmissionCoefficients>,
-  pub transparent_colour: Option<Colour>,
-  pub reflectivity: Option<(f32, Colour)>,
-  pub index_of_refraction: Option<(f32, f32)>,
   pub position: Point,
   pub normal: Vector,
-  pub media_transition: Option<MediaTransition>,
+  // The dielectric or medium on the inside of the surface, which rays
+  // transmitted through it enter or leave.
+  pub interior: Option<Interior>,
+  // How the surface scatters light. The colours above are only used for
+  // emission and the legacy ambient term.
+  pub bsdf: Bsdf,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct MediaTransition {
-  pub internal: Option<MediaIdx>,
-  pub external: Option<MediaIdx>,
+impl MaterialCollisionInfo {
+  /// The radiance emitted by the surface, if it is a light.
+  pub fn emitted_colour(&self) -> Option<Colour> {
+    return self.emissive_colour.map(|emission| {
+      emission.ambient * self.ambient_colour
+        + emission.diffuse * self.diffuse_colour
+        + emission.specular * self.specular_colour
+        + emission.colour.unwrap_or(Colour::new())
+    });
+  }
+
+  /// The same surface facing the other way, if it scatters to both sides,
+  /// see Bsdf::is_two_sided. Estimates of the irradiance, such as photon map
+  /// lookups, only look at the side the normal faces, so average the two.
+  pub fn back_face(&self) -> Option<MaterialCollisionInfo> {
+    if !self.bsdf.is_two_sided() {
+      return None;
+    }
+    let mut back = self.clone();
+    back.normal = -self.normal;
+    return Some(back);
+  }
+
+  /// The same surface with its colours converted to the spectrum at the
+  /// wavelengths `context` carries, if any, see RayContext::upsample_emission.
+  pub fn upsampled(mut self, context: &RayContext) -> MaterialCollisionInfo {
+    if let Some(wavelengths) = context.wavelengths {
+      let blackbody = self.emissive_colour.and_then(|emission| emission.blackbody);
+      self.ambient_colour = context.upsample_emission(self.ambient_colour, blackbody);
+      self.diffuse_colour = context.upsample_emission(self.diffuse_colour, blackbody);
+      self.specular_colour = context.upsample_emission(self.specular_colour, blackbody);
+      if let Some(emission) = self.emissive_colour.as_mut() {
+        emission.colour = emission
+          .colour
+          .map(|colour| context.upsample_emission(colour, blackbody));
+      }
+      self.bsdf = self.bsdf.upsampled(&wavelengths);
+    }
+    return self;
+  }
 }
 
 pub trait Material: Debug + Sync + Send {
@@ -77,20 +124,154 @@ impl Material for DefaultMaterial {
       diffuse_colour: self.colour,
       specular_colour: self.colour,
       emissive_colour: None,
-      transparent_colour: None,
       position: f.position,
       normal: f.normal,
-      index_of_refraction: None,
-      reflectivity: self.reflection.map(|p| (p, self.colour)),
-      media_transition: None,
+      interior: None,
+      bsdf: match self.reflection {
+        Some(reflection) => Bsdf::new(f.normal, f.true_normal)
+          .with_lobe(Lobe::Mirror(self.colour * reflection))
+          .with_lobe(Lobe::Diffuse(self.colour * (1.0 - reflection).max(0.0))),
+        None => Bsdf::new(f.normal, f.true_normal).with_lobe(Lobe::Diffuse(self.colour)),
+      },
+    }
+  }
+}
+
+/// A diffuse surface with a GGX glossy highlight.
+#[derive(Debug)]
+pub struct GlossyMaterial {
+  colour: Colour,
+  specular: Colour,
+  roughness: f32,
+}
+
+impl GlossyMaterial {
+  /// The roughness is perceptual, so the width of the GGX distribution is
+  /// its square.
+  pub fn new(colour: Colour, specular: Colour, roughness: f32) -> GlossyMaterial {
+    GlossyMaterial {
+      colour,
+      specular,
+      roughness,
+    }
+  }
+}
+
+impl Material for GlossyMaterial {
+  fn is_light(&self) -> bool {
+    false
+  }
+
+  fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    MaterialCollisionInfo {
+      ambient_colour: self.colour,
+      diffuse_colour: self.colour,
+      specular_colour: self.specular,
+      emissive_colour: None,
+      position: f.position,
+      normal: f.normal,
+      interior: None,
+      bsdf: Bsdf::new(f.normal, f.true_normal)
+        .with_lobe(Lobe::Diffuse(self.colour))
+        .with_lobe(Lobe::Glossy(self.specular, self.roughness * self.roughness)),
+    }
+  }
+}
+
+// The complex indices of refraction of metals at the red, green and blue
+// wavelengths of 650, 550 and 450nm, from the measurements of Palik.
+const METALS: [(&str, [f32; 3], [f32; 3]); 4] = [
+  ("gold", [0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+  ("copper", [0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+  ("silver", [0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+  ("aluminium", [1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+];
+
+/// A metal, which reflects according to its complex index of refraction
+/// rather than a constant colour, so that it brightens and loses its tint
+/// towards grazing angles.
+#[derive(Debug)]
+pub struct ConductorMaterial {
+  eta: Colour,
+  k: Colour,
+  roughness: f32,
+}
+
+impl ConductorMaterial {
+  /// A smooth metal with the index of refraction eta + ik for each channel.
+  pub fn new(eta: Colour, k: Colour) -> ConductorMaterial {
+    ConductorMaterial { eta, k, roughness: 0.0 }
+  }
+
+  /// One of the built in metals: gold, copper, silver or aluminium.
+  pub fn named(name: &str) -> Option<ConductorMaterial> {
+    let (_, eta, k) = METALS.iter().find(|(metal, _, _)| *metal == name)?;
+    return Some(ConductorMaterial::new(
+      Colour::RGB(eta[0], eta[1], eta[2]),
+      Colour::RGB(k[0], k[1], k[2]),
+    ));
+  }
+
+  /// The perceptual roughness of the surface, as for GlossyMaterial, where
+  /// zero is a mirror.
+  pub fn with_roughness(mut self, roughness: f32) -> Self {
+    self.roughness = roughness;
+    return self;
+  }
+}
+
+impl Material for ConductorMaterial {
+  fn is_light(&self) -> bool {
+    false
+  }
+
+  fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    // The index is relative to whatever the metal is in, such as water.
+    let outside_ior = f.ray_context.ior();
+    let (eta, k) = (self.eta * (1.0 / outside_ior), self.k * (1.0 / outside_ior));
+    let lobe = if self.roughness > 0.0 {
+      Lobe::RoughConductor {
+        eta,
+        k,
+        alpha: self.roughness * self.roughness,
+      }
+    } else {
+      Lobe::Conductor { eta, k }
+    };
+    // The albedo AOV shows the colour of the metal head on. Metals have no
+    // diffuse base, so no ambient term either.
+    let head_on = |eta: f32, k: f32| ((eta - 1.0) * (eta - 1.0) + k * k) / ((eta + 1.0) * (eta + 1.0) + k * k);
+    let colour = Colour::RGB(
+      head_on(self.eta.r(), self.k.r()),
+      head_on(self.eta.g(), self.k.g()),
+      head_on(self.eta.b(), self.k.b()),
+    );
+    MaterialCollisionInfo {
+      ambient_colour: Colour::new(),
+      diffuse_colour: colour,
+      specular_colour: colour,
+      emissive_colour: None,
+      position: f.position,
+      normal: f.normal,
+      interior: None,
+      bsdf: Bsdf::new(f.normal, f.true_normal).with_lobe(lobe),
     }
   }
 }
 
+/// Glass, which may be rough, and may be filled with a medium such as an
+/// AbsorbingMedia to tint it with depth. Where glass overlaps other
+/// dielectrics, such as the water in it, the one with the highest priority
+/// fills the overlap. In spectral mode, glass with a Dispersion splits light
+/// into its colours.
 #[derive(Debug)]
 pub struct TransparentMaterial {
   ior: f32,
   colour: Colour,
+  roughness: f32,
+  media: Option<MediaIdx>,
+  priority: i32,
+  dispersion: Option<Dispersion>,
 }
 
 impl Material for TransparentMaterial {
@@ -99,17 +280,47 @@ impl Material for TransparentMaterial {
   }
 
   fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    // Dispersive glass bends each path by the index of its hero wavelength.
+    let ior = match (self.dispersion, f.ray_context.wavelengths) {
+      (Some(dispersion), Some(wavelengths)) => dispersion.ior(wavelengths.hero()),
+      _ => self.ior,
+    };
+    let interior = Interior {
+      material: f.material,
+      priority: self.priority,
+      ior: Some(ior),
+      media: self.media,
+    };
+    let (inside_ior, outside_ior) = f.ray_context.interface_iors(interior);
+    let lobe = if self.roughness > 0.0 {
+      Lobe::RoughDielectric {
+        reflection: self.colour,
+        transmission: self.colour,
+        inside_ior,
+        outside_ior,
+        alpha: self.roughness * self.roughness,
+      }
+    } else {
+      Lobe::Dielectric {
+        reflection: self.colour,
+        transmission: self.colour,
+        inside_ior,
+        outside_ior,
+      }
+    };
+    let mut bsdf = Bsdf::new(f.normal, f.true_normal).with_lobe(lobe);
+    if self.dispersion.is_some() {
+      bsdf = bsdf.with_dispersion();
+    }
     MaterialCollisionInfo {
       ambient_colour: self.colour,
       diffuse_colour: self.colour,
       specular_colour: self.colour,
       emissive_colour: None,
-      transparent_colour: Some(self.colour),
       position: f.position,
       normal: f.normal,
-      index_of_refraction: Some((self.ior, 1.0)),
-      reflectivity: None,
-      media_transition: None,
+      interior: Some(interior),
+      bsdf,
     }
   }
 }
@@ -118,132 +329,222 @@ impl TransparentMaterial {
     TransparentMaterial {
       ior,
       colour: Colour::RGB(1.0, 1.0, 1.0),
+      roughness: 0.0,
+      media: None,
+      priority: 0,
+      dispersion: None,
     }
   }
+
+  /// The perceptual roughness of the surface, as for GlossyMaterial, where
+  /// zero is smooth.
+  pub fn with_roughness(mut self, roughness: f32) -> Self {
+    self.roughness = roughness;
+    return self;
+  }
+
+  /// The medium that fills the inside of the glass.
+  pub fn with_media(mut self, media: MediaIdx) -> Self {
+    self.media = Some(media);
+    return self;
+  }
+
+  pub fn with_priority(mut self, priority: i32) -> Self {
+    self.priority = priority;
+    return self;
+  }
+
+  /// How the index varies with wavelength. Outside of spectral mode, the
+  /// glass takes its index at the D line.
+  pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+    self.ior = dispersion.ior(D_LINE);
+    self.dispersion = Some(dispersion);
+    return self;
+  }
 }
 
-pub fn compute_secondaries(ray: &Ray, fragment: &Fragment, surface: &MaterialCollisionInfo) -> Vec<(Ray, Colour, f32)> {
-  if surface.transparent_colour.is_none() {
-    if let Some((reflection_weight, reflection_colour)) = surface.reflectivity {
-      let reflected_direction = fragment.view.reflect(surface.normal);
-      let reflected_ray = Ray::new(
-        surface.position + (reflected_direction * 0.01),
-        reflected_direction,
-        Some(ray.ray_context.clone()),
-      );
-      return vec![(reflected_ray, reflection_colour, reflection_weight)];
-    } else {
-      return vec![];
+#[derive(Debug)]
+pub struct EmissiveMaterial {
+  colour: Colour,
+  emission: EmissionCoefficients,
+}
+
+impl EmissiveMaterial {
+  pub fn new(colour: Colour, intensity: f32) -> EmissiveMaterial {
+    EmissiveMaterial {
+      colour,
+      emission: EmissionCoefficients {
+        ambient: 0.0,
+        diffuse: intensity,
+        specular: 0.0,
+        blackbody: None,
+        colour: None,
+      },
     }
   }
 
-  let normal = surface.normal;
-  let reflected_ray = fragment.view.reflect(normal);
-  let transparent_colour = if let Some(transparent_colour) = surface.transparent_colour {
-    Colour::RGB(1.0, 1.0, 1.0) - transparent_colour
-  } else {
-    Colour::RGB(1.0, 1.0, 1.0)
-  };
-  let reflected_colour = if let Some((_, reflected_colour)) = surface.reflectivity {
-    reflected_colour
-  } else {
-    surface.diffuse_colour
-  };
+  /// Emits the spectrum of the black body in spectral rendering, if there is
+  /// one, which the colour should be that of, see Blackbody::colour.
+  pub fn with_blackbody(mut self, blackbody: Option<Blackbody>) -> Self {
+    self.emission.blackbody = blackbody;
+    return self;
+  }
+}
 
-  let mut result = vec![];
-  let mut refraction_weight = 1.0;
-  let mut exiting_object = false;
-  let (refracted_vector, new_context): (Vector, RayContext) = match surface.index_of_refraction {
-    None => (fragment.view, ray.ray_context.clone()),
-    Some((internal_ior, external_ior)) => {
-      let view = fragment.view * -1.0;
-      let in_object = fragment.view.dot(fragment.true_normal) < 0.0;
-      let (ni, nt, new_context) = if in_object {
-        exiting_object = true;
-        let new_context = ray.ray_context.exit_material();
-        (internal_ior, external_ior, new_context)
-      } else {
-        let new_context = ray
-          .ray_context
-          .enter_material(surface.media_transition.map(|m| m.internal).unwrap_or(None));
-        (external_ior, internal_ior, new_context)
-      };
-      let nr = ni as f32 / nt as f32;
-
-      let n_dot_v = normal.dot(view);
-
-      let inner = 1.0 - nr * nr * (1.0 - n_dot_v * n_dot_v);
-      if inner < 0.0 {
-        (reflected_ray, ray.ray_context.clone())
-      } else {
-        // Schlick approximation of fresnel term
-        let r0 = {
-          let r0root = (nt - ni) / (nt + ni);
-          r0root * r0root
-        };
-        let fresnel_weight = {
-          let one_minus_cos_theta = 1.0 - n_dot_v;
-          let squared = one_minus_cos_theta * one_minus_cos_theta;
-          let quintupled = squared * squared * one_minus_cos_theta;
-          r0 + (1.0 - r0) * quintupled
-        };
-        if fresnel_weight > 0.02 {
-          result.push((
-            Ray::new(
-              fragment.position + reflected_ray * 0.01,
-              reflected_ray,
-              Some(ray.ray_context.clone()),
-            ),
-            surface.specular_colour,
-            fresnel_weight,
-          ));
-          refraction_weight -= fresnel_weight;
-        }
-        (
-          ((nr * n_dot_v - inner.sqrt()) * normal - nr * view).normalize(),
-          new_context,
-        )
-      }
+impl Material for EmissiveMaterial {
+  fn is_light(&self) -> bool {
+    self.emission.max_value() > 0.0
+  }
+
+  fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    MaterialCollisionInfo {
+      ambient_colour: self.colour,
+      diffuse_colour: self.colour,
+      specular_colour: self.colour,
+      emissive_colour: Some(self.emission),
+      position: f.position,
+      normal: f.normal,
+      interior: None,
+      bsdf: Bsdf::new(f.normal, f.true_normal),
     }
+  }
+}
+
+/// The context of a ray leaving the surface in `direction`. Rays that are
+/// transmitted through the surface enter or leave its interior, if it has
+/// one, and rays scattered by a dispersive surface are left with their hero
+/// wavelength.
+pub fn scattered_context(
+  context: &RayContext,
+  fragment: &Fragment,
+  surface: &MaterialCollisionInfo,
+  direction: Vector,
+) -> RayContext {
+  let exiting = fragment.view.dot(fragment.true_normal) < 0.0;
+  let transmitted = (direction.dot(fragment.true_normal) < 0.0) == exiting;
+  let mut context = match surface.interior {
+    Some(interior) if transmitted && exiting => context.exit_material(interior.material),
+    Some(interior) if transmitted => context.enter_material(interior),
+    _ => context.clone(),
   };
-  result.push((
-    Ray::new(
-      fragment.position + refracted_vector * 0.01,
-      refracted_vector,
-      Some(new_context),
-    ),
-    transparent_colour,
-    refraction_weight,
-  ));
+  if surface.bsdf.disperses() {
+    context.wavelengths = context.wavelengths.map(|wavelengths| wavelengths.dispersed());
+  }
+  return context;
+}
 
-  return result;
+/// The ray leaving the surface in a direction picked by its Bsdf.
+pub fn scattered_ray(ray: &Ray, fragment: &Fragment, surface: &MaterialCollisionInfo, direction: Vector) -> Ray {
+  // Triangles accept hits slightly behind the minimum distance, so
+  // offsetting the origin alone is not enough to avoid hitting the surface
+  // again.
+  return Ray::new_bound(
+    surface.position,
+    direction,
+    0.005,
+    std::f32::INFINITY,
+    Some(scattered_context(&ray.ray_context, fragment, surface, direction)),
+  );
 }
 
+/// The rays that the delta lobes of the surface scatter the ray into, with
+/// the colour that each is scaled by.
+pub fn compute_secondaries(ray: &Ray, fragment: &Fragment, surface: &MaterialCollisionInfo) -> Vec<(Ray, Colour)> {
+  let wo = -fragment.view.normalize();
+  return surface
+    .bsdf
+    .delta_directions(wo)
+    .into_iter()
+    .map(|(direction, colour)| (scattered_ray(ray, fragment, surface, direction), colour))
+    .collect();
+}
+
+/// Invisible surfaces with an interior are the boundaries of media, which
+/// rays pass straight through into the medium on the other side.
+/// Returns the continuation of the ray if the surface is one of these.
+pub fn cross_media_boundary(ray: &Ray, fragment: &Fragment, surface: &MaterialCollisionInfo) -> Option<Ray> {
+  surface.interior?;
+  if !surface.bsdf.is_invisible() {
+    return None;
+  }
+  return Some(Ray::new_bound(
+    fragment.position,
+    ray.direction,
+    0.005,
+    std::f32::INFINITY,
+    Some(scattered_context(&ray.ray_context, fragment, surface, ray.direction)),
+  ));
+}
+
+/// An invisible boundary that fills the inside of an object with a
+/// participating medium.
 #[derive(Debug)]
-struct Fog {
+pub struct Fog {
   media: MediaIdx,
 }
 
+impl Fog {
+  pub fn new(media: MediaIdx) -> Fog {
+    Fog { media }
+  }
+}
+
 impl Material for Fog {
   fn is_light(&self) -> bool {
     false
   }
-  fn compute_surface_properties(&self, s: &Scene, ray: &Ray, f: &Fragment) -> MaterialCollisionInfo {
-    let transition = MediaTransition {
-      internal: Some(self.media),
-      external: None,
+  fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    let interior = Interior {
+      material: f.material,
+      priority: 0,
+      ior: None,
+      media: Some(self.media),
     };
     return MaterialCollisionInfo {
       ambient_colour: Colour::RGB(1.0, 1.0, 1.0),
       diffuse_colour: Colour::RGB(1.0, 1.0, 1.0),
       specular_colour: Colour::RGB(1.0, 1.0, 1.0),
       emissive_colour: None,
-      transparent_colour: Some(Colour::RGB(1.0, 1.0, 1.0)),
       position: f.position,
       normal: f.normal,
-      index_of_refraction: Some((1.0, 1.0)),
-      reflectivity: None,
-      media_transition: Some(transition),
+      interior: Some(interior),
+      bsdf: Bsdf::new(f.normal, f.true_normal).with_lobe(Lobe::Dielectric {
+        reflection: Colour::new(),
+        transmission: Colour::RGB(1.0, 1.0, 1.0),
+        inside_ior: 1.0,
+        outside_ior: 1.0,
+      }),
+    };
+  }
+}
+
+/// The material of the points where a medium scatters light, which scatter
+/// it to both sides with the albedo of the medium, see Media.
+#[derive(Debug)]
+pub struct ScatteringMaterial {
+  colour: Colour,
+}
+
+impl ScatteringMaterial {
+  pub fn new(colour: Colour) -> ScatteringMaterial {
+    ScatteringMaterial { colour }
+  }
+}
+
+impl Material for ScatteringMaterial {
+  fn is_light(&self) -> bool {
+    false
+  }
+  fn compute_surface_properties(&self, _s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    return MaterialCollisionInfo {
+      ambient_colour: self.colour,
+      diffuse_colour: self.colour,
+      specular_colour: self.colour,
+      emissive_colour: None,
+      position: f.position,
+      normal: f.normal,
+      interior: None,
+      bsdf: Bsdf::new(f.normal, f.true_normal).with_lobe(Lobe::Scattering(self.colour)),
     };
   }
 }
@@ -1,28 +1,460 @@
 use crate::fragment::Fragment;
 use crate::colour::Colour;
-use crate::bounding_box::BoundingBox;
-use crate::light::Light;
 use crate::scene::Scene;
-use crate::intersectable::HitMode;
-use crate::bounding_box::HasBoundingBox;
+use crate::scene::MaterialIdx;
 use std::fmt::Debug;
-use crate::intersectable::Intersectable;
 use crate::ray::Ray;
 use crate::collision::Collision;
 use crate::shader::Shadable;
+use crate::photon_map::random;
+use crate::sampling::tangent_frame;
+use crate::bounding_box::BoundingBox;
+use crate::vectors::{Point, Vector, VectorType};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
 
+/// A point where a ray travelling through a medium was scattered.
 pub struct MediaIntersection {
-  diffuse_colour: Colour,
-  density: f32,
+  pub diffuse_colour: Colour,
+  pub density: f32,
 }
 
+/// A participating medium that fills the inside of objects whose material
+/// has a media transition, see Fog.
+///
+/// Media scatter with a phase function of |cos| / 2pi about the direction
+/// of the ray, which is a diffuse lobe on each side of the scattering point,
+/// see Lobe::Scattering.
 pub trait Media: Debug + Send + Sync + Shadable {
+  /// Samples the distance that the ray travels before it is scattered or
+  /// absorbed, if that happens before it reaches `r.max`.
   fn compute_media_fragment(&self, s: &Scene, r: &Ray) -> Option<(f32, MediaIntersection)>;
+  /// The fraction of light that passes between `r.min` and `r.max` along
+  /// the ray without being scattered or absorbed.
+  fn transmittance(&self, s: &Scene, r: &Ray) -> f32;
+  /// The colour that light passing between `r.min` and `r.max` is scaled
+  /// by, on top of the chance that compute_media_fragment stops it. Media
+  /// that only absorb light never stop rays, and attenuate them here
+  /// instead, see Scene::attenuation.
+  fn attenuation(&self, _: &Scene, _: &Ray) -> Colour {
+    return Colour::RGB(1.0, 1.0, 1.0);
+  }
 }
 
+/// A medium with the same density throughout. The density is the extinction
+/// coefficient, and the colour is the fraction of extinguished light that is
+/// scattered rather than absorbed.
 #[derive(Debug)]
-struct HomogenousMedia {
+pub struct HomogenousMedia {
   density: f32,
   colour: Colour,
+  material: MaterialIdx,
+}
+
+impl HomogenousMedia {
+  /// `material` shades the scattering points, and should be a
+  /// ScatteringMaterial with the same colour.
+  pub fn new(density: f32, colour: Colour, material: MaterialIdx) -> HomogenousMedia {
+    HomogenousMedia {
+      density,
+      colour,
+      material,
+    }
+  }
+}
+
+impl Media for HomogenousMedia {
+  fn compute_media_fragment(&self, _: &Scene, r: &Ray) -> Option<(f32, MediaIntersection)> {
+    if self.density <= 0.0 {
+      return None;
+    }
+    let distance = r.min - (1.0 - random(0.0, 1.0) as f32).ln() / self.density;
+    if distance >= r.max {
+      return None;
+    }
+    return Some((
+      distance,
+      MediaIntersection {
+        diffuse_colour: self.colour,
+        density: self.density,
+      },
+    ));
+  }
+
+  fn transmittance(&self, _: &Scene, r: &Ray) -> f32 {
+    let length = r.max - r.min;
+    if !length.is_finite() {
+      return if self.density > 0.0 { 0.0 } else { 1.0 };
+    }
+    return (-self.density * length.max(0.0)).exp();
+  }
+}
+
+/// A clear medium that absorbs a different fraction of each channel per unit
+/// of distance, following the Beer-Lambert law, such as the inside of tinted
+/// glass or water.
+#[derive(Debug)]
+pub struct AbsorbingMedia {
+  absorption: Colour,
+}
+
+impl AbsorbingMedia {
+  /// The absorption coefficients are per unit of distance in the scene.
+  pub fn new(absorption: Colour) -> AbsorbingMedia {
+    AbsorbingMedia { absorption }
+  }
+
+  /// The medium through which a unit of distance leaves `colour` of the
+  /// light. Channels that are zero absorb everything.
+  pub fn from_transmission(colour: Colour) -> AbsorbingMedia {
+    let coefficient = |value: f32| -value.max(1e-6).min(1.0).ln();
+    AbsorbingMedia::new(Colour::RGB(
+      coefficient(colour.r()),
+      coefficient(colour.g()),
+      coefficient(colour.b()),
+    ))
+  }
+}
+
+impl Media for AbsorbingMedia {
+  fn compute_media_fragment(&self, _: &Scene, _: &Ray) -> Option<(f32, MediaIntersection)> {
+    return None;
+  }
+
+  fn transmittance(&self, _: &Scene, _: &Ray) -> f32 {
+    return 1.0;
+  }
+
+  fn attenuation(&self, _: &Scene, r: &Ray) -> Colour {
+    let length = (r.max - r.min).max(0.0);
+    let absorption = r.ray_context.upsample(self.absorption);
+    let channel = |coefficient: f32| {
+      if coefficient <= 0.0 {
+        return 1.0;
+      }
+      return (-coefficient * length).exp();
+    };
+    return Colour::RGB(
+      channel(absorption.r()),
+      channel(absorption.g()),
+      channel(absorption.b()),
+    );
+  }
+}
+
+impl Shadable for AbsorbingMedia {
+  fn compute_fragment(&self, _: &Scene, _: &Ray, _: &Collision) -> Fragment {
+    unreachable!("Absorbing media never stop rays");
+  }
+}
+
+// The fragment for a scattering point, which faces back along the ray.
+fn scattering_fragment(ray: &Ray, collision: &Collision, material: MaterialIdx) -> Fragment {
+  let direction = ray.direction.normalize();
+  let (dpdu, dpdv) = tangent_frame(direction);
+  return Fragment {
+    position: ray.origin + ray.direction * collision.distance,
+    normal: -direction,
+    true_normal: -direction,
+    uv: collision.uv,
+    dpdu,
+    dpdv,
+    view: ray.direction,
+    material,
+    ray_context: ray.ray_context.clone(),
+  };
+}
+
+impl Shadable for HomogenousMedia {
+  fn compute_fragment(&self, _: &Scene, ray: &Ray, collision: &Collision) -> Fragment {
+    return scattering_fragment(ray, collision, self.material);
+  }
+}
+
+// Reads a single channel float32 grid in the Mitsuba .vol format: the bytes
+// "VOL" and a version of 3, then little endian fields for the encoding, the
+// resolution along each axis, the channel count and the bounding box, followed
+// by the values with x varying fastest.
+fn read_vol<R: Read>(mut reader: R) -> Result<VoxelGrid, String> {
+  let mut data = vec![];
+  reader.read_to_end(&mut data).map_err(|msg| msg.to_string())?;
+  if data.len() < 48 || &data[0..3] != b"VOL" {
+    return Err("Not a VOL file".to_string());
+  }
+  if data[3] != 3 {
+    return Err(format!("Unsupported VOL version {}", data[3]));
+  }
+  let word = |offset: usize| [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+  let int = |index: usize| i32::from_le_bytes(word(4 + index * 4));
+  let float = |offset: usize| f32::from_bits(u32::from_le_bytes(word(offset)));
+  if int(0) != 1 {
+    return Err(format!(
+      "Unsupported VOL encoding {}, only float32 grids can be read",
+      int(0)
+    ));
+  }
+  if int(4) != 1 {
+    return Err(format!("Unsupported VOL channel count {}", int(4)));
+  }
+  if int(1) <= 0 || int(2) <= 0 || int(3) <= 0 {
+    return Err("Empty VOL grid".to_string());
+  }
+  let size = [int(1) as usize, int(2) as usize, int(3) as usize];
+  let count = size[0] * size[1] * size[2];
+  if data.len() < 48 + count * 4 {
+    return Err("Truncated VOL data".to_string());
+  }
+  let bounds = BoundingBox {
+    min: Vector::point(float(24) as f64, float(28) as f64, float(32) as f64),
+    max: Vector::point(float(36) as f64, float(40) as f64, float(44) as f64),
+  };
+  let densities = (0..count).map(|index| float(48 + index * 4)).collect();
+  return Ok(VoxelGrid::new(size, densities, bounds));
+}
+
+/// A dense grid of densities placed in the scene by a bounding box, such as
+/// a simulation of smoke. The values are at the centres of the voxels.
+#[derive(Debug)]
+pub struct VoxelGrid {
+  size: [usize; 3],
+  densities: Vec<f32>,
   bounds: BoundingBox,
+  max_density: f32,
+}
+
+impl VoxelGrid {
+  /// `densities` has x varying fastest, then y, then z.
+  pub fn new(size: [usize; 3], densities: Vec<f32>, bounds: BoundingBox) -> VoxelGrid {
+    assert_eq!(densities.len(), size[0] * size[1] * size[2]);
+    let max_density = densities.iter().cloned().fold(0.0, f32::max);
+    return VoxelGrid {
+      size,
+      densities,
+      bounds,
+      max_density,
+    };
+  }
+
+  /// Loads a .vol grid, placed by the bounding box in the file.
+  pub fn load(path: &Path) -> VoxelGrid {
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(msg) => panic!("Fopen({:?}) failed with {}", path, msg),
+    };
+    return match read_vol(BufReader::new(file)) {
+      Ok(grid) => grid,
+      Err(msg) => panic!("Failed to open {:?} with error: {}", path, msg),
+    };
+  }
+
+  /// Places the grid in a different box.
+  pub fn with_bounds(mut self, bounds: BoundingBox) -> Self {
+    self.bounds = bounds;
+    return self;
+  }
+
+  pub fn bounds(&self) -> BoundingBox {
+    return self.bounds;
+  }
+
+  fn voxel(&self, x: isize, y: isize, z: isize) -> f32 {
+    let clamp = |value: isize, size: usize| value.max(0).min(size as isize - 1) as usize;
+    let (x, y, z) = (clamp(x, self.size[0]), clamp(y, self.size[1]), clamp(z, self.size[2]));
+    return self.densities[(z * self.size[1] + y) * self.size[0] + x];
+  }
+
+  /// The trilinearly interpolated density at the point, which is zero outside
+  /// of the bounds.
+  pub fn density(&self, point: Point) -> f32 {
+    let offset = self.bounds.offset(point);
+    let mut cell = [0isize; 3];
+    let mut fraction = [0.0f32; 3];
+    for axis in 0..3 {
+      let value = offset.axis(axis);
+      if !(value >= 0.0 && value <= 1.0) {
+        return 0.0;
+      }
+      let position = value * self.size[axis] as f32 - 0.5;
+      let floor = position.floor();
+      cell[axis] = floor as isize;
+      fraction[axis] = position - floor;
+    }
+    let mut result = 0.0;
+    for corner in 0..8 {
+      let mut weight = 1.0;
+      let mut index = cell;
+      for axis in 0..3 {
+        if corner & (1 << axis) != 0 {
+          index[axis] += 1;
+          weight *= fraction[axis];
+        } else {
+          weight *= 1.0 - fraction[axis];
+        }
+      }
+      if weight > 0.0 {
+        result += weight * self.voxel(index[0], index[1], index[2]);
+      }
+    }
+    return result;
+  }
+}
+
+/// A medium whose density varies through a voxel grid. Free flights are
+/// sampled by delta tracking and transmittance is estimated by ratio
+/// tracking, both against the largest density in the grid.
+#[derive(Debug)]
+pub struct HeterogeneousMedia {
+  grid: VoxelGrid,
+  density: f32,
+  colour: Colour,
+  material: MaterialIdx,
+}
+
+impl HeterogeneousMedia {
+  /// The grid's values are scaled by `density` to give the extinction
+  /// coefficient, and the colour is the albedo, as for HomogenousMedia.
+  pub fn new(grid: VoxelGrid, density: f32, colour: Colour, material: MaterialIdx) -> HeterogeneousMedia {
+    HeterogeneousMedia {
+      grid,
+      density,
+      colour,
+      material,
+    }
+  }
+
+  fn majorant(&self) -> f32 {
+    return self.grid.max_density * self.density;
+  }
+
+  // The part of the ray inside the grid, if there is one.
+  fn overlap(&self, r: &Ray) -> Option<(f32, f32)> {
+    if !(self.majorant() > 0.0) {
+      return None;
+    }
+    let (start, end) = self.grid.bounds.intersect(r, r.min, r.max)?;
+    let end = end.min(r.max);
+    if start >= end {
+      return None;
+    }
+    return Some((start, end));
+  }
+}
+
+impl Media for HeterogeneousMedia {
+  fn compute_media_fragment(&self, _: &Scene, r: &Ray) -> Option<(f32, MediaIntersection)> {
+    let (mut distance, end) = self.overlap(r)?;
+    let majorant = self.majorant();
+    loop {
+      distance -= (1.0 - random(0.0, 1.0) as f32).ln() / majorant;
+      if distance >= end {
+        return None;
+      }
+      // Collisions with the fictitious medium that tops the density up to
+      // the majorant are skipped.
+      let density = self.grid.density(r.origin + r.direction * distance) * self.density;
+      if random(0.0, 1.0) as f32 * majorant < density {
+        return Some((
+          distance,
+          MediaIntersection {
+            diffuse_colour: self.colour,
+            density,
+          },
+        ));
+      }
+    }
+  }
+
+  fn transmittance(&self, _: &Scene, r: &Ray) -> f32 {
+    let (mut distance, end) = match self.overlap(r) {
+      Some(overlap) => overlap,
+      None => return 1.0,
+    };
+    let majorant = self.majorant();
+    let mut transmittance = 1.0;
+    loop {
+      distance -= (1.0 - random(0.0, 1.0) as f32).ln() / majorant;
+      if distance >= end {
+        return transmittance;
+      }
+      let density = self.grid.density(r.origin + r.direction * distance) * self.density;
+      transmittance *= 1.0 - density / majorant;
+      if transmittance <= 0.0 {
+        return 0.0;
+      }
+    }
+  }
+}
+
+impl Shadable for HeterogeneousMedia {
+  fn compute_fragment(&self, _: &Scene, ray: &Ray, collision: &Collision) -> Fragment {
+    return scattering_fragment(ray, collision, self.material);
+  }
+}
+
+#[test]
+fn test_read_vol() {
+  let mut data = b"VOL".to_vec();
+  data.push(3);
+  for value in &[1i32, 2, 1, 1, 1] {
+    data.extend_from_slice(&value.to_le_bytes());
+  }
+  for value in &[0.0f32, 0.0, 0.0, 2.0, 1.0, 1.0, 1.0, 3.0] {
+    data.extend_from_slice(&value.to_bits().to_le_bytes());
+  }
+  let grid = read_vol(&data[..]).unwrap();
+  assert_eq!(grid.size, [2, 1, 1]);
+  assert_eq!(grid.max_density, 3.0);
+  // Values are at the centres of the voxels, and clamp towards the edges.
+  assert_eq!(grid.density(Vector::point(0.25, 0.5, 0.5)), 1.0);
+  assert_eq!(grid.density(Vector::point(1.0, 0.5, 0.5)), 2.0);
+  assert_eq!(grid.density(Vector::point(1.9, 0.5, 0.5)), 3.0);
+  assert_eq!(grid.density(Vector::point(2.5, 0.5, 0.5)), 0.0);
+}
+
+#[test]
+fn test_transmittance() {
+  use crate::scene::SceneSettings;
+  let mut settings = SceneSettings::new();
+  settings.scene_file = std::env::temp_dir().to_string_lossy().to_string();
+  let scene = Scene::new(&settings);
+  let ray = Ray::new_bound(
+    Vector::point(-1.0, 0.25, 0.5),
+    Vector::vector(1.0, 0.0, 0.0),
+    0.0,
+    4.0,
+    None,
+  );
+  let count = 20000;
+
+  // A homogenous medium has the transmittance exp(-sigma_t * d), which is
+  // also the chance that a free flight gets through.
+  let homogenous = HomogenousMedia::new(0.5, Colour::RGB(1.0, 1.0, 1.0), MaterialIdx(0));
+  let expected = (-0.5f32 * 4.0).exp();
+  assert!((homogenous.transmittance(&scene, &ray) - expected).abs() < 0.0001);
+  let escaped = (0..count)
+    .filter(|_| homogenous.compute_media_fragment(&scene, &ray).is_none())
+    .count();
+  assert!((escaped as f32 / count as f32 - expected).abs() < 0.015);
+
+  // The ray crosses two units of the grid's lower row, where the density is
+  // a quarter of the largest, so ratio tracking has to weight its steps.
+  let bounds = BoundingBox::new_from_point(Vector::point(0.0, 0.0, 0.0)).merge_with_point(Vector::point(2.0, 2.0, 1.0));
+  let grid = VoxelGrid::new([2, 2, 1], vec![1.0, 1.0, 4.0, 4.0], bounds);
+  let heterogeneous = HeterogeneousMedia::new(grid, 0.5, Colour::RGB(1.0, 1.0, 1.0), MaterialIdx(0));
+  let expected = (-0.5f32 * 2.0).exp();
+  let mut transmittance = 0.0;
+  let mut escaped = 0;
+  for _ in 0..count {
+    transmittance += heterogeneous.transmittance(&scene, &ray) / count as f32;
+    if heterogeneous.compute_media_fragment(&scene, &ray).is_none() {
+      escaped += 1;
+    }
+  }
+  assert!(
+    (transmittance - expected).abs() < 0.015,
+    "transmittance {}",
+    transmittance
+  );
+  assert!((escaped as f32 / count as f32 - expected).abs() < 0.015);
 }
@@ -4,10 +4,12 @@ use crate::bvh::BVH;
 use crate::collision::Collision;
 use crate::intersectable::*;
 use crate::ray::Ray;
+use crate::scene::MaterialIdx;
 use crate::scene::Scene;
 use crate::shader::*;
 
 use crate::triangle::Triangle;
+use crate::vectors::{Vector, VectorType};
 
 #[derive(Debug)]
 pub struct Mesh {
@@ -34,6 +36,42 @@ impl Mesh {
       bbox: bounds,
     }
   }
+
+  /// A closed box filling the bounds, wound so that the faces look outwards.
+  pub fn new_box(bounds: &BoundingBox, material: MaterialIdx) -> Mesh {
+    let corner = |flags: [bool; 3]| {
+      let select = |axis: usize| {
+        if flags[axis] {
+          bounds.max.axis(axis) as f64
+        } else {
+          bounds.min.axis(axis) as f64
+        }
+      };
+      return (Vector::point(select(0), select(1), select(2)), None, None);
+    };
+    let mut triangles = vec![];
+    for axis in 0..3 {
+      for &side in &[false, true] {
+        // Going around the face in this order winds it towards +axis.
+        let mut quad: Vec<_> = [(false, false), (true, false), (true, true), (false, true)]
+          .iter()
+          .map(|&(u, v)| {
+            let mut flags = [false; 3];
+            flags[axis] = side;
+            flags[(axis + 1) % 3] = u;
+            flags[(axis + 2) % 3] = v;
+            corner(flags)
+          })
+          .collect();
+        if !side {
+          quad.reverse();
+        }
+        triangles.push(Triangle::new(material, quad[0], quad[1], quad[2]));
+        triangles.push(Triangle::new(material, quad[0], quad[2], quad[3]));
+      }
+    }
+    return Mesh::new(&triangles);
+  }
 }
 
 impl HasBoundingBox for Mesh {
@@ -0,0 +1,174 @@
+use crate::sampling::{random_pair, tangent_frame};
+use crate::vectors::{Vector, VectorType};
+use std::f32::consts::PI;
+
+// The GGX microfacet reflection model of Walter et al. with the separable
+// Smith shadowing term. Directions all point away from the surface, and
+// alpha is the width of the distribution, where small values are close to a
+// mirror. The Fresnel term is left to the specular colour that scales the
+// lobe.
+
+/// The GGX width that gives a highlight similar to a Phong exponent, such as
+/// the Ns of a wavefront material.
+pub fn roughness_from_exponent(exponent: f32) -> f32 {
+  return (2.0 / (exponent.max(0.0) + 2.0)).sqrt();
+}
+
+// The density of microfacet normals at the given cosine to the normal.
+fn distribution(cos_h: f32, alpha: f32) -> f32 {
+  if cos_h <= 0.0 {
+    return 0.0;
+  }
+  let alpha2 = alpha * alpha;
+  let denominator = cos_h * cos_h * (alpha2 - 1.0) + 1.0;
+  return alpha2 / (PI * denominator * denominator);
+}
+
+// The fraction of microfacets visible from a direction at the given cosine to
+// the normal.
+fn smith_g1(cos: f32, alpha: f32) -> f32 {
+  let alpha2 = alpha * alpha;
+  return 2.0 * cos / (cos + (alpha2 + (1.0 - alpha2) * cos * cos).sqrt());
+}
+
+/// The GGX BRDF, without the Fresnel term, for light arriving from
+/// `incoming` and leaving towards `outgoing`.
+pub fn ggx_reflectance(normal: Vector, outgoing: Vector, incoming: Vector, alpha: f32) -> f32 {
+  let cos_o = normal.dot(outgoing);
+  let cos_i = normal.dot(incoming);
+  if cos_o <= 0.0 || cos_i <= 0.0 {
+    return 0.0;
+  }
+  let half = (outgoing + incoming).normalize();
+  let alpha = alpha.max(0.001);
+  let d = distribution(normal.dot(half), alpha);
+  let g = smith_g1(cos_o, alpha) * smith_g1(cos_i, alpha);
+  return d * g / (4.0 * cos_o * cos_i);
+}
+
+/// The density of microfacets facing along `half`.
+pub fn ggx_distribution(normal: Vector, half: Vector, alpha: f32) -> f32 {
+  return distribution(normal.dot(half), alpha.max(0.001));
+}
+
+/// The shadowing and masking of the microfacets seen from both directions,
+/// which may be on either side of the surface.
+pub fn ggx_shadowing(normal: Vector, outgoing: Vector, incoming: Vector, alpha: f32) -> f32 {
+  let alpha = alpha.max(0.001);
+  return smith_g1(normal.dot(outgoing).abs(), alpha) * smith_g1(normal.dot(incoming).abs(), alpha);
+}
+
+// Picks a microfacet normal for the uniform sample `u` in proportion to its
+// projected density, see ggx_normal_pdf.
+fn sample_ggx_normal(normal: Vector, alpha: f32, u: (f32, f32)) -> Vector {
+  let alpha = alpha.max(0.001);
+  let (u, v) = u;
+  let phi = 2.0 * PI * v;
+  let cos_h = ((1.0 - u) / (1.0 + (alpha * alpha - 1.0) * u)).sqrt();
+  let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+  let (tangent, bitangent) = tangent_frame(normal);
+  return (tangent * (sin_h * phi.cos()) + bitangent * (sin_h * phi.sin()) + normal * cos_h).normalize();
+}
+
+// The solid angle density with which sample_ggx_normal picks `half`.
+fn ggx_normal_pdf(normal: Vector, half: Vector, alpha: f32) -> f32 {
+  return ggx_distribution(normal, half, alpha) * normal.dot(half);
+}
+
+/// Picks a microfacet normal for the uniform sample `u` in proportion to how
+/// much of it is visible from `outgoing`, with the method of Heitz 2018. The
+/// weights of samples taken this way stay bounded at grazing angles, where
+/// refraction through the steepest microfacets matters most.
+pub fn sample_ggx_visible_normal(normal: Vector, outgoing: Vector, alpha: f32, u: (f32, f32)) -> Vector {
+  let alpha = alpha.max(0.001);
+  let (tangent, bitangent) = tangent_frame(normal);
+  // Stretch the view into the configuration where the distribution is a
+  // hemisphere, and sample the projection of that.
+  let view = Vector::vector(
+    (alpha * outgoing.dot(tangent)) as f64,
+    (alpha * outgoing.dot(bitangent)) as f64,
+    outgoing.dot(normal).max(0.0) as f64,
+  )
+  .normalize();
+  let length_squared = view.x() * view.x() + view.y() * view.y();
+  let axis1 = if length_squared > 0.0 {
+    Vector::vector(-view.y() as f64, view.x() as f64, 0.0) * (1.0 / length_squared.sqrt())
+  } else {
+    Vector::vector(1.0, 0.0, 0.0)
+  };
+  let axis2 = view.cross(axis1);
+  let radius = u.0.sqrt();
+  let phi = 2.0 * PI * u.1;
+  let t1 = radius * phi.cos();
+  let s = 0.5 * (1.0 + view.z());
+  let t2 = (1.0 - s) * (1.0 - t1 * t1).max(0.0).sqrt() + s * radius * phi.sin();
+  let local = axis1 * t1 + axis2 * t2 + view * (1.0 - t1 * t1 - t2 * t2).max(0.0).sqrt();
+  return (tangent * (alpha * local.x()) + bitangent * (alpha * local.y()) + normal * local.z().max(0.0)).normalize();
+}
+
+/// The solid angle density with which sample_ggx_visible_normal picks
+/// `half`.
+pub fn ggx_visible_normal_pdf(normal: Vector, outgoing: Vector, half: Vector, alpha: f32) -> f32 {
+  let cos_o = normal.dot(outgoing);
+  let o_dot_h = outgoing.dot(half);
+  if cos_o <= 0.0 || o_dot_h <= 0.0 {
+    return 0.0;
+  }
+  return smith_g1(cos_o, alpha.max(0.001)) * o_dot_h * ggx_distribution(normal, half, alpha) / cos_o;
+}
+
+/// Picks an incoming direction for the uniform sample `u` by sampling a
+/// microfacet normal in proportion to its projected density, and reflecting
+/// `outgoing` in it. Returns None if the reflection ends up below the surface.
+pub fn sample_ggx(normal: Vector, outgoing: Vector, alpha: f32, u: (f32, f32)) -> Option<Vector> {
+  let half = sample_ggx_normal(normal, alpha, u);
+  let o_dot_h = outgoing.dot(half);
+  if o_dot_h <= 0.0 {
+    return None;
+  }
+  let incoming = (half * (2.0 * o_dot_h) - outgoing).normalize();
+  if incoming.dot(normal) <= 0.0 {
+    return None;
+  }
+  return Some(incoming);
+}
+
+/// The solid angle density with which sample_ggx picks `incoming`.
+pub fn ggx_pdf(normal: Vector, outgoing: Vector, incoming: Vector, alpha: f32) -> f32 {
+  if normal.dot(outgoing) <= 0.0 || normal.dot(incoming) <= 0.0 {
+    return 0.0;
+  }
+  let half = (outgoing + incoming).normalize();
+  let o_dot_h = outgoing.dot(half);
+  if o_dot_h <= 0.0 {
+    return 0.0;
+  }
+  return ggx_normal_pdf(normal, half, alpha) / (4.0 * o_dot_h);
+}
+
+#[test]
+fn test_ggx_sampling() {
+  let normal = Vector::vector(0.0, 0.0, 1.0);
+  let outgoing = Vector::vector(0.6, 0.0, 0.8);
+  let alpha = 0.3;
+  // Weighting samples by reflectance * cos / pdf estimates the albedo of the
+  // lobe, which can't be more than one, and shouldn't lose much at this
+  // roughness.
+  let count = 20000;
+  let mut albedo = 0.0;
+  for _ in 0..count {
+    if let Some(incoming) = sample_ggx(normal, outgoing, alpha, random_pair()) {
+      let pdf = ggx_pdf(normal, outgoing, incoming, alpha);
+      assert!(pdf > 0.0);
+      albedo += ggx_reflectance(normal, outgoing, incoming, alpha) * incoming.dot(normal) / pdf;
+    }
+  }
+  albedo /= count as f32;
+  assert!(albedo > 0.8 && albedo <= 1.0, "albedo {}", albedo);
+  // The lobe is reciprocal.
+  let incoming = Vector::vector(-0.3, 0.2, 0.9).normalize();
+  let forward = ggx_reflectance(normal, outgoing, incoming, alpha);
+  let backward = ggx_reflectance(normal, incoming, outgoing, alpha);
+  assert!((forward - backward).abs() < 1e-5 * forward);
+  assert_eq!(roughness_from_exponent(0.0), 1.0);
+}
@@ -0,0 +1,269 @@
+use crate::colour::Colour;
+use crate::fragment::Fragment;
+use crate::light::Emitter;
+use crate::light_tree::LightTree;
+use crate::material::{cross_media_boundary, scattered_context};
+use crate::material::MaterialCollisionInfo;
+use crate::photon_map::random;
+use crate::ray::Ray;
+use crate::ray::RayContext;
+use crate::render_configuration::LightingIntegrator;
+use crate::render_configuration::SampleLighting;
+use crate::sampling::random_pair;
+use crate::scene::Scene;
+use crate::vectors::Vector;
+use std::sync::Arc;
+
+// Paths are never terminated by russian roulette before this many bounces.
+const MIN_ROULETTE_DEPTH: usize = 3;
+
+/// Monte Carlo path tracing with next event estimation. This is slow, but
+/// does not make the approximations of the photon map, so it serves as the
+/// reference that the other integrators are compared against.
+pub struct PathTracer {
+  light_tree: LightTree,
+  max_depth: usize,
+  paths_per_sample: usize,
+}
+
+impl PathTracer {
+  pub fn new(scene: &Arc<Scene>, max_depth: usize, paths_per_sample: usize) -> Self {
+    return PathTracer {
+      light_tree: LightTree::for_scene(scene),
+      max_depth,
+      paths_per_sample: paths_per_sample.max(1),
+    };
+  }
+
+  // Next event estimation from a light picked by the light tree, scattered
+  // towards `wo` by the surface. Emissive surfaces are sampled at a fresh
+  // point, which is uniform over their area, so the pdf with respect to
+  // solid angle is the probability of picking the light times
+  // distance^2 / (area * cosine). Point and directional lights are only
+  // picked, and the environment map picks a direction itself, so its pdf is
+  // with respect to solid angle. Shadow rays start in the medium of
+  // `context`, or the one on the other side of the surface if they pass
+  // through it.
+  fn direct_lighting(
+    &self,
+    scene: &Scene,
+    context: &RayContext,
+    fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+    wo: Vector,
+  ) -> Vector {
+    if !surface.bsdf.has_non_delta() {
+      return Vector::new();
+    }
+    // Lights behind the surface can't reach it, unless it lets light through.
+    let tree_normal = if surface.bsdf.transmits() {
+      None
+    } else {
+      Some(surface.normal)
+    };
+    let (index, probability) = match self.light_tree.sample(surface.position, tree_normal) {
+      Some(sample) => sample,
+      None => return Vector::new(),
+    };
+    let shadow_ray = |direction: Vector, max: f32| {
+      let context = scattered_context(context, fragment, surface, direction);
+      return Ray::new_bound(surface.position, direction, 0.005, max, Some(context));
+    };
+    let (light, _) = scene.light(index);
+    let light = match light.get_samples(1, scene).pop() {
+      Some(light) => light,
+      None => return Vector::new(),
+    };
+    if light.emitter == Emitter::Environment {
+      let (direction, radiance, pdf) = match scene.environment().and_then(|environment| environment.sample()) {
+        Some(sample) => sample,
+        None => return Vector::new(),
+      };
+      let reflectance = Vector::from(surface.bsdf.eval(wo, direction));
+      if reflectance.length() <= 0.0 {
+        return Vector::new();
+      }
+      let surface_cosine = direction.dot(surface.bsdf.normal()).abs();
+      let transmittance = Vector::from(scene.transmittance(&shadow_ray(direction, std::f32::INFINITY)));
+      return context.upsample(radiance) * reflectance * transmittance * (surface_cosine / (probability * pdf));
+    }
+    let (ldir, distance) = light.direction_from(surface.position);
+    let reflectance = Vector::from(surface.bsdf.eval(wo, ldir));
+    if reflectance.length() <= 0.0 {
+      return Vector::new();
+    }
+    let surface_cosine = ldir.dot(surface.bsdf.normal()).abs();
+    let transmittance = scene.transmittance(&shadow_ray(ldir, distance - 0.001));
+    if transmittance.max_value() <= 0.0 {
+      return Vector::new();
+    }
+    let reflectance = reflectance * Vector::from(transmittance);
+    if light.is_delta() {
+      let irradiance = context.upsample_emission(light.delta_irradiance(ldir, distance), light.emission.blackbody);
+      return irradiance * reflectance * (surface_cosine / probability);
+    }
+    let light_cosine = match light.direction {
+      Some(direction) => direction.dot(ldir).abs(),
+      None => 1.0,
+    };
+    let geometry = surface_cosine * light_cosine / (distance * distance);
+    return context.upsample_emission(light.radiance, light.emission.blackbody)
+      * reflectance
+      * (light.area * geometry / probability);
+  }
+
+  // Estimates the radiance scattered towards the viewer of the fragment,
+  // from next event estimation and a single sample of the surface's Bsdf.
+  // Emitters reached through delta lobes can't have been sampled directly,
+  // so those are the only ones the sampled ray counts. Scene::intersect_ray
+  // already follows the delta lobes of the surfaces it sees, so
+  // `follow_delta` is only set further along the path.
+  fn scattered_lighting(
+    &self,
+    scene: &Scene,
+    context: &RayContext,
+    fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+    depth: usize,
+    follow_delta: bool,
+  ) -> Vector {
+    let wo = -fragment.view.normalize();
+    let direct = self.direct_lighting(scene, context, fragment, surface, wo);
+    let sample = match surface.bsdf.sample(wo, random_pair()) {
+      Some(sample) if follow_delta || !sample.delta => sample,
+      _ => return direct,
+    };
+    let mut continue_probability = 1.0;
+    if depth >= MIN_ROULETTE_DEPTH {
+      continue_probability = sample.weight.max_value().min(0.95);
+      if random(0.0, 1.0) as f32 >= continue_probability {
+        return direct;
+      }
+    }
+    // Triangles accept hits slightly behind the minimum distance, so offsetting
+    // the origin alone is not enough to avoid hitting the surface again.
+    let context = scattered_context(context, fragment, surface, sample.direction);
+    let ray = Ray::new_bound(
+      surface.position,
+      sample.direction,
+      0.005,
+      std::f32::INFINITY,
+      Some(context),
+    );
+    let indirect = self.radiance(scene, &ray, depth + 1, sample.delta);
+    return direct + indirect * Vector::from(sample.weight) / continue_probability;
+  }
+
+  // The radiance arriving along the ray. Emitters and the environment reached
+  // through a diffuse bounce have already been counted by next event
+  // estimation, so their emission only counts when `count_emission` is set.
+  fn radiance(&self, scene: &Scene, ray: &Ray, depth: usize, count_emission: bool) -> Vector {
+    if depth > self.max_depth {
+      return Vector::new();
+    }
+    let (collision, shadable) = match scene.intersect(ray) {
+      None if count_emission => return ray.ray_context.upsample(scene.background(ray.direction)),
+      None => return Vector::new(),
+      Some(hit) => hit,
+    };
+    let attenuation = Vector::from(scene.attenuation(ray, collision.distance));
+    let fragment = shadable.compute_fragment(scene, ray, &collision);
+    let surface = scene
+      .get_material(fragment.material)
+      .compute_surface_properties(scene, ray, &fragment)
+      .upsampled(&ray.ray_context);
+    // Emitters may reflect light as well, so paths carry on from them.
+    let emission = match surface.emitted_colour() {
+      Some(emission) if count_emission => Vector::from(emission),
+      _ => Vector::new(),
+    };
+    // Next event estimation sees through the boundaries of media, so rays
+    // crossing them carry on as they were.
+    if let Some(continued_ray) = cross_media_boundary(ray, &fragment, &surface) {
+      return self.radiance(scene, &continued_ray, depth, count_emission) * attenuation;
+    }
+
+    let scattered = self.scattered_lighting(scene, &ray.ray_context, &fragment, &surface, depth, true);
+    return (emission + scattered) * attenuation;
+  }
+}
+
+impl LightingIntegrator for PathTracer {
+  fn lighting(&self, scene: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> SampleLighting {
+    let mut reflected = Vector::new();
+    for _ in 0..self.paths_per_sample {
+      let lighting = self.scattered_lighting(scene, &fragment.ray_context, fragment, surface, 1, false);
+      reflected = reflected + lighting;
+    }
+    return SampleLighting {
+      diffuse: Colour::new(),
+      ambient: Colour::new(),
+      reflected: Colour::from(reflected / self.paths_per_sample as f32),
+    };
+  }
+}
+
+#[test]
+fn test_glowing_furnace() {
+  use crate::bsdf::{Bsdf, Lobe};
+  use crate::material::{EmissionCoefficients, Material};
+  use crate::scene::SceneSettings;
+  use crate::sphere::Sphere;
+
+  // A surface that both emits and reflects, so that inside a closed sphere
+  // of it the radiance everywhere is emission / (1 - albedo).
+  #[derive(Debug)]
+  struct GlowingDiffuse;
+  impl Material for GlowingDiffuse {
+    fn is_light(&self) -> bool {
+      return true;
+    }
+    fn compute_surface_properties(&self, _: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+      let albedo = Colour::RGB(0.5, 0.5, 0.5);
+      return MaterialCollisionInfo {
+        ambient_colour: albedo,
+        diffuse_colour: albedo,
+        specular_colour: albedo,
+        emissive_colour: Some(EmissionCoefficients {
+          ambient: 0.0,
+          diffuse: 0.0,
+          specular: 0.0,
+          blackbody: None,
+          colour: Some(Colour::RGB(1.0, 1.0, 1.0)),
+        }),
+        position: f.position,
+        normal: f.normal,
+        interior: None,
+        bsdf: Bsdf::new(f.normal, f.true_normal).with_lobe(Lobe::Diffuse(albedo)),
+      };
+    }
+  }
+
+  let mut settings = SceneSettings::new();
+  settings.scene_file = std::env::temp_dir().to_string_lossy().to_string();
+  let mut scene = Scene::new(&settings);
+  let (material, _) = scene.get_or_create_material("glowing", |_| Some(Box::new(GlowingDiffuse)));
+  scene.add_object(Box::new(Sphere::new(Vector::point(0.0, 0.0, 0.0), 1.0, material)));
+  scene.finalize();
+  let scene = Arc::new(scene);
+  let tracer = PathTracer::new(&scene, 30, 1);
+
+  let ray = Ray::new(
+    Vector::point(0.0, 0.0, 0.0),
+    Vector::vector(0.3, 0.5, -0.8).normalize(),
+    None,
+  );
+  let (collision, shadable) = scene.intersect(&ray).unwrap();
+  let fragment = shadable.compute_fragment(&scene, &ray, &collision);
+  let surface = scene
+    .get_material(fragment.material)
+    .compute_surface_properties(&scene, &ray, &fragment);
+  // The radiance arriving is 2, half of which is reflected.
+  let count = 20000;
+  let mut reflected = 0.0;
+  for _ in 0..count {
+    reflected += tracer.lighting(&scene, &fragment, &surface).reflected.r();
+  }
+  let reflected = reflected / count as f32;
+  assert!((reflected - 1.0).abs() < 0.03, "reflected {}", reflected);
+}
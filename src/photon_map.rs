@@ -1,10 +1,11 @@
-use crate::material::compute_secondaries;
+use crate::material::{scattered_context, scattered_ray};
 use crate::direct_lighting::IndirectLightingSource;
 use crate::render_configuration::SampleLighting;
 use crate::render_configuration::LightingIntegrator;
 use std::sync::Arc;
 use std::fmt::Debug;
 use std::time::Instant;
+use std::f32::consts::PI;
 use crate::kdtree::HasPosition;
 use crate::bounding_box::BoundingBox;
 use crate::bounding_box::HasBoundingBox;
@@ -14,8 +15,10 @@ use crate::kdtree::KDTree;
 use crate::material::MaterialCollisionInfo;
 use rand::{thread_rng, Rng};
 use crate::ray::Ray;
+use crate::sampling::{cosine_weighted_hemisphere, random_pair, tangent_frame, uniform_cone, uniform_sphere};
 use crate::scene::Scene;
-use crate::light::LightSample;
+use crate::spectrum::{Blackbody, Wavelengths};
+use crate::light::{Emitter, LightSample};
 use crate::vectors::{Point, Vector};
 use crate::dispatch_queue::DispatchQueue;
 
@@ -33,6 +36,13 @@ pub struct Photon {
   position: Point,
 }
 
+impl Photon {
+  /// The power carried by the photon, or None for shadow photons.
+  pub(crate) fn colour(&self) -> Option<Colour> {
+    return self.data.map(|data| data.colour);
+  }
+}
+
 impl HasBoundingBox for Photon {
   fn bounds(&self) -> BoundingBox {
     return BoundingBox::new_from_point(self.position);
@@ -69,12 +79,10 @@ impl RecordMode {
 }
 
 pub trait PhotonSelector: Debug + Clone + Sync + Send {
-  fn record_mode(
-    &self,
-    surface: &MaterialCollisionInfo,
-    secondaries: &[(Ray, Colour, f32)],
-    depth: usize,
-  ) -> RecordMode;
+  // `diffuse_bounces` is the number of bounces off non-delta lobes the photon
+  // took before reaching the surface, so a photon with none has only been
+  // reflected or refracted specularly since leaving the light.
+  fn record_mode(&self, surface: &MaterialCollisionInfo, depth: usize, diffuse_bounces: usize) -> RecordMode;
   fn weight_for_sample(
     &self,
     surface: &MaterialCollisionInfo,
@@ -90,46 +98,76 @@ pub struct PhotonMap<Selector: PhotonSelector + 'static> {
   tree: KDTree<Photon>,
   selector: Arc<Selector>,
   max_photon_samples: usize,
+  final_gather_rays: usize,
 }
 
 pub fn random(min: f64, max: f64) -> f64 {
   thread_rng().gen_range(min, max)
 }
 
-fn random_in_hemisphere(normal: Vector) -> Vector {
-  loop {
-    let x = random(-1.0, 1.0);
-    let y = random(-1.0, 1.0);
-    let z = random(-1.0, 1.0);
-    if (x * x + y * y + z * z) > 1.0 {
-      continue;
-    }
-    let result = Vector::vector(x, y, z);
-    if result.dot(normal) > 0.1 {
-      return result;
-    }
-  }
+// The point a photon travelling in `direction` from a light infinitely far
+// away starts from, on the disc described by the light's sample.
+pub(crate) fn distant_origin(sample: &LightSample, direction: Vector) -> Point {
+  let radius = (sample.area / PI).sqrt();
+  let (tangent, bitangent) = tangent_frame(direction);
+  let r = radius * (random(0.0, 1.0) as f32).sqrt();
+  let phi = 2.0 * PI * random(0.0, 1.0) as f32;
+  return sample.position - direction * radius + tangent * (r * phi.cos()) + bitangent * (r * phi.sin());
 }
-fn make_photon(sample: &LightSample) -> (Ray, Colour) {
-  loop {
-    let light_dir = {
-      let u = random(0.0, 1.0);
-      let v = 2.0 * 3.14127 * random(0.0, 1.0);
-      Vector::vector(v.cos() * u.sqrt(), -(1.0 - u).sqrt(), v.sin() * u.sqrt())
-    };
 
-    // if sample.direction.is_some() && light_dir.dot(sample.direction.unwrap()) < 0.0 {
-    //   light_dir = -light_dir;
-    // }
-
-    return (
-      Ray::new(sample.position + light_dir * 0.01, light_dir, None),
-      Colour::from(
-        sample.emission.diffuse * sample.diffuse
-          + sample.emission.ambient * sample.ambient
-          + sample.emission.specular * sample.specular,
-      ),
-    );
+// Picks the ray a photon leaves the light along, and the power it carries,
+// on the scale of LightSample::photon_power.
+fn make_photon(scene: &Scene, sample: &LightSample) -> (Ray, Vector) {
+  match sample.emitter {
+    Emitter::Area => {
+      // Area lights emit diffusely, so photons leave cosine weighted around
+      // the normal of the sample.
+      let light_dir = match sample.direction {
+        Some(normal) => cosine_weighted_hemisphere(normal),
+        None => uniform_sphere(),
+      };
+      return (
+        Ray::new(sample.position + light_dir * 0.01, light_dir, None),
+        sample.photon_power(),
+      );
+    }
+    Emitter::Point { spot: None } => {
+      return (Ray::new(sample.position, uniform_sphere(), None), sample.photon_power());
+    }
+    Emitter::Point {
+      spot: Some((_, cos_outer)),
+    } => {
+      let direction = uniform_cone(sample.direction.unwrap(), cos_outer);
+      return (
+        Ray::new(sample.position, direction, None),
+        sample.photon_power() * sample.spot_falloff(direction),
+      );
+    }
+    Emitter::Directional => {
+      let direction = sample.direction.unwrap();
+      return (
+        Ray::new(distant_origin(sample, direction), direction, None),
+        sample.photon_power(),
+      );
+    }
+    Emitter::Environment => {
+      // Each direction is treated as a directional light, with the radiance
+      // arriving from it over the pdf of picking it.
+      let (towards, radiance, pdf) = match scene.environment().and_then(|environment| environment.sample()) {
+        Some(sample) => sample,
+        None => {
+          return (
+            Ray::new(sample.position, Vector::vector(0.0, -1.0, 0.0), None),
+            Vector::new(),
+          )
+        }
+      };
+      let direction = towards * -1.0;
+      return (
+        Ray::new(distant_origin(sample, direction), direction, None),
+        radiance * (sample.area / (PI * pdf)),
+      );
+    }
   }
 }
 
@@ -138,14 +176,25 @@ fn bounce_photon<Selector: PhotonSelector + 'static>(
   scene: &Arc<Scene>,
   initial_ray: &Ray,
   initial_colour: Colour,
+  blackbody: Option<Blackbody>,
 ) -> Vec<Photon> {
-  let mut throughput = Colour::RGB(1.0, 1.0, 1.0);
   let mut path_length: usize = 0;
-  let mut recorded = false;
+  let mut diffuse_bounces: usize = 0;
   let mut max_bounces = 0;
   let mut photons = vec![];
   let mut photon_colour = initial_colour;
   let mut photon_ray = initial_ray.clone();
+  // In spectral mode, each photon carries its own wavelengths, and the
+  // photons it leaves are converted back to RGB.
+  let wavelengths = if scene.is_spectral() {
+    Some(Wavelengths::sample(random(0.0, 1.0) as f32))
+  } else {
+    None
+  };
+  if let Some(wavelengths) = wavelengths {
+    photon_ray.ray_context.wavelengths = Some(wavelengths);
+    photon_colour = photon_ray.ray_context.upsample_emission(photon_colour, blackbody);
+  }
   if selector.record_shadow_rays() {
     let mut shadow_depth = 0;
     let mut shadow_ray = Ray::new(
@@ -167,8 +216,6 @@ fn bounce_photon<Selector: PhotonSelector + 'static>(
   }
   // println!("Photon colour {:?}", photon_colour);
   while path_length < 32 {
-    let current_colour = photon_colour;
-
     path_length += 1;
     max_bounces = max_bounces.max(path_length);
     let (c, shadable) = match scene.intersect(&photon_ray) {
@@ -177,101 +224,57 @@ fn bounce_photon<Selector: PhotonSelector + 'static>(
       }
       Some(x) => x,
     };
+    photon_colour = photon_colour * scene.attenuation(&photon_ray, c.distance);
+    let current_colour = match wavelengths {
+      Some(wavelengths) => wavelengths.to_rgb(photon_colour),
+      None => photon_colour,
+    };
 
-    let fragment = shadable.unwrap_left().compute_fragment(scene, &photon_ray, &c);
+    let fragment = shadable.compute_fragment(scene, &photon_ray, &c);
     let material = scene.get_material(fragment.material);
 
-    let surface = material.compute_surface_properties(scene, &photon_ray, &fragment);
-    let mut remaining_weight = 1.0;
-    let secondaries = compute_secondaries(&photon_ray, &fragment, &surface);
-    let mut next = {
-      let mut selection = random(0.0, 1.0) as f32;
-      let mut result: Option<(Ray, Colour)> = None;
-      for (_, _, secondary_weight) in &secondaries {
-        remaining_weight -= secondary_weight;
-      }
-      remaining_weight = remaining_weight.max(0.0);
-      for (secondary_ray, secondary_colour, secondary_weight) in &secondaries {
-        if selection > *secondary_weight {
-          selection -= secondary_weight;
-          continue;
-        }
-        let next_colour = photon_colour * *secondary_colour;
-        let next_power = next_colour.max_value() / photon_colour.max_value();
-        result = Some((secondary_ray.clone(), next_colour * next_power))
-      }
-      result
-    };
-
-    if next.is_none() {
-      let prob_diffuse = surface.diffuse_colour.max_value(); // (surface.diffuse_colour * photon_colour).max_value() / photon_colour.max_value();
-      let prob_specular = (surface.specular_colour * photon_colour).max_value() / photon_colour.max_value();
-      let p = random(0.0, 1.0) as f32;
-      let (new_direction, new_colour) = if p * remaining_weight < prob_diffuse {
-        (
-          random_in_hemisphere(surface.normal),
-          surface.diffuse_colour * photon_colour * (1.0 / prob_diffuse),
-        )
-      } else if p * remaining_weight < (prob_diffuse + prob_specular) {
-        (
-          fragment.view.reflect(surface.normal),
-          surface.specular_colour * photon_colour * (1.0 / prob_specular),
-        )
-      } else {
+    let surface = material
+      .compute_surface_properties(scene, &photon_ray, &fragment)
+      .upsampled(&photon_ray.ray_context);
+    // Photons aren't stored in media, which scatter them through
+    // Lobe::Scattering.
+    if !shadable.is_media() {
+      let path_mode = selector.record_mode(&surface, path_length, diffuse_bounces);
+      if path_mode.should_record() {
         photons.push(Photon {
           data: Some(PhotonData {
             colour: current_colour,
-            in_direction: photon_ray.direction,
             surface_normal: surface.normal,
+            in_direction: photon_ray.direction,
             is_direct: path_length == 1,
           }),
           position: fragment.position,
         });
+      }
+      if path_mode.should_terminate() {
         break;
-      };
-
-      next = Some((
-        Ray::new(
-          fragment.position + new_direction * 0.001,
-          new_direction,
-          Some(photon_ray.ray_context.clone()),
-        ),
-        new_colour,
-      ));
-    };
-    let (next_ray, mut next_colour) = next.unwrap();
-    let path_mode = selector.record_mode(&surface, &secondaries, path_length);
-    let recorded_photon = if path_mode.should_record() {
-      if !recorded {
-        recorded = true;
       }
+    }
 
-      photons.push(Photon {
-        data: Some(PhotonData {
-          colour: current_colour,
-          surface_normal: surface.normal,
-          in_direction: photon_ray.direction,
-          is_direct: path_length == 1,
-        }),
-        position: fragment.position,
-      });
-      true
-    } else {
-      false
+    let outgoing = -fragment.view.normalize();
+    let sample = match surface.bsdf.sample(outgoing, random_pair()) {
+      Some(sample) => sample,
+      None => break,
     };
-    if path_mode.should_terminate() {
-      break;
+    if !sample.delta {
+      diffuse_bounces += 1;
     }
-    if !recorded_photon {
-      // Now we know the colour and direction of the next bounce, let's decide if we're keeping it.
-      throughput = throughput * next_colour;
-      let p = random(0.0, 1.0) as f32;
-      if p > throughput.max_value() {
-        break;
-      }
-      throughput = throughput * (1.0 / p);
-      next_colour = next_colour;
+    // Russian roulette keeps the power of the photon, so that scattering only
+    // changes its colour.
+    let mut next_colour = photon_colour * sample.weight;
+    let survival = (next_colour.max_value() / photon_colour.max_value()).min(1.0);
+    // Bounces that absorb everything, such as the refraction of a totally
+    // internally reflected photon, would otherwise turn the power into NaN.
+    if !(survival > 0.0) || random(0.0, 1.0) as f32 >= survival {
+      break;
     }
+    next_colour = next_colour * (1.0 / survival);
+    let next_ray = scattered_ray(&photon_ray, &fragment, &surface, sample.direction);
     photon_colour = next_colour;
     photon_ray = next_ray;
   }
@@ -281,7 +284,7 @@ fn bounce_photon<Selector: PhotonSelector + 'static>(
 fn bounce_photons<Selector: PhotonSelector + 'static>(
   selector: &Arc<Selector>,
   scene: &Arc<Scene>,
-  initial_photons: &[(Ray, Colour)],
+  initial_photons: &[(Ray, Colour, Option<Blackbody>)],
 ) -> Vec<Photon> {
   let mut photons = vec![];
   let mut queue = DispatchQueue::default();
@@ -293,8 +296,8 @@ fn bounce_photons<Selector: PhotonSelector + 'static>(
   let scene = scene.clone();
   let selector = selector.clone();
   queue
-    .consume_tasks(&move |(photon_ray, photon_colour)| {
-      return bounce_photon(&selector, &scene, photon_ray, *photon_colour);
+    .consume_tasks(&move |(photon_ray, photon_colour, blackbody)| {
+      return bounce_photon(&selector, &scene, photon_ray, *photon_colour, *blackbody);
     })
     .iter()
     .for_each(|photon_paths| {
@@ -334,6 +337,52 @@ impl Drop for Timing {
   }
 }
 
+/// Emits roughly `target_photon_count` photons from the lights and bounces
+/// them through the scene, returning the photons the selector recorded with
+/// their power normalised by the number emitted.
+pub(crate) fn trace_photons<Selector: PhotonSelector + 'static>(
+  selector: &Arc<Selector>,
+  scene: &Arc<Scene>,
+  lights: &[LightSample],
+  target_photon_count: usize,
+) -> Vec<Photon> {
+  assert!(!lights.is_empty());
+  let initial_photons = Timing::time("Generating initial rays", || {
+    let mut initial_photons = vec![];
+    let total_power = lights.iter().fold(0.0, |a, b| a + b.output());
+    let photon_counts: Vec<usize> = lights
+      .iter()
+      .map(|light| ((light.output() / total_power * target_photon_count as f32).ceil() as usize).max(1))
+      .collect();
+    let total_count: usize = photon_counts.iter().sum();
+    for (light, photon_count) in lights.iter().zip(photon_counts) {
+      // A light's photons share its power. They're scaled up by the total
+      // count here, as every photon is divided by it once they're traced.
+      let scale = total_count as f32 / photon_count as f32;
+      for _ in 0..photon_count {
+        let (ray, power) = make_photon(scene, light);
+        initial_photons.push((ray, Colour::from(power * scale), light.emission.blackbody));
+      }
+    }
+    return initial_photons;
+  });
+
+  let initial_photon_count = initial_photons.len();
+  assert_eq!(initial_photon_count, initial_photons.len());
+  let mut photons = Timing::time("Bouncing photons", || {
+    return bounce_photons(selector, scene, &initial_photons);
+  });
+  {
+    let _t = Timing::new("Normalising photon power");
+    for i in 0..photons.len() {
+      if let Some(ref mut photon_data) = photons[i].data {
+        photon_data.colour = photon_data.colour * (1.0 / initial_photon_count as f32);
+      }
+    }
+  }
+  return photons;
+}
+
 impl<Selector: PhotonSelector + 'static> PhotonMap<Selector> {
   pub fn new(
     selector: &Arc<Selector>,
@@ -343,36 +392,10 @@ impl<Selector: PhotonSelector + 'static> PhotonMap<Selector> {
     max_elements_per_leaf: usize,
     max_photon_samples: usize,
   ) -> Option<PhotonMap<Selector>> {
-    assert!(!lights.is_empty());
-    let initial_photons = Timing::time("Generating initial rays", || {
-      let mut initial_photons = vec![];
-      let total_power = lights.iter().fold(0.0, |a, b| a + b.output());
-      for light in lights {
-        let power = light.output();
-        let photon_count = (power / total_power * target_photon_count as f32).ceil() as usize;
-        for _ in 0..photon_count.max(1) {
-          initial_photons.push(make_photon(&light));
-        }
-      }
-      return initial_photons;
-    });
-
-    let initial_photon_count = initial_photons.len();
-    assert_eq!(initial_photon_count, initial_photons.len());
-    let mut photons = Timing::time("Bouncing photons", || {
-      return bounce_photons(selector, scene, &initial_photons);
-    });
+    let mut photons = trace_photons(selector, scene, lights, target_photon_count);
     if photons.is_empty() {
       return None;
     }
-    {
-      let _t = Timing::new("Normalising photon power");
-      for i in 0..photons.len() {
-        if let Some(ref mut photon_data) = photons[i].data {
-          photon_data.colour = photon_data.colour * (1.0 / initial_photon_count as f32);
-        }
-      }
-    }
     let tree = Timing::time("Creating KDTree", || {
       return KDTree::new(&mut photons, max_elements_per_leaf);
     });
@@ -381,9 +404,62 @@ impl<Selector: PhotonSelector + 'static> PhotonMap<Selector> {
       tree,
       selector: selector.clone(),
       max_photon_samples,
+      final_gather_rays: 0,
     });
   }
 
+  /// Rather than looking the photon map up at the shading point, traces
+  /// `rays` cosine weighted rays from it and looks the map up where they
+  /// land. This hides the blotches of a direct lookup, but the map must then
+  /// include the first bounce, as it provides the direct lighting of the
+  /// surfaces the rays reach.
+  pub fn with_final_gather(mut self, rays: usize) -> Self {
+    self.final_gather_rays = rays;
+    return self;
+  }
+
+  // Averages the radiance that the diffuse surfaces reached by final gather
+  // rays reflect towards the shading point. Emitters that the rays reach are
+  // only counted when `include_emission` is set, as otherwise they are left
+  // to direct lighting. The rays start in the medium of the side of the
+  // surface they leave from, as shadow rays do.
+  fn final_gather(
+    &self,
+    scene: &Scene,
+    fragment: &Fragment,
+    surface: &MaterialCollisionInfo,
+    include_emission: bool,
+  ) -> Colour {
+    let mut result = Vector::new();
+    for _ in 0..self.final_gather_rays {
+      let direction = cosine_weighted_hemisphere(surface.normal);
+      let context = scattered_context(&fragment.ray_context, fragment, surface, direction);
+      let ray = Ray::new_bound(surface.position, direction, 0.005, std::f32::INFINITY, Some(context));
+      let (collision, shadable) = match scene.intersect(&ray) {
+        None => continue,
+        Some(hit) => hit,
+      };
+      let fragment = shadable.compute_fragment(scene, &ray, &collision);
+      let hit = scene
+        .get_material(fragment.material)
+        .compute_surface_properties(scene, &ray, &fragment);
+      if let Some(emission) = hit.emitted_colour() {
+        if include_emission {
+          result = result + Vector::from(emission);
+        }
+        continue;
+      }
+      let diffuse_colour = hit.bsdf.diffuse_colour();
+      if diffuse_colour.max_value() <= 0.0 {
+        continue;
+      }
+      if let (Some(lighting), _) = self.lighting(&fragment, &hit, self.max_photon_samples) {
+        result = result + Vector::from(diffuse_colour * lighting);
+      }
+    }
+    return Colour::from(result / self.final_gather_rays as f32);
+  }
+
   fn lighting(
     &self,
     _fragment: &Fragment,
@@ -433,13 +509,25 @@ impl<Selector: PhotonSelector + 'static> PhotonMap<Selector> {
 }
 
 impl<Selector: PhotonSelector + 'static> LightingIntegrator for PhotonMap<Selector> {
-  fn lighting(&self, _: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> SampleLighting {
-    let (photons, _) = self.lighting(fragment, surface, self.max_photon_samples);
-    let result_colour = photons.unwrap_or(Colour::new());
+  fn lighting(&self, scene: &Scene, fragment: &Fragment, surface: &MaterialCollisionInfo) -> SampleLighting {
+    let estimate = |surface: &MaterialCollisionInfo| {
+      if self.final_gather_rays > 0 {
+        return self.final_gather(scene, fragment, surface, true);
+      }
+      let (photons, _) = self.lighting(fragment, surface, self.max_photon_samples);
+      return photons.unwrap_or(Colour::new());
+    };
+    // Surfaces that scatter to both sides are lit from both.
+    let result_colour = match surface.back_face() {
+      Some(back) => (estimate(surface) + estimate(&back)) * 0.5,
+      None => estimate(surface),
+    };
+    // Photons are stored in RGB.
+    let result_colour = fragment.ray_context.upsample(result_colour);
     return SampleLighting {
       ambient: result_colour,
       diffuse: result_colour,
-      specular: Colour::new(),
+      reflected: Colour::new(),
     };
   }
 }
@@ -447,29 +535,34 @@ impl<Selector: PhotonSelector + 'static> LightingIntegrator for PhotonMap<Select
 #[derive(Debug, Clone)]
 pub struct DiffuseSelector {
   include_first_bounce: bool,
+  exclude_caustics: bool,
 }
 
 impl DiffuseSelector {
   pub fn new(include_first_bounce: bool) -> DiffuseSelector {
-    DiffuseSelector { include_first_bounce }
+    DiffuseSelector {
+      include_first_bounce,
+      exclude_caustics: false,
+    }
   }
-}
 
-fn is_specular(secondaries: &[(Ray, Colour, f32)]) -> bool {
-  let mut secondary_weight = 0.0;
-  for secondary in secondaries {
-    secondary_weight += secondary.2;
-  }
-  if (random(0.0, 1.0) as f32) < secondary_weight {
-    return true;
+  /// Skips the photons that a CausticSelector would record, for use when a
+  /// separate caustic map provides them.
+  pub fn excluding_caustics(mut self) -> DiffuseSelector {
+    self.exclude_caustics = true;
+    return self;
   }
-  return false;
+}
+
+// Whether any light reaching the surface is reflected diffusely.
+fn has_diffuse_component(surface: &MaterialCollisionInfo) -> bool {
+  return surface.bsdf.diffuse_colour().max_value() > 0.0;
 }
 
 impl PhotonSelector for DiffuseSelector {
-  fn record_mode(&self, _: &MaterialCollisionInfo, secondaries: &[(Ray, Colour, f32)], depth: usize) -> RecordMode {
-    if depth == 1 && is_specular(secondaries) && false {
-      return RecordMode::TerminatePath;
+  fn record_mode(&self, _: &MaterialCollisionInfo, depth: usize, diffuse_bounces: usize) -> RecordMode {
+    if self.exclude_caustics && depth > 1 && diffuse_bounces == 0 {
+      return RecordMode::DontRecord;
     }
 
     if depth > 1 || self.include_first_bounce {
@@ -503,20 +596,22 @@ impl PhotonSelector for DiffuseSelector {
 pub struct CausticSelector {}
 
 impl CausticSelector {
-  #[allow(dead_code)]
   pub fn new() -> CausticSelector {
     CausticSelector {}
   }
 }
 
 impl PhotonSelector for CausticSelector {
-  fn record_mode(&self, _: &MaterialCollisionInfo, secondaries: &[(Ray, Colour, f32)], depth: usize) -> RecordMode {
-    if depth == 1 {
-      if is_specular(secondaries) {
-        return RecordMode::DontRecord;
-      }
+  // Caustic photons leave the light through specular bounces alone, and are
+  // recorded on every diffuse surface they reach until their first diffuse
+  // bounce. Light that reaches a surface directly is left to direct lighting.
+  fn record_mode(&self, surface: &MaterialCollisionInfo, depth: usize, diffuse_bounces: usize) -> RecordMode {
+    if diffuse_bounces > 0 || (depth == 1 && !surface.bsdf.has_delta()) {
       return RecordMode::TerminatePath;
     }
+    if depth == 1 || !has_diffuse_component(surface) {
+      return RecordMode::DontRecord;
+    }
     return RecordMode::Record;
   }
   fn weight_for_sample(
@@ -536,10 +631,15 @@ impl PhotonSelector for CausticSelector {
 impl<Selector: PhotonSelector + 'static> IndirectLightingSource for PhotonMap<Selector> {
   fn lighting_and_shadow(
     &self,
-    _: &Scene,
+    scene: &Scene,
     fragment: &Fragment,
     surface: &MaterialCollisionInfo,
   ) -> (Option<Colour>, Option<bool>) {
+    if self.final_gather_rays > 0 {
+      // The photons near the shading point are never looked at, so they
+      // cannot tell us whether it is in shadow.
+      return (Some(self.final_gather(scene, fragment, surface, false)), None);
+    }
     let (photons, shadows) = self.lighting(fragment, surface, self.max_photon_samples);
     let lighting = if let Some(photon_lighting) = photons {
       Some(photon_lighting)
@@ -549,3 +649,37 @@ impl<Selector: PhotonSelector + 'static> IndirectLightingSource for PhotonMap<Se
     return (lighting, shadows);
   }
 }
+
+#[test]
+fn test_caustic_photons_are_kept_apart() {
+  use crate::bsdf::{Bsdf, Lobe};
+  let normal = Vector::vector(0.0, 1.0, 0.0);
+  let albedo = Colour::RGB(0.5, 0.5, 0.5);
+  let surface = MaterialCollisionInfo {
+    ambient_colour: albedo,
+    diffuse_colour: albedo,
+    specular_colour: albedo,
+    emissive_colour: None,
+    position: Vector::point(0.0, 0.0, 0.0),
+    normal,
+    interior: None,
+    bsdf: Bsdf::new(normal, normal).with_lobe(Lobe::Diffuse(albedo)),
+  };
+  let diffuse = DiffuseSelector::new(false).excluding_caustics();
+  let caustics = CausticSelector::new();
+  // Photons that only bounced specularly before reaching the diffuse surface
+  // are caustics, and are only stored in the caustic map.
+  for &depth in &[2, 3] {
+    assert!(!diffuse.record_mode(&surface, depth, 0).should_record());
+    assert!(caustics.record_mode(&surface, depth, 0).should_record());
+    assert!(DiffuseSelector::new(false)
+      .record_mode(&surface, depth, 0)
+      .should_record());
+  }
+  // Once a photon has bounced diffusely it's only in the diffuse map, and
+  // neither map holds the first bounce, which direct lighting provides.
+  assert!(diffuse.record_mode(&surface, 2, 1).should_record());
+  assert!(!caustics.record_mode(&surface, 2, 1).should_record());
+  assert!(!diffuse.record_mode(&surface, 1, 0).should_record());
+  assert!(!caustics.record_mode(&surface, 1, 0).should_record());
+}
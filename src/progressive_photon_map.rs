@@ -0,0 +1,310 @@
+use crate::camera::{Camera, PerspectiveCamera, RenderBuffer};
+use crate::colour::Colour;
+use crate::dispatch_queue::DispatchQueue;
+use crate::kdtree::{HasPosition, KDTree};
+use crate::material::{scattered_ray, MaterialCollisionInfo};
+use crate::photon_map::{random, trace_photons, Photon, PhotonSelector, Timing};
+use crate::ray::Ray;
+use crate::render_configuration::RenderConfiguration;
+use crate::sampling::random_pair;
+use crate::scene::Scene;
+use crate::spectrum::Wavelengths;
+use crate::vectors::Vector;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// The fraction of the photons gathered by a pass that is kept when a pixel's
+// radius shrinks, as in Hachisuka and Jensen's paper.
+const ALPHA: f32 = 0.7;
+// Camera paths are followed through at most this many specular bounces.
+const MAX_SPECULAR_DEPTH: usize = 10;
+
+// The diffuse surface a pixel's camera path reached in the current pass, and
+// the throughput of the path, including the diffuse colour of the surface.
+// In spectral mode, the weight is at the wavelengths the path carried.
+#[derive(Clone)]
+struct VisiblePoint {
+  surface: MaterialCollisionInfo,
+  weight: Vector,
+  wavelengths: Option<Wavelengths>,
+}
+
+// The statistics a pixel accumulates over every pass. A radius of zero means
+// the pixel has not gathered any photons yet.
+#[derive(Clone)]
+struct PixelStatistics {
+  radius: f32,
+  photon_count: f32,
+  flux: Vector,
+  emitted: Vector,
+  depth: f32,
+}
+
+impl PixelStatistics {
+  // Adds the photons a pass gathered within `radius`. Only a fraction of the
+  // new photons are kept, and the radius and accumulated flux shrink to
+  // match.
+  fn add_photons(&mut self, radius: f32, count: f32, flux: Vector) {
+    if count == 0.0 {
+      self.radius = radius;
+      return;
+    }
+    let photon_count = self.photon_count + ALPHA * count;
+    let ratio = photon_count / (self.photon_count + count);
+    self.radius = radius * ratio.sqrt();
+    self.flux = (self.flux + flux) * ratio;
+    self.photon_count = photon_count;
+  }
+}
+
+/// Stochastic progressive photon mapping. Rather than building a single
+/// photon map of a fixed size, every pass traces new camera paths and a new
+/// set of photons, and each pixel keeps its own gather radius that shrinks as
+/// it collects photons. The image converges as passes are added, without the
+/// total photon count being limited by memory.
+///
+/// All of the lighting comes from photons, so the selector should record the
+/// first bounce. AOVs are not produced, and glossy lobes are ignored.
+pub struct ProgressivePhotonMap<Selector: PhotonSelector + 'static> {
+  camera: PerspectiveCamera,
+  selector: Arc<Selector>,
+  passes: usize,
+  photons_per_pass: usize,
+  // The initial radius of each pixel covers this many photons.
+  initial_photon_samples: usize,
+  max_elements_per_leaf: usize,
+}
+
+impl<Selector: PhotonSelector + 'static> ProgressivePhotonMap<Selector> {
+  pub fn new(
+    camera: PerspectiveCamera,
+    selector: &Arc<Selector>,
+    passes: usize,
+    photons_per_pass: usize,
+    initial_photon_samples: usize,
+    max_elements_per_leaf: usize,
+  ) -> Self {
+    return ProgressivePhotonMap {
+      camera,
+      selector: selector.clone(),
+      passes: passes.max(1),
+      photons_per_pass,
+      initial_photon_samples: initial_photon_samples.max(1),
+      max_elements_per_leaf,
+    };
+  }
+}
+
+// Follows a camera ray through specular bounces, picked by sampling the Bsdf
+// of each surface, until it reaches a diffuse surface.
+// Returns the emission seen along the way, including the environment if the
+// path leaves the scene, the distance to the first hit and the diffuse
+// surface, if one was reached. Photons are never stored in media, so paths
+// that a medium scatters end there.
+fn trace_camera_path(scene: &Scene, mut ray: Ray) -> (Vector, f32, Option<VisiblePoint>) {
+  if !scene.is_spectral() {
+    return follow_camera_path(scene, ray);
+  }
+  let wavelengths = Wavelengths::sample(random(0.0, 1.0) as f32);
+  ray.ray_context.wavelengths = Some(wavelengths);
+  let (emitted, distance, point) = follow_camera_path(scene, ray);
+  return (Vector::from(wavelengths.to_rgb(Colour::from(emitted))), distance, point);
+}
+
+fn follow_camera_path(scene: &Scene, mut ray: Ray) -> (Vector, f32, Option<VisiblePoint>) {
+  let mut throughput = Vector::vector(1.0, 1.0, 1.0);
+  let mut first_distance = std::f32::INFINITY;
+  for depth in 0..MAX_SPECULAR_DEPTH {
+    let (collision, shadable) = match scene.intersect(&ray) {
+      None => {
+        let background = ray.ray_context.upsample(scene.background(ray.direction));
+        return (throughput * background, first_distance, None);
+      }
+      Some(hit) => hit,
+    };
+    throughput = throughput * Vector::from(scene.attenuation(&ray, collision.distance));
+    if depth == 0 {
+      first_distance = collision.distance;
+    }
+    if shadable.is_media() {
+      return (Vector::new(), first_distance, None);
+    }
+    let fragment = shadable.compute_fragment(scene, &ray, &collision);
+    let surface = scene
+      .get_material(fragment.material)
+      .compute_surface_properties(scene, &ray, &fragment)
+      .upsampled(&ray.ray_context);
+    if let Some(emission) = surface.emitted_colour() {
+      return (throughput * Vector::from(emission), first_distance, None);
+    }
+
+    let outgoing = -fragment.view.normalize();
+    let sample = match surface.bsdf.sample(outgoing, random_pair()) {
+      Some(sample) => sample,
+      None => return (Vector::new(), first_distance, None),
+    };
+    if sample.delta {
+      throughput = throughput * Vector::from(sample.weight);
+      ray = scattered_ray(&ray, &fragment, &surface, sample.direction);
+      continue;
+    }
+
+    // The delta lobes were passed over with the probability of picking them,
+    // so the rest is scaled up to make up for it.
+    let scale = 1.0 / (1.0 - surface.bsdf.delta_probability());
+    let weight = throughput * Vector::from(surface.bsdf.diffuse_colour()) * scale;
+    let point = VisiblePoint {
+      surface,
+      weight,
+      wavelengths: ray.ray_context.wavelengths,
+    };
+    return (Vector::new(), first_distance, Some(point));
+  }
+  return (Vector::new(), first_distance, None);
+}
+
+// Finds the photons within `radius` of the visible point, returning the
+// radius used, the number of photons and the power they carry towards the
+// camera. Pixels that have not gathered before start with the radius that
+// covers the nearest `initial_photon_samples` photons.
+fn gather<Selector: PhotonSelector>(
+  selector: &Selector,
+  photons: &KDTree<Photon>,
+  point: &VisiblePoint,
+  radius: f32,
+  initial_photon_samples: usize,
+) -> (f32, f32, Vector) {
+  let position = point.surface.position;
+  let radius = if radius > 0.0 {
+    radius
+  } else {
+    let (nearest, _) = photons.nearest(position, initial_photon_samples, &mut |photon| {
+      return photon
+        .colour()
+        .map(|_| (photon.get_position() - position).length() as f64);
+    });
+    nearest
+      .iter()
+      .fold(0.0f32, |radius, (distance, _)| radius.max(*distance as f32))
+  };
+
+  let mut found = vec![];
+  photons.within(position, radius, &mut |photon, _| {
+    if photon.colour().is_some() {
+      found.push(*photon);
+    }
+  });
+  let mut flux = Vector::new();
+  for photon in &found {
+    if let Some(weight) = selector.weight_for_sample(&point.surface, photon, found.len(), radius as f64) {
+      flux = flux + Vector::from(photon.colour().unwrap()) * weight.max(0.0);
+    }
+  }
+  // Photons are stored in RGB.
+  let flux = match point.wavelengths {
+    Some(wavelengths) => {
+      let flux = Vector::from(wavelengths.upsample(Colour::from(flux)));
+      Vector::from(wavelengths.to_rgb(Colour::from(point.weight * flux)))
+    }
+    None => point.weight * flux,
+  };
+  return (radius, found.len() as f32, flux);
+}
+
+impl<Selector: PhotonSelector + 'static> Camera for ProgressivePhotonMap<Selector> {
+  fn render(&self, configuration: &Arc<RenderConfiguration>) -> RenderBuffer {
+    let scene = configuration.scene();
+    let lights = scene.get_light_samples(10000);
+    let (width, height) = self.camera.size();
+    let mut pixels = vec![
+      PixelStatistics {
+        radius: 0.0,
+        photon_count: 0.0,
+        flux: Vector::new(),
+        emitted: Vector::new(),
+        depth: std::f32::INFINITY,
+      };
+      width * height
+    ];
+
+    for pass in 0..self.passes {
+      let _t = Timing::new(&format!("Progressive photon map pass {}", pass + 1));
+      let visible_points = {
+        let mut queue = DispatchQueue::default();
+        for index in 0..width * height {
+          queue.add_task(&index);
+        }
+        let camera = self.camera.clone();
+        let scene = scene.clone();
+        queue.consume_tasks(&move |index| {
+          let x = (index % width) as f64 + random(0.0, 1.0);
+          let y = (index / width) as f64 + random(0.0, 1.0);
+          let (emitted, distance, point) = trace_camera_path(&scene, camera.ray_for_coordinate(x, y));
+          return (*index, emitted, distance, point);
+        })
+      };
+
+      let mut gather_queue = DispatchQueue::default();
+      for (index, emitted, distance, point) in visible_points {
+        let pixel = &mut pixels[index];
+        pixel.emitted = pixel.emitted + emitted;
+        if pass == 0 {
+          pixel.depth = distance;
+        }
+        if let Some(point) = point {
+          gather_queue.add_task(&(index, point, pixel.radius));
+        }
+      }
+
+      let mut photons = trace_photons(&self.selector, &scene, &lights, self.photons_per_pass);
+      if photons.is_empty() {
+        continue;
+      }
+      let tree = Arc::new(KDTree::new(&mut photons, self.max_elements_per_leaf));
+      let selector = self.selector.clone();
+      let initial_photon_samples = self.initial_photon_samples;
+      let gathered = gather_queue.consume_tasks(&move |(index, point, radius)| {
+        return (
+          *index,
+          gather(&*selector, &tree, point, *radius, initial_photon_samples),
+        );
+      });
+
+      for (index, (radius, count, flux)) in gathered {
+        pixels[index].add_photons(radius, count, flux);
+      }
+    }
+
+    let mut buffer = RenderBuffer::new(width, height);
+    let passes = self.passes as f32;
+    for (index, pixel) in pixels.iter().enumerate() {
+      let mut colour = pixel.emitted / passes;
+      if pixel.radius > 0.0 {
+        colour = colour + pixel.flux / (passes * PI * pixel.radius * pixel.radius);
+      }
+      buffer.set(index % width, index / width, (colour, self.passes, pixel.depth as f64));
+    }
+    return buffer;
+  }
+}
+
+#[test]
+fn test_radius_reduction() {
+  let mut pixel = PixelStatistics {
+    radius: 0.0,
+    photon_count: 10.0,
+    flux: Vector::vector(3.0, 3.0, 3.0),
+    emitted: Vector::new(),
+    depth: 0.0,
+  };
+  pixel.add_photons(2.0, 20.0, Vector::vector(6.0, 6.0, 6.0));
+  // The area shrinks by (N + alpha M) / (N + M), with alpha of 0.7.
+  let ratio = (10.0 + 0.7 * 20.0) / (10.0 + 20.0);
+  assert!((pixel.photon_count - 24.0).abs() < 0.0001);
+  assert!((pixel.radius * pixel.radius - 4.0 * ratio).abs() < 0.0001);
+  assert!((pixel.flux - Vector::vector(9.0, 9.0, 9.0) * ratio).length() < 0.0001);
+  // Passes that gather nothing keep the radius they looked in.
+  pixel.add_photons(1.5, 0.0, Vector::new());
+  assert_eq!(pixel.radius, 1.5);
+  assert!((pixel.photon_count - 24.0).abs() < 0.0001);
+}
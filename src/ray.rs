@@ -1,23 +1,110 @@
-use crate::scene::MediaIdx;
-use crate::media::Media;
+use crate::colour::Colour;
+use crate::scene::{MaterialIdx, MediaIdx};
+use crate::spectrum::{Blackbody, Wavelengths};
 use crate::vectors::*;
 
+/// The inside of a dielectric or medium that a ray can be in. Objects that
+/// share a material are treated as the same interior, so they shouldn't be
+/// nested inside one another.
+#[derive(Debug, Clone, Copy)]
+pub struct Interior {
+  pub material: MaterialIdx,
+  /// Where objects overlap, rays are inside the one with the highest
+  /// priority, or the one they entered last if those are equal.
+  pub priority: i32,
+  /// The index of refraction, or None if the interior takes the index of
+  /// whatever it's inside of, as fog does.
+  pub ior: Option<f32>,
+  pub media: Option<MediaIdx>,
+}
+
+/// State carried along a ray as it passes through the scene, such as the
+/// dielectrics and participating media it is inside of.
 #[derive(Debug, Clone)]
 pub struct RayContext {
-  pub media: Option<MediaIdx>,
+  // Ordered by priority, so the interior the ray is actually in is last.
+  interiors: Vec<Interior>,
+  /// The wavelengths the ray carries when rendering spectrally, in which
+  /// case colours hold a value for each of them instead of RGB.
+  pub wavelengths: Option<Wavelengths>,
 }
 
 impl RayContext {
   pub fn new() -> RayContext {
-    RayContext { media: None }
+    RayContext {
+      interiors: Vec::new(),
+      wavelengths: None,
+    }
+  }
+
+  /// Converts an RGB colour to the values at the ray's wavelengths, if it
+  /// carries any.
+  pub fn upsample<T: From<Colour> + Into<Colour>>(&self, value: T) -> T {
+    return match self.wavelengths {
+      Some(wavelengths) => T::from(wavelengths.upsample(value.into())),
+      None => value,
+    };
+  }
+
+  /// As upsample, for light from an emitter whose spectrum is that of the
+  /// black body, if it has one, and whose colour is then the black body's
+  /// scaled by its luminance.
+  pub fn upsample_emission<T: From<Colour> + Into<Colour>>(&self, value: T, blackbody: Option<Blackbody>) -> T {
+    return match (self.wavelengths, blackbody) {
+      (Some(wavelengths), Some(blackbody)) => T::from(blackbody.spectrum(&wavelengths) * value.into().luminance()),
+      _ => self.upsample(value),
+    };
+  }
+
+  /// Whether the ray has been left with only its hero wavelength, see
+  /// Wavelengths::hero_only.
+  pub fn is_dispersed(&self) -> bool {
+    return self.wavelengths.map_or(false, |wavelengths| wavelengths.is_dispersed());
   }
 
-  pub fn enter_material(&self, media: Option<MediaIdx>) -> RayContext {
-    self.clone()
+  /// The participating medium the ray is travelling through.
+  pub fn media(&self) -> Option<MediaIdx> {
+    return self.interiors.last().and_then(|interior| interior.media);
   }
 
-  pub fn exit_material(&self) -> RayContext {
-    return self.clone();
+  /// The index of refraction around the ray.
+  pub fn ior(&self) -> f32 {
+    return self
+      .interiors
+      .iter()
+      .rev()
+      .find_map(|interior| interior.ior)
+      .unwrap_or(1.0);
+  }
+
+  /// The context of a ray that has crossed into `interior`.
+  pub fn enter_material(&self, interior: Interior) -> RayContext {
+    let mut context = self.exit_material(interior.material);
+    let index = context
+      .interiors
+      .iter()
+      .position(|other| other.priority > interior.priority)
+      .unwrap_or(context.interiors.len());
+    context.interiors.insert(index, interior);
+    return context;
+  }
+
+  /// The context of a ray that has left the interior of `material`.
+  pub fn exit_material(&self, material: MaterialIdx) -> RayContext {
+    let mut context = self.clone();
+    context.interiors.retain(|interior| interior.material.0 != material.0);
+    return context;
+  }
+
+  /// The indices of refraction on the inside and outside of a surface of
+  /// `interior`. When the ray is inside something with a higher priority,
+  /// they're the same, and the surface only changes what the ray is inside
+  /// of.
+  pub fn interface_iors(&self, interior: Interior) -> (f32, f32) {
+    return (
+      self.enter_material(interior).ior(),
+      self.exit_material(interior.material).ior(),
+    );
   }
 }
 
@@ -58,3 +145,37 @@ impl Ray {
     }
   }
 }
+
+#[test]
+fn test_nested_interiors() {
+  let interior = |material: u32, priority: i32, ior: Option<f32>| Interior {
+    material: MaterialIdx(material),
+    priority,
+    ior,
+    media: Some(MediaIdx(material)),
+  };
+  let glass = interior(0, 1, Some(1.5));
+  let water = interior(1, 0, Some(1.33));
+  let fog = interior(2, 0, None);
+
+  // Water entered from inside the glass is under it, so the glass wins.
+  let in_glass = RayContext::new().enter_material(glass);
+  assert_eq!(in_glass.interface_iors(water), (1.5, 1.5));
+  let in_both = in_glass.enter_material(water);
+  assert_eq!(in_both.ior(), 1.5);
+  assert_eq!(in_both.media().unwrap().0, 0);
+  // Leaving the glass leaves the ray in the water.
+  assert_eq!(in_both.interface_iors(glass), (1.5, 1.33));
+  let in_water = in_both.exit_material(glass.material);
+  assert_eq!(in_water.ior(), 1.33);
+  assert_eq!(in_water.media().unwrap().0, 1);
+
+  // Fog takes the index of whatever it's in.
+  let in_fog = in_water.enter_material(fog);
+  assert_eq!(in_fog.ior(), 1.33);
+  assert_eq!(in_fog.media().unwrap().0, 2);
+  assert_eq!(
+    in_fog.exit_material(fog.material).exit_material(water.material).ior(),
+    1.0
+  );
+}
@@ -5,10 +5,14 @@ use crate::fragment::Fragment;
 use crate::material::MaterialCollisionInfo;
 use crate::scene::Scene;
 
+/// The lighting at a shading point. Estimates of the irradiance, such as
+/// photon map lookups, go in `diffuse` and are scaled by the diffuse colour of
+/// the surface's Bsdf, while `reflected` is radiance that has already been
+/// scattered by the Bsdf towards the viewer.
 pub struct SampleLighting {
   pub diffuse: Colour,
   pub ambient: Colour,
-  pub specular: Colour,
+  pub reflected: Colour,
 }
 
 pub trait LightingIntegrator: Sync + Send {
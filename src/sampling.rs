@@ -0,0 +1,92 @@
+use crate::photon_map::random;
+use crate::vectors::{Vector, VectorType};
+use std::f64::consts::PI;
+
+/// Returns two unit tangents that form an orthonormal basis with the normal.
+pub fn tangent_frame(normal: Vector) -> (Vector, Vector) {
+  let helper = if normal.x().abs() > 0.9 {
+    Vector::vector(0.0, 1.0, 0.0)
+  } else {
+    Vector::vector(1.0, 0.0, 0.0)
+  };
+  let tangent = helper.cross(normal).normalize();
+  let bitangent = normal.cross(tangent);
+  return (tangent, bitangent);
+}
+
+/// Picks a direction in the hemisphere around the normal with probability
+/// proportional to the cosine of the angle to the normal, i.e. a pdf of
+/// cos(theta) / pi.
+pub fn cosine_weighted_hemisphere(normal: Vector) -> Vector {
+  return cosine_weighted_direction(normal, random_pair());
+}
+
+/// The direction that cosine_weighted_hemisphere picks for the uniform
+/// sample `u`.
+pub fn cosine_weighted_direction(normal: Vector, u: (f32, f32)) -> Vector {
+  let (u, v) = (u.0 as f64, u.1 as f64);
+  let phi = 2.0 * PI * v;
+  let r = u.sqrt();
+  let (tangent, bitangent) = tangent_frame(normal);
+  return (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - u).sqrt()).normalize();
+}
+
+/// A pair of uniform random numbers in [0, 1), for the samplers that take
+/// their random numbers as arguments.
+pub fn random_pair() -> (f32, f32) {
+  return (random(0.0, 1.0) as f32, random(0.0, 1.0) as f32);
+}
+
+/// Picks a direction uniformly within the cone around the axis whose edge has
+/// the given cosine, with a pdf of 1 / (2 * pi * (1 - cos_max)).
+pub fn uniform_cone(axis: Vector, cos_max: f32) -> Vector {
+  let cos_theta = 1.0 - random(0.0, 1.0) * (1.0 - cos_max as f64);
+  let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+  let phi = 2.0 * PI * random(0.0, 1.0);
+  let (tangent, bitangent) = tangent_frame(axis);
+  return (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta).normalize();
+}
+
+/// Picks a direction uniformly over the unit sphere, with a pdf of 1 / (4 * pi).
+pub fn uniform_sphere() -> Vector {
+  let z = 1.0 - 2.0 * random(0.0, 1.0);
+  let r = (1.0 - z * z).max(0.0).sqrt();
+  let phi = 2.0 * PI * random(0.0, 1.0);
+  return Vector::vector(r * phi.cos(), r * phi.sin(), z);
+}
+
+/// The power heuristic, with an exponent of two, for combining `f_samples`
+/// samples with a pdf of `f_pdf` with `g_samples` from another strategy with
+/// a pdf of `g_pdf`. Returns the weight for the first strategy.
+pub fn power_heuristic(f_samples: usize, f_pdf: f32, g_samples: usize, g_pdf: f32) -> f32 {
+  let f = f_samples as f32 * f_pdf;
+  let g = g_samples as f32 * g_pdf;
+  if f == 0.0 {
+    return 0.0;
+  }
+  return (f * f) / (f * f + g * g);
+}
+
+#[test]
+fn test_cosine_weighted_hemisphere() {
+  let normal = Vector::vector(0.0, 0.6, 0.8);
+  let mut mean_cosine = 0.0;
+  for _ in 0..10000 {
+    let direction = cosine_weighted_hemisphere(normal);
+    assert!((direction.length() - 1.0).abs() < 0.001);
+    assert!(direction.dot(normal) >= -0.001);
+    mean_cosine += direction.dot(normal) / 10000.0;
+  }
+  // The expected cosine under a cosine weighted distribution is 2/3.
+  assert!((mean_cosine - 2.0 / 3.0).abs() < 0.02);
+}
+
+#[test]
+fn test_power_heuristic() {
+  assert_eq!(power_heuristic(1, 2.0, 1, 0.0), 1.0);
+  assert_eq!(power_heuristic(1, 0.0, 1, 2.0), 0.0);
+  assert!((power_heuristic(2, 1.0, 1, 1.0) - 0.8).abs() < 0.0001);
+  // The weights of two strategies for the same sample sum to one.
+  let weights = power_heuristic(50, 0.3, 4, 1.7) + power_heuristic(4, 1.7, 50, 0.3);
+  assert!((weights - 1.0).abs() < 0.0001);
+}
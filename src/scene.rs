@@ -1,4 +1,5 @@
-use crate::either::left;
+use crate::either::{left, right};
+use crate::fragment::Fragment;
 use crate::media::Media;
 use crate::material::compute_secondaries;
 use crate::render_configuration::RenderConfiguration;
@@ -14,14 +15,20 @@ use image::*;
 use crate::intersectable::*;
 use crate::material;
 use crate::ray::Ray;
+use crate::light::Light;
 use crate::light::LightSample;
 use crate::shader::Shadable;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use crate::texture::Texture;
 use crate::vectors::*;
-use crate::photon_map::Timing;
+use crate::photon_map::{random, Timing};
+use crate::spectrum::Wavelengths;
 use crate::either::Either;
+use crate::environment::EnvironmentMap;
+use crate::aov::AOVSample;
+use crate::bounding_box::{BoundingBox, HasBoundingBox};
 
 #[derive(Debug, Copy, Clone)]
 pub struct MaterialIdx(pub u32);
@@ -42,6 +49,23 @@ impl MediaIdx {
   }
 }
 
+impl<'a> Either<&'a Shadable, &'a Media> {
+  /// Computes the fragment for a hit returned by Scene::intersect, which may
+  /// be a surface or a scattering point in a medium.
+  pub fn compute_fragment(&self, s: &Scene, r: &Ray, collision: &Collision) -> Fragment {
+    return match *self {
+      Either::Left(shadable) => shadable.compute_fragment(s, r, collision),
+      Either::Right(media) => media.compute_fragment(s, r, collision),
+    };
+  }
+  pub fn is_media(&self) -> bool {
+    return match *self {
+      Either::Left(_) => false,
+      Either::Right(_) => true,
+    };
+  }
+}
+
 impl NormalIdx {
   pub fn get(&self, s: &Scene) -> Vector {
     let NormalIdx(idx) = *self;
@@ -49,21 +73,63 @@ impl NormalIdx {
   }
 }
 
+/// Selects the LightingIntegrator used to render the scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntegratorType {
+  /// Direct lighting, using the photon map for indirect lighting if one is
+  /// configured.
+  DirectLighting,
+  PathTracing,
+  BidirectionalPathTracing,
+  /// Stochastic progressive photon mapping, which replaces the camera rather
+  /// than shading individual hits.
+  ProgressivePhotonMapping,
+}
+
+impl FromStr for IntegratorType {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, String> {
+    return match s.trim().to_lowercase().as_str() {
+      "direct" => Ok(IntegratorType::DirectLighting),
+      "path" => Ok(IntegratorType::PathTracing),
+      "bdpt" => Ok(IntegratorType::BidirectionalPathTracing),
+      "sppm" => Ok(IntegratorType::ProgressivePhotonMapping),
+      other => Err(format!("Unknown integrator '{}'", other)),
+    };
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct SceneSettings {
   pub scene_file: String,
   pub camera_position: Point,
   pub camera_direction: Vector,
   pub camera_up: Vector,
+  pub fov: f64,
   pub max_leaf_photons: usize,
   pub photon_samples: usize,
   pub width: usize,
   pub height: usize,
   pub samples_per_pixel: usize,
   pub photon_count: usize,
+  pub caustic_photon_count: usize,
+  pub caustic_photon_samples: usize,
+  pub final_gather_rays: usize,
+  pub irradiance_cache_rays: usize,
+  pub irradiance_cache_accuracy: f32,
+  pub light_samples: usize,
+  pub bsdf_samples: usize,
   pub use_direct_lighting: bool,
   pub use_multisampling: bool,
   pub gamma: f32,
+  pub integrator: IntegratorType,
+  pub max_path_depth: usize,
+  pub path_samples: usize,
+  pub progressive_passes: usize,
+  /// Trace a few wavelengths along each path instead of RGB, see
+  /// spectrum.rs.
+  pub spectral: bool,
 }
 
 impl SceneSettings {
@@ -73,15 +139,28 @@ impl SceneSettings {
       camera_position: Vector::point(0., 0.5, 0.),
       camera_direction: Vector::vector(0., 0., 1.),
       camera_up: Vector::vector(0.0, 1.0, 0.0),
+      fov: 40.0,
       max_leaf_photons: 8,
       width: 700,
       height: 700,
       photon_samples: 0,
       samples_per_pixel: 4,
       photon_count: 0,
+      caustic_photon_count: 0,
+      caustic_photon_samples: 0,
+      final_gather_rays: 0,
+      irradiance_cache_rays: 0,
+      irradiance_cache_accuracy: 0.2,
+      light_samples: 50,
+      bsdf_samples: 4,
       use_direct_lighting: false,
       use_multisampling: false,
       gamma: 1.0,
+      integrator: IntegratorType::DirectLighting,
+      max_path_depth: 10,
+      path_samples: 16,
+      progressive_passes: 16,
+      spectral: false,
     };
   }
 }
@@ -103,6 +182,20 @@ pub struct Scene {
   mirror_material: MaterialIdx,
   glass_material: MaterialIdx,
   root_object: CompoundObject,
+  // Lights that aren't part of any object, such as point lights.
+  lights: Vec<Box<Light>>,
+  environment: Option<EnvironmentMap>,
+  // The emissive surfaces of the objects, copied out of them by finalize, and
+  // the index of each by the address of the original, which is what
+  // intersections return.
+  emitters: Vec<(Box<Light>, BoundingBox)>,
+  emitter_indices: HashMap<usize, usize>,
+}
+
+// The address of an object, which identifies it whichever of its traits it's
+// seen through.
+fn address<T: ?Sized>(object: &T) -> usize {
+  return object as *const T as *const u8 as usize;
 }
 
 impl Scene {
@@ -123,6 +216,10 @@ impl Scene {
       texture_coords: Vec::new(),
       textures: Vec::new(),
       root_object: CompoundObject::new(),
+      lights: Vec::new(),
+      environment: None,
+      emitters: Vec::new(),
+      emitter_indices: HashMap::new(),
       material_map: HashMap::new(),
       texture_map: HashMap::new(),
       default_material: MaterialIdx(0),
@@ -177,7 +274,7 @@ impl Scene {
     return Some(texture_idx);
   }
 
-  pub fn get_or_create_material<Loader: Fn(&mut Scene) -> Option<Box<Material>>>(
+  pub fn get_or_create_material<Loader: FnOnce(&mut Scene) -> Option<Box<Material>>>(
     &mut self,
     name: &str,
     loader: Loader,
@@ -197,6 +294,40 @@ impl Scene {
   pub fn add_object(&mut self, object: Box<Intersectable>) {
     self.root_object.add_object(object)
   }
+  pub fn add_media(&mut self, media: Box<Media>) -> MediaIdx {
+    let index = MediaIdx(self.medias.len() as u32);
+    self.medias.push(media);
+    return index;
+  }
+  pub fn add_light(&mut self, light: Box<Light>) {
+    self.lights.push(light);
+  }
+  /// Lights the scene with the environment map, which is also seen by any
+  /// ray that leaves the scene.
+  pub fn set_environment(&mut self, environment: EnvironmentMap) {
+    self.environment = Some(environment);
+  }
+  pub fn environment(&self) -> Option<&EnvironmentMap> {
+    return self.environment.as_ref();
+  }
+  /// The settings the scene was loaded with.
+  pub fn settings(&self) -> &SceneSettings {
+    return &self.settings;
+  }
+  /// Whether paths carry wavelengths rather than RGB, see RayContext.
+  pub fn is_spectral(&self) -> bool {
+    return self.settings.spectral;
+  }
+  /// The radiance arriving along a ray that misses everything in the scene.
+  pub fn background(&self, direction: Vector) -> Vector {
+    return match self.environment {
+      Some(ref environment) => environment.radiance(direction),
+      None => Vector::new(),
+    };
+  }
+  pub fn bounds(&self) -> BoundingBox {
+    return self.root_object.bounds();
+  }
   pub fn default_material(&self) -> MaterialIdx {
     self.default_material
   }
@@ -208,14 +339,23 @@ impl Scene {
   pub fn glass_material(&self) -> MaterialIdx {
     self.glass_material
   }
+  /// Finds the nearest surface along the ray, or the point where the medium
+  /// the ray is travelling through scatters or absorbs it before reaching
+  /// that surface.
   pub fn intersect<'a>(&'a self, ray: &Ray) -> Option<(Collision, Either<&'a Shadable, &'a Media>)> {
-    if let Some(media) = ray.ray_context.media {
+    let hit = self.root_object.intersect(ray, HitMode::Nearest, ray.min, ray.max);
+    if let Some(media) = ray.ray_context.media() {
       let media = media.get(self);
+      let max = match hit {
+        Some((ref collision, _)) => collision.distance,
+        None => ray.max,
+      };
+      let segment = Ray::new_bound(ray.origin, ray.direction, ray.min, max, Some(ray.ray_context.clone()));
+      if let Some((distance, _)) = media.compute_media_fragment(self, &segment) {
+        return Some((Collision::new(distance, Vec2d(0.0, 0.0)), right(media)));
+      }
     }
-    return self
-      .root_object
-      .intersect(ray, HitMode::Nearest, ray.min, ray.max)
-      .map(|(d, c)| (d, left(c)));
+    return hit.map(|(d, c)| (d, left(c)));
   }
 
   pub fn has_intersection(&self, ray: &Ray) -> bool {
@@ -225,10 +365,141 @@ impl Scene {
       .is_some();
   }
 
+  /// The fraction of light that travels between the bounds of the ray, which
+  /// is zero if a surface is in the way. Only the invisible boundaries of
+  /// media let light through, after it has been attenuated by the media they
+  /// enclose.
+  pub fn transmittance(&self, ray: &Ray) -> Colour {
+    if self.medias.is_empty() {
+      return if self.has_intersection(ray) {
+        Colour::new()
+      } else {
+        Colour::RGB(1.0, 1.0, 1.0)
+      };
+    }
+    let through = |media: MediaIdx, span: &Ray| {
+      let media = media.get(self);
+      return media.attenuation(self, span) * media.transmittance(self, span);
+    };
+    let mut transmittance = Colour::RGB(1.0, 1.0, 1.0);
+    let mut context = ray.ray_context.clone();
+    let mut min = ray.min;
+    loop {
+      let segment = Ray::new_bound(ray.origin, ray.direction, min, ray.max, Some(context.clone()));
+      let (collision, shadable) = match self.root_object.intersect(&segment, HitMode::Nearest, min, ray.max) {
+        Some(hit) => hit,
+        None => break,
+      };
+      let fragment = shadable.compute_fragment(self, &segment, &collision);
+      let surface = self
+        .get_material(fragment.material)
+        .compute_surface_properties(self, &segment, &fragment);
+      let interior = match surface.interior {
+        Some(interior) if surface.bsdf.is_invisible() => interior,
+        _ => return Colour::new(),
+      };
+      if let Some(media) = context.media() {
+        let span = Ray::new_bound(
+          ray.origin,
+          ray.direction,
+          min,
+          collision.distance,
+          Some(context.clone()),
+        );
+        transmittance = transmittance * through(media, &span);
+      }
+      if transmittance.max_value() <= 0.0 {
+        return Colour::new();
+      }
+      // The orientation of the boundary says whether the ray is leaving the
+      // medium or entering it.
+      let exiting = fragment.view.dot(fragment.true_normal) < 0.0;
+      context = if exiting {
+        context.exit_material(interior.material)
+      } else {
+        context.enter_material(interior)
+      };
+      min = collision.distance + 0.005;
+    }
+    if let Some(media) = context.media() {
+      let span = Ray::new_bound(ray.origin, ray.direction, min, ray.max, Some(context.clone()));
+      transmittance = transmittance * through(media, &span);
+    }
+    return transmittance;
+  }
+
+  /// The colour that light travelling along the ray from `ray.min` to
+  /// `distance` is scaled by, from media such as AbsorbingMedia that absorb
+  /// it without stopping the ray. Anything that follows rays through the
+  /// scene scales what it finds at the end of them by this.
+  pub fn attenuation(&self, ray: &Ray, distance: f32) -> Colour {
+    return match ray.ray_context.media() {
+      Some(media) => {
+        let span = Ray::new_bound(
+          ray.origin,
+          ray.direction,
+          ray.min,
+          distance,
+          Some(ray.ray_context.clone()),
+        );
+        media.get(self).attenuation(self, &span)
+      }
+      None => Colour::RGB(1.0, 1.0, 1.0),
+    };
+  }
+
   pub fn finalize(&mut self) {
     Timing::time("Build scene graph", || {
       self.root_object.finalize();
     });
+    // Building the scene graph moves the objects, so they're only found once
+    // it's done.
+    let mut emitters = vec![];
+    let mut emitter_indices = HashMap::new();
+    for light in self.root_object.get_lights(self) {
+      if let Some(surface) = light.copy_surface() {
+        emitter_indices.insert(address(light), emitters.len());
+        emitters.push(surface);
+      }
+    }
+    self.emitters = emitters;
+    self.emitter_indices = emitter_indices;
+  }
+
+  /// The number of lights that Scene::light indexes: the emissive surfaces
+  /// of the objects, then the lights that aren't part of any object, then
+  /// the environment map.
+  pub fn light_count(&self) -> usize {
+    return self.emitters.len() + self.lights.len() + if self.environment.is_some() { 1 } else { 0 };
+  }
+
+  /// The light with the given index, and its bounds if it's a surface. Light
+  /// samples of surfaces are at a fresh random point, with the `area` of the
+  /// whole surface.
+  pub fn light(&self, index: usize) -> (&Light, Option<BoundingBox>) {
+    if index < self.emitters.len() {
+      let (ref light, bounds) = self.emitters[index];
+      return (&**light, Some(bounds));
+    }
+    let index = index - self.emitters.len();
+    if index < self.lights.len() {
+      return (&*self.lights[index], None);
+    }
+    return (self.environment.as_ref().unwrap(), None);
+  }
+
+  /// The index of the environment map's light, see Scene::light.
+  pub fn environment_light(&self) -> Option<usize> {
+    return self
+      .environment
+      .as_ref()
+      .map(|_| self.emitters.len() + self.lights.len());
+  }
+
+  /// The index of the light that an intersection found, if it hit an
+  /// emissive surface, see Scene::light.
+  pub fn emitter_index(&self, shadable: &Shadable) -> Option<usize> {
+    return self.emitter_indices.get(&address(shadable)).cloned();
   }
 
   pub fn get_normal(&self, idx: u32) -> Vector {
@@ -254,67 +525,115 @@ impl Scene {
   }
 
   pub fn colour_and_depth_for_ray(&self, configuration: &RenderConfiguration, ray: &Ray) -> (Vector, f32) {
-    return self.intersect_ray(configuration, ray, 0);
+    return self.trace_camera_ray(configuration, ray, None);
+  }
+
+  /// As colour_and_depth_for_ray, but also reports the surface properties
+  /// at the first hit, if there was one.
+  pub fn colour_depth_and_aovs_for_ray(
+    &self,
+    configuration: &RenderConfiguration,
+    ray: &Ray,
+  ) -> (Vector, f32, Option<AOVSample>) {
+    let mut first_hit = None;
+    let (colour, depth) = self.trace_camera_ray(configuration, ray, Some(&mut first_hit));
+    return (colour, depth, first_hit);
   }
 
-  fn intersect_ray(&self, configuration: &RenderConfiguration, ray: &Ray, depth: usize) -> (Vector, f32) {
+  // In spectral mode, each camera ray carries a fresh set of wavelengths, and
+  // what it finds is converted back to RGB.
+  fn trace_camera_ray(
+    &self,
+    configuration: &RenderConfiguration,
+    ray: &Ray,
+    first_hit: Option<&mut Option<AOVSample>>,
+  ) -> (Vector, f32) {
+    if !self.settings.spectral {
+      return self.intersect_ray(configuration, ray, 0, first_hit);
+    }
+    let wavelengths = Wavelengths::sample(random(0.0, 1.0) as f32);
+    let mut ray = ray.clone();
+    ray.ray_context.wavelengths = Some(wavelengths);
+    let (colour, depth) = self.intersect_ray(configuration, &ray, 0, first_hit);
+    return (Vector::from(wavelengths.to_rgb(Colour::from(colour))), depth);
+  }
+
+  fn intersect_ray(
+    &self,
+    configuration: &RenderConfiguration,
+    ray: &Ray,
+    depth: usize,
+    first_hit: Option<&mut Option<AOVSample>>,
+  ) -> (Vector, f32) {
     if depth > 10 {
       return (Vector::vector(0.0, 0.0, 1.0), 0.0);
     }
 
     let (collision, shadable) = match self.intersect(ray) {
-      None => return (Vector::new(), std::f32::INFINITY),
-      Some((c, e)) => (c, e.unwrap_left()),
+      None => {
+        return (
+          ray.ray_context.upsample(self.background(ray.direction)),
+          std::f32::INFINITY,
+        )
+      }
+      Some(hit) => hit,
     };
 
+    // Light along the ray is absorbed by any media it passes through.
+    let attenuation = self.attenuation(ray, collision.distance);
     let (surface_colour, surface_distance) = {
       let fragment = shadable.compute_fragment(self, ray, &collision);
 
       let material = self.get_material(fragment.material);
       let surface = material.compute_surface_properties(self, ray, &fragment);
-
-      // let ambient_colour = Vector::from(surface.ambient_colour);
-      let mut diffuse_colour = Vector::from(surface.diffuse_colour);
-      if let Some(emission) = surface.emissive_colour {
-        return (
-          Vector::from(
-            emission.ambient * surface.ambient_colour
-              + emission.diffuse * surface.diffuse_colour
-              + emission.specular * surface.specular_colour,
-          ),
-          collision.distance,
-        );
+      if let Some(first_hit) = first_hit {
+        *first_hit = Some(AOVSample {
+          normal: surface.normal,
+          position: fragment.position,
+          albedo: surface.diffuse_colour,
+          uv: fragment.uv,
+          material: fragment.material,
+          object_id: collision.object_index,
+          node_count: collision.node_count,
+        });
       }
+      let surface = surface.upsampled(&ray.ray_context);
 
+      // Emitters may reflect light as well, so they're shaded like any other
+      // surface.
+      let emission = surface.emitted_colour().unwrap_or(Colour::new());
       let mut colour;
 
+      // The delta lobes of the surface are followed exhaustively, and the
+      // rest are left to the lighting integrator.
       let mut max_secondary_distance = 0.0f32;
-      let mut remaining_weight = 1.0;
       let mut secondaries_colour = Vector::new();
-      for (ray, secondary_colour, weight) in compute_secondaries(ray, &fragment, &surface) {
-        if remaining_weight <= 0.0 {
-          break;
-        }
-        remaining_weight -= weight;
-        let (secondary_intersection_colour, secondary_distance) = self.intersect_ray(configuration, &ray, depth + 1);
+      for (ray, secondary_colour) in compute_secondaries(ray, &fragment, &surface) {
+        let (secondary_intersection_colour, secondary_distance) =
+          self.intersect_ray(configuration, &ray, depth + 1, None);
         secondaries_colour =
-          secondaries_colour + Vector::from(Colour::from(secondary_intersection_colour) * secondary_colour * weight);
+          secondaries_colour + Vector::from(Colour::from(secondary_intersection_colour) * secondary_colour);
         max_secondary_distance = max_secondary_distance.max(secondary_distance);
       }
-      colour = secondaries_colour;
+      colour = secondaries_colour + Vector::from(emission);
 
-      diffuse_colour = diffuse_colour * remaining_weight;
-      if diffuse_colour.length() <= 0.01 {
-        return (colour, collision.distance + max_secondary_distance);
+      if !surface.bsdf.has_non_delta() {
+        return (
+          Vector::from(Colour::from(colour) * attenuation),
+          collision.distance + max_secondary_distance,
+        );
       }
       let sample_lighting = configuration.lighting_integrator().lighting(self, &fragment, &surface);
       colour = colour
         + Vector::from(
-          Colour::from(diffuse_colour) * sample_lighting.diffuse
+          surface.bsdf.diffuse_colour() * sample_lighting.diffuse
             + Colour::from(surface.ambient_colour) * sample_lighting.ambient
-            + Colour::from(surface.specular_colour) * sample_lighting.specular,
+            + sample_lighting.reflected,
         );
-      return (colour, collision.distance + max_secondary_distance);
+      return (
+        Vector::from(Colour::from(colour) * attenuation),
+        collision.distance + max_secondary_distance,
+      );
     };
     return (surface_colour, surface_distance);
   }
@@ -346,6 +665,14 @@ impl Scene {
       }
       lights.append(&mut samples);
     }
+    // Point and directional lights are a single sample each, outside of the
+    // budget shared by the emissive surfaces.
+    for light in &self.lights {
+      lights.append(&mut light.get_samples(1, self));
+    }
+    if let Some(ref environment) = self.environment {
+      lights.append(&mut environment.get_samples(1, self));
+    }
     return lights;
   }
 }
@@ -0,0 +1,709 @@
+use crate::analytic_light::{DirectionalLight, PointLight, SpotLight};
+use crate::bounding_box::BoundingBox;
+use crate::colour::Colour;
+use crate::environment::EnvironmentMap;
+use crate::light::Light;
+use crate::material::ConductorMaterial;
+use crate::material::DefaultMaterial;
+use crate::material::EmissiveMaterial;
+use crate::material::Fog;
+use crate::material::GlossyMaterial;
+use crate::material::Material;
+use crate::material::ScatteringMaterial;
+use crate::material::TransparentMaterial;
+use crate::media::{AbsorbingMedia, HeterogeneousMedia, HomogenousMedia, VoxelGrid};
+use crate::objects::Mesh;
+use crate::scene::MaterialIdx;
+use crate::scene::MediaIdx;
+use crate::scene::Scene;
+use crate::scene::SceneSettings;
+use crate::sky::PreethamSky;
+use crate::spectrum::{Blackbody, Dispersion};
+use crate::sphere::Sphere;
+use crate::vectors::*;
+use crate::wavefront_material;
+use crate::wavefront_material::PrincipledMaterial;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+// An XML scene description looks like:
+//
+// <scene name="cornell" camera="front">
+//   <settings width="700" height="700" samples-per-pixel="4" photon-count="100000" photon-samples="50"/>
+//   <camera name="front" position="0,1,3.5" target="0,1,0" fov="40"/>
+//   <material name="lamp" type="emissive" colour="1,0.9,0.8" intensity="20"/>
+//   <material name="bulb" type="emissive" temperature="2700" luminance="50000"/>
+//   <material name="plastic" type="glossy" colour="0.6,0.1,0.1" specular="0.04,0.04,0.04" roughness="0.3"/>
+//   <material name="gold" type="principled" colour="1,0.77,0.34" metallic="1" roughness="0.2"/>
+//   <material name="copper" type="conductor" metal="copper" roughness="0.1"/>
+//   <medium name="smoke" density="0.5" colour="0.9,0.9,0.9"/>
+//   <material name="haze" type="fog" medium="smoke"/>
+//   <medium name="green" absorption="0.4,0.05,0.3"/>
+//   <material name="bottle" type="glass" ior="1.5" roughness="0.1" medium="green" priority="1"/>
+//   <material name="prism" type="glass" ior="1.62" abbe="36"/>
+//   <volume path="cloud.vol" min="-0.5,1,-0.5" max="0.5,1.5,0.5" density="20" colour="1,1,1"/>
+//   <object path="CornellBox-Empty-CO.obj"/>
+//   <sphere position="0,1,0" radius="0.25" material="lamp"/>
+//   <point-light position="0,1.5,0" colour="1,1,1" intensity="2"/>
+//   <point-light position="1,1.5,0" temperature="3000" lumens="800"/>
+//   <spot-light position="0,1.9,0" target="0,0,0" inner-angle="20" outer-angle="30" intensity="5"/>
+//   <directional-light direction="-1,-1,-1" colour="1,0.95,0.9" intensity="3"/>
+//   <environment path="sky.hdr" intensity="1" rotation="90"/>
+//   <sky elevation="30" azimuth="45" turbidity="3" intensity="0.05" resolution="1024"/>
+// </scene>
+//
+// Environment maps are equirectangular .hdr or .pfm images, and rotate around
+// the vertical axis by `rotation` degrees. A sky is a daylight model with the
+// sun at the given angles in degrees, which replaces any environment map.
+// Glossy materials add a GGX highlight scaled by the specular colour to a
+// diffuse base, where a roughness of 0 is a mirror and 1 is very broad.
+// Principled materials also take specular, clearcoat, clearcoat-roughness,
+// sheen, transmission and ior, with the same meanings as Disney's model.
+// Conductors are metals, either one of gold, copper, silver or aluminium, or
+// one with the complex index of refraction given by `eta` and `k`.
+// Media fill objects with a fog material. Their density is the extinction
+// coefficient per unit length, and their colour the scattering albedo. Media
+// with an absorption coefficient per unit length only tint the light passing
+// through them, and fill glass instead. Glass is smooth unless it has a
+// roughness, which means the same as for glossy materials. Where pieces of
+// glass overlap, such as a glass and the water in it, the one with the
+// highest priority fills the overlap. Glass with an Abbe number, or with the
+// sellmeier-b and sellmeier-c coefficients of a glass catalogue, disperses
+// light when the settings have spectral="true".
+// Lights and emissive materials given a temperature in Kelvin instead of a
+// colour emit the light of a black body at that temperature, which spectral
+// rendering samples directly. Their brightness can also be given in
+// photometric units: point and spot lights in candela, or by the lumens they
+// emit over the sphere or the spot's outer cone, or the watts they radiate if
+// they're black bodies; directional lights in lux; and emissive materials by
+// their luminance in cd/m^2. A unit of light in the renderer is a thousand of
+// these, as for the sky, and the intensity scales them further.
+// Volumes are Mitsuba style .vol density grids, whose values are scaled by
+// the density. They fill the box given by `min` and `max`, or the one stored
+// in the file, and need no fog material.
+// Paths are relative to the XML file.
+// Anything not specified keeps the value from the settings passed to
+// load_scene.
+
+struct CameraDescription {
+  name: Option<String>,
+  position: Option<Point>,
+  target: Option<Point>,
+  direction: Option<Vector>,
+  up: Option<Vector>,
+  fov: Option<f64>,
+}
+
+struct MediaDescription {
+  name: String,
+  density: f32,
+  colour: Colour,
+  absorption: Option<Colour>,
+}
+
+enum ObjectDescription {
+  Mesh {
+    path: PathBuf,
+    material: Option<String>,
+  },
+  Sphere {
+    position: Point,
+    radius: f32,
+    material: Option<String>,
+  },
+  Volume {
+    grid: VoxelGrid,
+    density: f32,
+    colour: Colour,
+  },
+}
+
+struct SceneDescription {
+  settings: SceneSettings,
+  active_camera: Option<String>,
+  cameras: Vec<CameraDescription>,
+  materials: Vec<(String, Box<Material>)>,
+  media: Vec<MediaDescription>,
+  objects: Vec<ObjectDescription>,
+  lights: Vec<Box<Light>>,
+  environment: Option<EnvironmentMap>,
+}
+
+struct Attributes<'a> {
+  element: &'a str,
+  attributes: &'a [OwnedAttribute],
+}
+
+impl<'a> Attributes<'a> {
+  fn get(&self, name: &str) -> Option<&'a str> {
+    return self
+      .attributes
+      .iter()
+      .find(|attribute| attribute.name.local_name == name)
+      .map(|attribute| attribute.value.as_str());
+  }
+
+  fn required(&self, name: &str) -> &'a str {
+    match self.get(name) {
+      Some(value) => value,
+      None => panic!("<{}> is missing the '{}' attribute", self.element, name),
+    }
+  }
+
+  fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+    return self.get(name).map(|value| match value.trim().parse::<T>() {
+      Ok(result) => result,
+      Err(_) => panic!("<{}> has an invalid value for '{}': {}", self.element, name, value),
+    });
+  }
+
+  fn triple(&self, name: &str) -> Option<(f64, f64, f64)> {
+    return self.get(name).map(|value| {
+      let coords: Vec<f64> = value
+        .trim_matches(|p| p == '(' || p == ')')
+        .split(',')
+        .map(|coord| match coord.trim().parse::<f64>() {
+          Ok(result) => result,
+          Err(_) => panic!("<{}> has an invalid value for '{}': {}", self.element, name, value),
+        })
+        .collect();
+      if coords.len() != 3 {
+        panic!("<{}> expected three components for '{}': {}", self.element, name, value);
+      }
+      (coords[0], coords[1], coords[2])
+    });
+  }
+
+  fn point(&self, name: &str) -> Option<Point> {
+    return self.triple(name).map(|(x, y, z)| Vector::point(x, y, z));
+  }
+
+  fn vector(&self, name: &str) -> Option<Vector> {
+    return self.triple(name).map(|(x, y, z)| Vector::vector(x, y, z));
+  }
+
+  fn colour(&self, name: &str) -> Option<Colour> {
+    return self
+      .triple(name)
+      .map(|(r, g, b)| Colour::RGB(r as f32, g as f32, b as f32));
+  }
+}
+
+fn apply_settings(settings: &mut SceneSettings, attributes: &Attributes) {
+  if let Some(width) = attributes.parse("width") {
+    settings.width = width;
+  }
+  if let Some(height) = attributes.parse("height") {
+    settings.height = height;
+  }
+  if let Some(samples_per_pixel) = attributes.parse::<usize>("samples-per-pixel") {
+    settings.samples_per_pixel = samples_per_pixel.max(1);
+  }
+  if let Some(photon_count) = attributes.parse("photon-count") {
+    settings.photon_count = photon_count;
+  }
+  if let Some(photon_samples) = attributes.parse("photon-samples") {
+    settings.photon_samples = photon_samples;
+  }
+  if let Some(caustic_photon_count) = attributes.parse("caustic-photon-count") {
+    settings.caustic_photon_count = caustic_photon_count;
+  }
+  if let Some(caustic_photon_samples) = attributes.parse("caustic-photon-samples") {
+    settings.caustic_photon_samples = caustic_photon_samples;
+  }
+  if let Some(final_gather_rays) = attributes.parse("final-gather-rays") {
+    settings.final_gather_rays = final_gather_rays;
+  }
+  if let Some(irradiance_cache_rays) = attributes.parse("irradiance-cache-rays") {
+    settings.irradiance_cache_rays = irradiance_cache_rays;
+  }
+  if let Some(irradiance_cache_accuracy) = attributes.parse("irradiance-cache-accuracy") {
+    settings.irradiance_cache_accuracy = irradiance_cache_accuracy;
+  }
+  if let Some(light_samples) = attributes.parse("light-samples") {
+    settings.light_samples = light_samples;
+  }
+  if let Some(bsdf_samples) = attributes.parse("bsdf-samples") {
+    settings.bsdf_samples = bsdf_samples;
+  }
+  if let Some(max_leaf_photons) = attributes.parse::<usize>("max-leaf-photons") {
+    settings.max_leaf_photons = max_leaf_photons.max(4);
+  }
+  if let Some(use_direct_lighting) = attributes.parse("use-direct-lighting") {
+    settings.use_direct_lighting = use_direct_lighting;
+  }
+  if let Some(use_multisampling) = attributes.parse("multisampling") {
+    settings.use_multisampling = use_multisampling;
+  }
+  if let Some(spectral) = attributes.parse("spectral") {
+    settings.spectral = spectral;
+  }
+  if let Some(gamma) = attributes.parse("gamma") {
+    settings.gamma = gamma;
+  }
+  if let Some(integrator) = attributes.parse("integrator") {
+    settings.integrator = integrator;
+  }
+  if let Some(max_path_depth) = attributes.parse::<usize>("max-path-depth") {
+    settings.max_path_depth = max_path_depth.max(1);
+  }
+  if let Some(path_samples) = attributes.parse::<usize>("path-samples") {
+    settings.path_samples = path_samples.max(1);
+  }
+  if let Some(progressive_passes) = attributes.parse::<usize>("progressive-passes") {
+    settings.progressive_passes = progressive_passes.max(1);
+  }
+}
+
+// Media are added to the scene in the order they're described, so their
+// indices are known before the scene is built.
+fn find_media(media: &[MediaDescription], name: &str) -> MediaIdx {
+  return match media.iter().position(|medium| medium.name == name) {
+    Some(index) => MediaIdx(index as u32),
+    None => panic!("Unknown medium '{}'", name),
+  };
+}
+
+fn parse_material(attributes: &Attributes, media: &[MediaDescription]) -> Box<Material> {
+  let colour = attributes.colour("colour").unwrap_or(Colour::RGB(0.7, 0.7, 0.7));
+  return match attributes.get("type").unwrap_or("diffuse") {
+    "diffuse" => Box::new(DefaultMaterial::new(colour, None)),
+    "mirror" => Box::new(DefaultMaterial::new(
+      colour,
+      Some(attributes.parse("reflectivity").unwrap_or(1.0)),
+    )),
+    "glossy" => Box::new(GlossyMaterial::new(
+      colour,
+      attributes.colour("specular").unwrap_or(Colour::RGB(0.04, 0.04, 0.04)),
+      attributes.parse("roughness").unwrap_or(0.3),
+    )),
+    "principled" => Box::new(
+      PrincipledMaterial::new(colour)
+        .with_metallic(attributes.parse("metallic").unwrap_or(0.0))
+        .with_roughness(attributes.parse("roughness").unwrap_or(0.5))
+        .with_specular(attributes.parse("specular").unwrap_or(0.5))
+        .with_clearcoat(
+          attributes.parse("clearcoat").unwrap_or(0.0),
+          attributes.parse("clearcoat-roughness").unwrap_or(0.03),
+        )
+        .with_sheen(attributes.parse("sheen").unwrap_or(0.0))
+        .with_transmission(
+          attributes.parse("transmission").unwrap_or(0.0),
+          attributes.parse("ior").unwrap_or(1.5),
+        ),
+    ),
+    "conductor" => {
+      let metal = match attributes.get("metal") {
+        Some(name) => match ConductorMaterial::named(name) {
+          Some(metal) => metal,
+          None => panic!("Unknown metal '{}'", name),
+        },
+        None => ConductorMaterial::new(
+          attributes.colour("eta").unwrap_or(Colour::RGB(1.0, 1.0, 1.0)),
+          attributes.colour("k").unwrap_or(Colour::new()),
+        ),
+      };
+      Box::new(metal.with_roughness(attributes.parse("roughness").unwrap_or(0.0)))
+    }
+    "glass" => {
+      let ior = attributes.parse("ior").unwrap_or(1.5);
+      let mut glass = TransparentMaterial::new(ior)
+        .with_roughness(attributes.parse("roughness").unwrap_or(0.0))
+        .with_priority(attributes.parse("priority").unwrap_or(0));
+      let coefficients = |name: &str| attributes.triple(name).map(|(x, y, z)| [x as f32, y as f32, z as f32]);
+      if let (Some(b), Some(c)) = (coefficients("sellmeier-b"), coefficients("sellmeier-c")) {
+        glass = glass.with_dispersion(Dispersion::Sellmeier { b, c });
+      } else if let Some(abbe) = attributes.parse("abbe") {
+        glass = glass.with_dispersion(Dispersion::from_abbe(ior, abbe));
+      }
+      match attributes.get("medium") {
+        Some(name) => Box::new(glass.with_media(find_media(media, name))),
+        None => Box::new(glass),
+      }
+    }
+    "emissive" => {
+      let (colour, blackbody) = emitter_colour(attributes, colour);
+      let brightness = emitter_brightness(attributes, colour, blackbody, "luminance", None);
+      Box::new(EmissiveMaterial::new(colour, brightness).with_blackbody(blackbody))
+    }
+    "fog" => Box::new(Fog::new(find_media(media, attributes.required("medium")))),
+    other => panic!("Unknown material type '{}'", other),
+  };
+}
+
+// Renderer units of light are a thousand photometric ones, matching the sky.
+const PHOTOMETRIC_SCALE: f32 = 1.0 / 1000.0;
+
+// The colour of an emitter, and the black body that spectral rendering
+// samples for it when it's given a temperature instead.
+fn emitter_colour(attributes: &Attributes, default: Colour) -> (Colour, Option<Blackbody>) {
+  return match attributes.parse::<f32>("temperature") {
+    Some(temperature) => {
+      let blackbody = Blackbody::new(temperature);
+      (blackbody.colour(), Some(blackbody))
+    }
+    None => (attributes.colour("colour").unwrap_or(default), None),
+  };
+}
+
+// The scale of an emitter's colour, from its intensity. The photometric
+// quantity `unit`, or the lumens converted to it by `per_lumen` for emitters
+// that take them, set the luminance of the colour before it's scaled.
+fn emitter_brightness(
+  attributes: &Attributes,
+  colour: Colour,
+  blackbody: Option<Blackbody>,
+  unit: &str,
+  per_lumen: Option<f32>,
+) -> f32 {
+  let lumens = match (attributes.parse::<f32>("lumens"), attributes.parse::<f32>("watts")) {
+    (Some(lumens), _) => Some(lumens),
+    (None, Some(watts)) => match blackbody {
+      Some(blackbody) => Some(watts * blackbody.luminous_efficacy()),
+      None => panic!("<{}> needs a 'temperature' to be given in watts", attributes.element),
+    },
+    (None, None) => None,
+  };
+  let photometric = match (attributes.parse::<f32>(unit), lumens, per_lumen) {
+    (Some(value), _, _) => Some(value),
+    (None, Some(lumens), Some(per_lumen)) => Some(lumens * per_lumen),
+    (None, Some(_), None) => panic!("<{}> can't be given in lumens or watts", attributes.element),
+    (None, None, _) => None,
+  };
+  let intensity = attributes.parse::<f32>("intensity").unwrap_or(1.0);
+  return match photometric {
+    Some(value) => intensity * value * PHOTOMETRIC_SCALE / colour.luminance(),
+    None => intensity,
+  };
+}
+
+// The intensity of point and spot lights, or the irradiance of directional
+// lights, whose photometric `unit` and candela per lumen are as for
+// emitter_brightness.
+fn light_power(attributes: &Attributes, unit: &str, per_lumen: Option<f32>) -> (Vector, Option<Blackbody>) {
+  let (colour, blackbody) = emitter_colour(attributes, Colour::RGB(1.0, 1.0, 1.0));
+  let brightness = emitter_brightness(attributes, colour, blackbody, unit, per_lumen);
+  return (Vector::from(colour) * brightness, blackbody);
+}
+
+fn parse_spot_light(attributes: &Attributes) -> SpotLight {
+  let position = attributes.point("position").unwrap_or(Vector::point(0.0, 0.0, 0.0));
+  let direction = match attributes.point("target") {
+    Some(target) => target - position,
+    None => attributes.vector("direction").unwrap_or(Vector::vector(0.0, -1.0, 0.0)),
+  };
+  let outer_angle: f32 = attributes.parse("outer-angle").unwrap_or(30.0);
+  // Lumens are spread over the outer cone.
+  let solid_angle = 2.0 * PI * (1.0 - (outer_angle.max(0.0).min(180.0) * PI / 180.0).cos());
+  let (intensity, blackbody) = light_power(attributes, "candela", Some(1.0 / solid_angle));
+  return SpotLight::new(
+    position,
+    direction,
+    intensity,
+    attributes.parse("inner-angle").unwrap_or(outer_angle),
+    outer_angle,
+  )
+  .with_blackbody(blackbody);
+}
+
+fn parse_scene_description(path: &Path, defaults: &SceneSettings) -> SceneDescription {
+  let directory = path.parent().unwrap().to_owned();
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(msg) => panic!("Fopen({:?}) failed with {}", path, msg),
+  };
+
+  let mut description = SceneDescription {
+    settings: defaults.clone(),
+    active_camera: None,
+    cameras: vec![],
+    materials: vec![],
+    media: vec![],
+    objects: vec![],
+    lights: vec![],
+    environment: None,
+  };
+
+  for event in EventReader::new(BufReader::new(file)) {
+    let (name, attributes) = match event {
+      Ok(XmlEvent::StartElement { name, attributes, .. }) => (name, attributes),
+      Ok(_) => continue,
+      Err(msg) => panic!("Failed to parse {:?} with error: {}", path, msg),
+    };
+    let element = name.local_name.as_str();
+    let attributes = Attributes {
+      element,
+      attributes: &attributes,
+    };
+    match element {
+      "scene" => description.active_camera = attributes.get("camera").map(|c| c.to_string()),
+      "settings" => apply_settings(&mut description.settings, &attributes),
+      "camera" => description.cameras.push(CameraDescription {
+        name: attributes.get("name").map(|n| n.to_string()),
+        position: attributes.point("position"),
+        target: attributes.point("target"),
+        direction: attributes.vector("direction"),
+        up: attributes.vector("up"),
+        fov: attributes.parse("fov"),
+      }),
+      "material" => {
+        let name = attributes.required("name").to_string();
+        let material = parse_material(&attributes, &description.media);
+        description.materials.push((name, material));
+      }
+      "medium" => description.media.push(MediaDescription {
+        name: attributes.required("name").to_string(),
+        density: attributes.parse("density").unwrap_or(1.0),
+        colour: attributes.colour("colour").unwrap_or(Colour::RGB(1.0, 1.0, 1.0)),
+        absorption: attributes.colour("absorption"),
+      }),
+      "object" => description.objects.push(ObjectDescription::Mesh {
+        path: directory.join(attributes.required("path")),
+        material: attributes.get("material").map(|m| m.to_string()),
+      }),
+      "sphere" => description.objects.push(ObjectDescription::Sphere {
+        position: attributes.point("position").unwrap_or(Vector::point(0.0, 0.0, 0.0)),
+        radius: attributes.parse("radius").unwrap_or(1.0),
+        material: attributes.get("material").map(|m| m.to_string()),
+      }),
+      "volume" => {
+        let grid = VoxelGrid::load(&directory.join(attributes.required("path")));
+        let grid = match (attributes.point("min"), attributes.point("max")) {
+          (Some(min), Some(max)) => grid.with_bounds(BoundingBox { min, max }),
+          (None, None) => grid,
+          _ => panic!("<volume> needs both 'min' and 'max' to be placed"),
+        };
+        description.objects.push(ObjectDescription::Volume {
+          grid,
+          density: attributes.parse("density").unwrap_or(1.0),
+          colour: attributes.colour("colour").unwrap_or(Colour::RGB(1.0, 1.0, 1.0)),
+        });
+      }
+      "point-light" => {
+        let (intensity, blackbody) = light_power(&attributes, "candela", Some(1.0 / (4.0 * PI)));
+        let position = attributes.point("position").unwrap_or(Vector::point(0.0, 0.0, 0.0));
+        description
+          .lights
+          .push(Box::new(PointLight::new(position, intensity).with_blackbody(blackbody)));
+      }
+      "spot-light" => description.lights.push(Box::new(parse_spot_light(&attributes))),
+      "directional-light" => {
+        let (irradiance, blackbody) = light_power(&attributes, "lux", None);
+        let direction = attributes.vector("direction").unwrap_or(Vector::vector(0.0, -1.0, 0.0));
+        description.lights.push(Box::new(
+          DirectionalLight::new(direction, irradiance).with_blackbody(blackbody),
+        ));
+      }
+      "environment" => {
+        let environment = EnvironmentMap::load(
+          &directory.join(attributes.required("path")),
+          attributes.parse("intensity").unwrap_or(1.0),
+        );
+        description.environment = Some(environment.with_rotation(attributes.parse("rotation").unwrap_or(0.0)));
+      }
+      "sky" => {
+        let sky = PreethamSky::new(
+          attributes.parse("elevation").unwrap_or(45.0),
+          attributes.parse("azimuth").unwrap_or(0.0),
+          attributes.parse("turbidity").unwrap_or(3.0),
+        );
+        let sky = sky.with_intensity(attributes.parse("intensity").unwrap_or(1.0));
+        description.environment = Some(sky.environment_map(attributes.parse("resolution").unwrap_or(1024)));
+      }
+      other => panic!("Unknown scene element <{}> in {:?}", other, path),
+    }
+  }
+  return description;
+}
+
+fn apply_camera(settings: &mut SceneSettings, camera: &CameraDescription) {
+  if let Some(position) = camera.position {
+    settings.camera_position = position;
+  }
+  if let Some(direction) = camera.direction {
+    settings.camera_direction = direction.normalize();
+  }
+  if let Some(target) = camera.target {
+    settings.camera_direction = (target - settings.camera_position).normalize();
+  }
+  if let Some(up) = camera.up {
+    settings.camera_up = up.normalize();
+  }
+  if let Some(fov) = camera.fov {
+    settings.fov = fov;
+  }
+}
+
+fn lookup_material(materials: &HashMap<String, MaterialIdx>, name: &Option<String>) -> Option<MaterialIdx> {
+  return name.as_ref().map(|name| match materials.get(name) {
+    Some(index) => *index,
+    None => panic!("Unknown material '{}'", name),
+  });
+}
+
+/// Loads the scene named by `settings.scene_file`. XML scene descriptions may
+/// override any of the provided settings, so the settings the scene was
+/// actually built with are returned alongside it. Anything else is treated as
+/// a single wavefront OBJ file.
+pub fn load_scene(settings: &SceneSettings) -> (Scene, SceneSettings) {
+  let path = Path::new(&settings.scene_file);
+  let is_xml = match path.extension() {
+    Some(extension) => extension.to_str().unwrap().to_lowercase() == "xml",
+    None => false,
+  };
+  if !is_xml {
+    return (wavefront_material::load_scene(settings), settings.clone());
+  }
+
+  let SceneDescription {
+    mut settings,
+    active_camera,
+    cameras,
+    materials,
+    media,
+    objects,
+    lights,
+    environment,
+  } = parse_scene_description(path, settings);
+
+  let camera = match active_camera {
+    Some(ref name) => match cameras.iter().find(|c| c.name.as_ref() == Some(name)) {
+      Some(camera) => Some(camera),
+      None => panic!("Unknown camera '{}'", name),
+    },
+    None => cameras.first(),
+  };
+  if let Some(camera) = camera {
+    apply_camera(&mut settings, camera);
+  }
+
+  let mut scene = Scene::new(&settings);
+  for medium in media {
+    if let Some(absorption) = medium.absorption {
+      scene.add_media(Box::new(AbsorbingMedia::new(absorption)));
+      continue;
+    }
+    // The points where a medium scatters light are shaded with its colour.
+    let colour = medium.colour;
+    let (material, _) = scene.get_or_create_material(&format!("medium:{}", medium.name), |_| {
+      Some(Box::new(ScatteringMaterial::new(colour)))
+    });
+    scene.add_media(Box::new(HomogenousMedia::new(medium.density, medium.colour, material)));
+  }
+  let mut material_indices = HashMap::new();
+  for (name, material) in materials {
+    let (index, _) = scene.get_or_create_material(&name, |_| Some(material));
+    material_indices.insert(name, index);
+  }
+
+  for object in objects {
+    match object {
+      ObjectDescription::Mesh { path, material } => {
+        let material = lookup_material(&material_indices, &material);
+        wavefront_material::load_obj(&mut scene, &path, material);
+      }
+      ObjectDescription::Sphere {
+        position,
+        radius,
+        material,
+      } => {
+        let material = lookup_material(&material_indices, &material).unwrap_or(scene.default_material());
+        scene.add_object(Box::new(Sphere::new(position, radius, material)));
+      }
+      ObjectDescription::Volume { grid, density, colour } => {
+        // The volume is entered through an invisible box around the grid.
+        let bounds = grid.bounds();
+        let index = scene.medias.len();
+        let (material, _) = scene.get_or_create_material(&format!("volume:{}", index), |_| {
+          Some(Box::new(ScatteringMaterial::new(colour)))
+        });
+        let media = scene.add_media(Box::new(HeterogeneousMedia::new(grid, density, colour, material)));
+        let (boundary, _) = scene.get_or_create_material(&format!("volume-boundary:{}", index), |_| {
+          Some(Box::new(Fog::new(media)))
+        });
+        scene.add_object(Box::new(Mesh::new_box(&bounds, boundary)));
+      }
+    }
+  }
+  for light in lights {
+    scene.add_light(light);
+  }
+  if let Some(environment) = environment {
+    scene.set_environment(environment);
+  }
+  scene.finalize();
+  return (scene, settings);
+}
+
+// Writes `text` to a scene file of its own in the temporary directory, and
+// returns the settings that load it.
+fn test_scene_settings(name: &str, text: &str) -> SceneSettings {
+  let path = std::env::temp_dir().join(name);
+  std::fs::write(&path, text).unwrap();
+  let mut settings = SceneSettings::new();
+  settings.scene_file = path.to_string_lossy().to_string();
+  return settings;
+}
+
+#[test]
+fn test_load_inline_scene() {
+  let settings = test_scene_settings(
+    "test_load_inline_scene.xml",
+    r#"<scene camera="front">
+  <settings width="64" height="32" samples-per-pixel="0" photon-count="1000"/>
+  <camera name="side" position="5,1,0" target="0,1,0" fov="90"/>
+  <camera name="front" position="0,1,3" target="0,1,0" fov="40"/>
+  <material name="lamp" type="emissive" colour="1,1,1" intensity="2"/>
+  <material name="wall" type="diffuse" colour="0.5,0.5,0.5"/>
+  <sphere position="0,1,0" radius="0.5" material="lamp"/>
+  <sphere position="0,-10,0" radius="9" material="wall"/>
+</scene>"#,
+  );
+  let (scene, settings) = load_scene(&settings);
+  assert_eq!(settings.width, 64);
+  assert_eq!(settings.height, 32);
+  assert_eq!(settings.samples_per_pixel, 1);
+  assert_eq!(settings.photon_count, 1000);
+  // The scene's camera is used rather than the first one.
+  assert_eq!(settings.fov, 40.0);
+  assert!((settings.camera_position - Vector::point(0.0, 1.0, 3.0)).length() < 1e-6);
+  assert!((settings.camera_direction - Vector::vector(0.0, 0.0, -1.0)).length() < 1e-6);
+
+  // Objects get the materials they name.
+  let material_at = |origin: Point, direction: Vector| {
+    let ray = crate::ray::Ray::new(origin, direction, None);
+    let (collision, shadable) = scene.intersect(&ray).unwrap();
+    return shadable.compute_fragment(&scene, &ray, &collision).material;
+  };
+  let lamp = material_at(settings.camera_position, settings.camera_direction);
+  assert!(scene.get_material(lamp).is_light());
+  let wall = material_at(Vector::point(2.0, 1.0, 0.0), Vector::vector(0.0, -1.0, 0.0));
+  assert!(!scene.get_material(wall).is_light());
+}
+
+#[test]
+#[should_panic(expected = "Unknown scene element <teapot>")]
+fn test_unknown_element() {
+  let settings = test_scene_settings(
+    "test_unknown_element.xml",
+    r#"<scene><teapot position="0,0,0"/></scene>"#,
+  );
+  load_scene(&settings);
+}
+
+#[test]
+#[should_panic(expected = "<material> is missing the 'name' attribute")]
+fn test_material_without_name() {
+  let settings = test_scene_settings(
+    "test_material_without_name.xml",
+    r#"<scene><material type="diffuse" colour="1,1,1"/></scene>"#,
+  );
+  load_scene(&settings);
+}
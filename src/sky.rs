@@ -0,0 +1,215 @@
+use crate::environment::EnvironmentMap;
+use crate::vectors::{Vector, VectorType};
+use std::f32::consts::PI;
+
+// The angular radius of the sun.
+const SUN_RADIUS: f32 = 0.004_65;
+// The luminance of the sun outside the atmosphere, in the sky's units of
+// kcd/m^2.
+const SUN_LUMINANCE: f32 = 1.6e6;
+// The wavelengths, in micrometres, that the red, green and blue transmittance
+// of the atmosphere are evaluated at.
+const WAVELENGTHS: [f32; 3] = [0.680, 0.550, 0.440];
+// Pixels near the sun are supersampled this many times along each axis to
+// find how much of them the disc covers.
+const SUN_SUPERSAMPLES: usize = 8;
+
+// The Perez et al. sky luminance distribution, relative to the zenith.
+fn perez(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+  let [a, b, c, d, e] = *coefficients;
+  let cos_gamma = gamma.cos();
+  return (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma);
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vector {
+  if y <= 0.0 {
+    return Vector::new();
+  }
+  let cie_x = x / y * luminance;
+  let cie_z = (1.0 - x - y) / y * luminance;
+  let r = 3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z;
+  let g = -0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z;
+  let b = 0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z;
+  return Vector::vector(r.max(0.0) as f64, g.max(0.0) as f64, b.max(0.0) as f64);
+}
+
+/// The Preetham, Shirley and Smits analytic daylight model, with a sun disc
+/// dimmed and reddened by the same atmosphere. Radiance is in kcd/m^2, as in
+/// the paper, so an intensity of a few hundredths brings sunlit surfaces to
+/// around one.
+///
+/// The sun's azimuth is measured in degrees from -z towards +x, matching the
+/// centre of an unrotated environment map. Nothing is emitted from below the
+/// horizon.
+#[derive(Debug, Clone)]
+pub struct PreethamSky {
+  sun_direction: Vector,
+  sun_theta: f32,
+  // The Perez coefficients, and the values at the zenith, for the luminance
+  // and the two chromaticity coordinates.
+  coefficients: [[f32; 5]; 3],
+  zenith: [f32; 3],
+  sun_radiance: Vector,
+  intensity: f32,
+}
+
+impl PreethamSky {
+  /// Turbidity ranges from 2 for a very clear sky to around 10 for haze.
+  pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Self {
+    // The model falls apart once the sun sets.
+    let elevation = elevation.max(0.5).min(90.0) * PI / 180.0;
+    let azimuth = azimuth * PI / 180.0;
+    let t = turbidity.max(1.7).min(10.0);
+    let sun_theta = PI / 2.0 - elevation;
+    let sun_direction = Vector::vector(
+      (elevation.cos() * azimuth.sin()) as f64,
+      elevation.sin() as f64,
+      (-elevation.cos() * azimuth.cos()) as f64,
+    );
+
+    let coefficients = [
+      [
+        0.1787 * t - 1.4630,
+        -0.3554 * t + 0.4275,
+        -0.0227 * t + 5.3251,
+        0.1206 * t - 2.5771,
+        -0.0670 * t + 0.3703,
+      ],
+      [
+        -0.0193 * t - 0.2592,
+        -0.0665 * t + 0.0008,
+        -0.0004 * t + 0.2125,
+        -0.0641 * t - 0.8989,
+        -0.0033 * t + 0.0452,
+      ],
+      [
+        -0.0167 * t - 0.2608,
+        -0.0950 * t + 0.0092,
+        -0.0079 * t + 0.2102,
+        -0.0441 * t - 1.6537,
+        -0.0109 * t + 0.0529,
+      ],
+    ];
+
+    let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_theta);
+    let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let (theta2, theta3) = (sun_theta * sun_theta, sun_theta * sun_theta * sun_theta);
+    let chromaticity = |m: [[f32; 4]; 3]| {
+      let row = |r: [f32; 4]| r[0] * theta3 + r[1] * theta2 + r[2] * sun_theta + r[3];
+      return t * t * row(m[0]) + t * row(m[1]) + row(m[2]);
+    };
+    let zenith_x = chromaticity([
+      [0.00166, -0.00375, 0.00209, 0.0],
+      [-0.02903, 0.06377, -0.03202, 0.00394],
+      [0.11693, -0.21196, 0.06052, 0.25886],
+    ]);
+    let zenith_y = chromaticity([
+      [0.00275, -0.00610, 0.00317, 0.0],
+      [-0.04214, 0.08970, -0.04153, 0.00516],
+      [0.15346, -0.26756, 0.06670, 0.26688],
+    ]);
+
+    // Rayleigh scattering and Angstrom's aerosol turbidity formula, through
+    // the relative optical mass of air at the sun's elevation.
+    let optical_mass = 1.0 / (sun_theta.cos() + 0.15 * (93.885 - sun_theta * 180.0 / PI).max(0.001).powf(-1.253));
+    let beta = 0.046_083_66 * t - 0.045_860_26;
+    let transmittance = |wavelength: f32| {
+      let rayleigh = 0.008_735 * wavelength.powf(-4.08);
+      let aerosol = beta * wavelength.powf(-1.3);
+      return (-(rayleigh + aerosol) * optical_mass).exp() * SUN_LUMINANCE;
+    };
+    let sun_radiance = Vector::vector(
+      transmittance(WAVELENGTHS[0]) as f64,
+      transmittance(WAVELENGTHS[1]) as f64,
+      transmittance(WAVELENGTHS[2]) as f64,
+    );
+
+    return PreethamSky {
+      sun_direction,
+      sun_theta,
+      coefficients,
+      zenith: [zenith_luminance, zenith_x, zenith_y],
+      sun_radiance,
+      intensity: 1.0,
+    };
+  }
+
+  /// Scales the radiance of the sky and sun.
+  pub fn with_intensity(mut self, intensity: f32) -> Self {
+    self.intensity = intensity;
+    return self;
+  }
+
+  /// The radiance of the sky, without the sun disc.
+  pub fn sky_radiance(&self, direction: Vector) -> Vector {
+    let direction = direction.normalize();
+    if direction.y() <= 0.0 {
+      return Vector::new();
+    }
+    let cos_theta = direction.y().max(0.001);
+    let gamma = direction.dot(self.sun_direction).max(-1.0).min(1.0).acos();
+    let value = |channel: usize| {
+      let coefficients = &self.coefficients[channel];
+      return self.zenith[channel] * perez(coefficients, cos_theta, gamma) / perez(coefficients, 1.0, self.sun_theta);
+    };
+    return xyy_to_rgb(value(1), value(2), value(0)) * self.intensity;
+  }
+
+  /// The radiance arriving from the direction, including the sun.
+  pub fn radiance(&self, direction: Vector) -> Vector {
+    let mut radiance = self.sky_radiance(direction);
+    if direction.normalize().dot(self.sun_direction) >= SUN_RADIUS.cos() {
+      radiance = radiance + self.sun_radiance * self.intensity;
+    }
+    return radiance;
+  }
+
+  /// Tabulates the sky as an environment map `width` pixels wide, so that it
+  /// can be importance sampled. The sun covers only a few pixels, so they're
+  /// supersampled to keep its power.
+  pub fn environment_map(&self, width: usize) -> EnvironmentMap {
+    let width = width.max(2);
+    let height = width / 2;
+    let pixel_angle = PI / height as f32;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+      for x in 0..width {
+        let direction =
+          EnvironmentMap::direction_for_coordinates((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32);
+        // Only pixels the sun could overlap need to be supersampled.
+        let sun_angle = direction.dot(self.sun_direction).max(-1.0).min(1.0).acos();
+        if sun_angle > SUN_RADIUS + pixel_angle * 2.0 {
+          pixels.push(self.sky_radiance(direction));
+          continue;
+        }
+        let mut total = Vector::new();
+        for sy in 0..SUN_SUPERSAMPLES {
+          for sx in 0..SUN_SUPERSAMPLES {
+            let u = (x as f32 + (sx as f32 + 0.5) / SUN_SUPERSAMPLES as f32) / width as f32;
+            let v = (y as f32 + (sy as f32 + 0.5) / SUN_SUPERSAMPLES as f32) / height as f32;
+            total = total + self.radiance(EnvironmentMap::direction_for_coordinates(u, v));
+          }
+        }
+        pixels.push(total * (1.0 / (SUN_SUPERSAMPLES * SUN_SUPERSAMPLES) as f32));
+      }
+    }
+    return EnvironmentMap::new(width, height, pixels);
+  }
+}
+
+#[test]
+fn test_preetham_sky() {
+  let sky = PreethamSky::new(45.0, 90.0, 3.0);
+  // The Perez function cancels out at the zenith, leaving its luminance.
+  let zenith = sky.sky_radiance(Vector::vector(0.0, 1.0, 0.0));
+  let luminance = 0.2126 * zenith.x() + 0.7152 * zenith.y() + 0.0722 * zenith.z();
+  assert!((luminance / sky.zenith[0] - 1.0).abs() < 0.01);
+  // A clear sky is blue, and dark below the horizon.
+  assert!(zenith.z() > zenith.x());
+  assert_eq!(sky.radiance(Vector::vector(0.0, -1.0, 0.0)), Vector::new());
+
+  // The sun is towards +x, and far brighter than the sky next to it.
+  let sun = Vector::vector(1.0, 1.0, 0.0).normalize();
+  let near_sun = Vector::vector(1.0, 1.1, 0.0).normalize();
+  assert!(sky.radiance(sun).x() > sky.radiance(near_sun).x() * 1000.0);
+}
@@ -0,0 +1,317 @@
+use crate::colour::Colour;
+
+// Spectral rendering carries a few wavelengths along each path rather than
+// red, green and blue. Colours from textures and materials are upsampled to
+// smooth spectra made from three overlapping basis functions, and whatever a
+// path gathers is projected back to linear sRGB through the CIE colour
+// matching functions, so that an RGB colour comes back as itself on average.
+
+/// The range of wavelengths, in nanometres, that are sampled.
+pub const MIN_WAVELENGTH: f32 = 380.0;
+pub const MAX_WAVELENGTH: f32 = 730.0;
+/// The sodium D line that indices of refraction are usually quoted at.
+pub const D_LINE: f32 = 587.6;
+// The Fraunhofer F and C lines that Abbe numbers are measured between.
+const F_LINE: f32 = 486.1;
+const C_LINE: f32 = 656.3;
+// The second radiation constant of Planck's law, hc/k, in micrometre kelvins.
+const SECOND_RADIATION_CONSTANT: f32 = 14_387.77;
+// The lumens per watt of light at the peak of the eye's response.
+const MAX_LUMINOUS_EFFICACY: f32 = 683.0;
+
+// The inverse of the matrix that takes the basis weights of a spectrum to
+// sRGB, averaged over the sampled range. Projecting the spectrum of a colour
+// through rgb_response gives back that colour.
+const RESPONSE_NORMALISATION: [[f32; 3]; 3] = [
+  [2.74839, -0.23392, 0.21596],
+  [-0.61714, 4.56492, -0.35367],
+  [0.12456, -0.01855, 3.46104],
+];
+
+fn lobe(wavelength: f32, mean: f32, below: f32, above: f32) -> f32 {
+  let t = (wavelength - mean) / if wavelength < mean { below } else { above };
+  return (-0.5 * t * t).exp();
+}
+
+/// The CIE 1931 colour matching functions, using the multi-lobe fit of
+/// Wyman, Sloan and Shirley.
+pub fn colour_matching(wavelength: f32) -> (f32, f32, f32) {
+  let x = 1.056 * lobe(wavelength, 599.8, 37.9, 31.0) + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7)
+    - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2);
+  let y = 0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1);
+  let z = 1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8);
+  return (x, y, z);
+}
+
+/// Converts CIE XYZ to linear sRGB.
+pub fn xyz_to_rgb(x: f32, y: f32, z: f32) -> Colour {
+  return Colour::RGB(
+    3.2406 * x - 1.5372 * y - 0.4986 * z,
+    -0.9689 * x + 1.8758 * y + 0.0415 * z,
+    0.0557 * x - 0.2040 * y + 1.0570 * z,
+  );
+}
+
+// The red, green and blue basis spectra at the wavelength. They sum to one,
+// so white upsamples to a flat spectrum.
+fn basis(wavelength: f32) -> [f32; 3] {
+  let r = if wavelength >= 610.0 {
+    1.0
+  } else {
+    lobe(wavelength, 610.0, 35.0, 35.0)
+  };
+  let g = lobe(wavelength, 545.0, 35.0, 35.0);
+  let b = if wavelength <= 455.0 {
+    1.0
+  } else {
+    lobe(wavelength, 455.0, 35.0, 35.0)
+  };
+  let total = r + g + b;
+  return [r / total, g / total, b / total];
+}
+
+// How much a unit of energy at the wavelength adds to each sRGB channel.
+fn rgb_response(wavelength: f32) -> [f32; 3] {
+  let (x, y, z) = colour_matching(wavelength);
+  let rgb = xyz_to_rgb(x, y, z);
+  let rgb = [rgb.r(), rgb.g(), rgb.b()];
+  let mut response = [0.0; 3];
+  for (channel, row) in RESPONSE_NORMALISATION.iter().enumerate() {
+    response[channel] = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+  }
+  return response;
+}
+
+/// The wavelengths a path carries, stored in the channels of the colours it
+/// works with. The first is the hero wavelength, which decides the path
+/// through anything dispersive.
+#[derive(Debug, Clone, Copy)]
+pub struct Wavelengths {
+  wavelengths: [f32; 3],
+  // Whether the path has been through something dispersive, after which
+  // only the hero is left, see hero_only.
+  dispersed: bool,
+}
+
+impl Wavelengths {
+  /// Three wavelengths spread evenly over the visible range, offset by the
+  /// uniform sample `u`.
+  pub fn sample(u: f32) -> Wavelengths {
+    let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+    let at = |offset: f32| MIN_WAVELENGTH + ((u + offset) % 1.0) * range;
+    return Wavelengths {
+      wavelengths: [at(0.0), at(1.0 / 3.0), at(2.0 / 3.0)],
+      dispersed: false,
+    };
+  }
+
+  pub fn hero(&self) -> f32 {
+    return self.wavelengths[0];
+  }
+
+  /// The wavelengths of a path that dispersion has left with only its hero.
+  pub fn dispersed(&self) -> Wavelengths {
+    return Wavelengths {
+      wavelengths: self.wavelengths,
+      dispersed: true,
+    };
+  }
+
+  pub fn is_dispersed(&self) -> bool {
+    return self.dispersed;
+  }
+
+  /// The spectrum of an RGB colour at each of the wavelengths.
+  pub fn upsample(&self, colour: Colour) -> Colour {
+    let value = |wavelength: f32| {
+      let [r, g, b] = basis(wavelength);
+      return colour.r() * r + colour.g() * g + colour.b() * b;
+    };
+    let [first, second, third] = self.wavelengths;
+    return Colour::RGB(value(first), value(second), value(third));
+  }
+
+  /// Projects the values carried at each wavelength back to RGB.
+  pub fn to_rgb(&self, values: Colour) -> Colour {
+    let values = [values.r(), values.g(), values.b()];
+    let mut rgb = [0.0; 3];
+    for (wavelength, value) in self.wavelengths.iter().zip(values.iter()) {
+      let response = rgb_response(*wavelength);
+      for channel in 0..3 {
+        rgb[channel] += value * response[channel] / 3.0;
+      }
+    }
+    return Colour::RGB(rgb[0], rgb[1], rgb[2]);
+  }
+
+  /// What's left of `values` once dispersion has split the wavelengths up,
+  /// and only the hero follows the path. It carries the others' share, so
+  /// this must only happen once along a path.
+  pub fn hero_only(values: Colour) -> Colour {
+    return Colour::RGB(values.r() * 3.0, 0.0, 0.0);
+  }
+}
+
+// Planck's law, without its constant factor, at a wavelength in nanometres.
+fn planck(wavelength: f32, temperature: f32) -> f32 {
+  let wavelength = wavelength / 1000.0;
+  let exponent = SECOND_RADIATION_CONSTANT / (wavelength * temperature);
+  return 1.0 / (wavelength.powi(5) * exponent.exp_m1());
+}
+
+/// The light of an ideal black body at a temperature in Kelvin, such as an
+/// incandescent lamp. Its colour is scaled to a luminance of one, and its
+/// spectrum to match.
+#[derive(Debug, Clone, Copy)]
+pub struct Blackbody {
+  temperature: f32,
+  scale: f32,
+  colour: Colour,
+}
+
+impl Blackbody {
+  pub fn new(temperature: f32) -> Blackbody {
+    // Anything cooler barely glows.
+    let temperature = temperature.max(500.0);
+    let steps = 350;
+    let step = (MAX_WAVELENGTH - MIN_WAVELENGTH) / steps as f32;
+    let mut rgb = [0.0; 3];
+    for i in 0..steps {
+      let wavelength = MIN_WAVELENGTH + (i as f32 + 0.5) * step;
+      let value = planck(wavelength, temperature);
+      let response = rgb_response(wavelength);
+      for channel in 0..3 {
+        rgb[channel] += value * response[channel] / steps as f32;
+      }
+    }
+    let scale = 1.0 / Colour::RGB(rgb[0], rgb[1], rgb[2]).luminance();
+    // Cool bodies are redder than sRGB can show, so their colour is clipped
+    // to it, keeping its luminance.
+    let colour = Colour::RGB(rgb[0].max(0.0), rgb[1].max(0.0), rgb[2].max(0.0));
+    return Blackbody {
+      temperature,
+      scale,
+      colour: colour * (1.0 / colour.luminance()),
+    };
+  }
+
+  /// The linear sRGB colour of the light.
+  pub fn colour(&self) -> Colour {
+    return self.colour;
+  }
+
+  /// The spectrum at each of the wavelengths, which projects back to the
+  /// colour on average.
+  pub fn spectrum(&self, wavelengths: &Wavelengths) -> Colour {
+    let [first, second, third] = wavelengths.wavelengths;
+    let value = |wavelength: f32| planck(wavelength, self.temperature) * self.scale;
+    return Colour::RGB(value(first), value(second), value(third));
+  }
+
+  /// The lumens for each watt of power radiated.
+  pub fn luminous_efficacy(&self) -> f32 {
+    // Planck's law integrates to T⁴π⁴/15 over every wavelength.
+    let total = (self.temperature / SECOND_RADIATION_CONSTANT).powi(4) * std::f32::consts::PI.powi(4) / 15.0;
+    let steps = 350;
+    let step = (MAX_WAVELENGTH - MIN_WAVELENGTH) / steps as f32;
+    let mut visible = 0.0;
+    for i in 0..steps {
+      let wavelength = MIN_WAVELENGTH + (i as f32 + 0.5) * step;
+      let (_, y, _) = colour_matching(wavelength);
+      visible += planck(wavelength, self.temperature) * y * step / 1000.0;
+    }
+    return MAX_LUMINOUS_EFFICACY * visible / total;
+  }
+}
+
+/// How the index of refraction of a dielectric varies with wavelength, with
+/// wavelengths measured in micrometres as they are in glass catalogues.
+#[derive(Debug, Clone, Copy)]
+pub enum Dispersion {
+  /// n = a + b / λ²
+  Cauchy { a: f32, b: f32 },
+  /// n² = 1 + Σ b λ² / (λ² - c)
+  Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+  /// The Cauchy dispersion of a glass with index `ior` at the D line, and the
+  /// given Abbe number. Crown glasses are around 60, and dense flints 30.
+  pub fn from_abbe(ior: f32, abbe: f32) -> Dispersion {
+    let (f, c, d) = (F_LINE / 1000.0, C_LINE / 1000.0, D_LINE / 1000.0);
+    let b = (ior - 1.0) / (abbe.max(1.0) * (1.0 / (f * f) - 1.0 / (c * c)));
+    return Dispersion::Cauchy {
+      a: ior - b / (d * d),
+      b,
+    };
+  }
+
+  /// The index of refraction at a wavelength in nanometres.
+  pub fn ior(&self, wavelength: f32) -> f32 {
+    let l2 = (wavelength / 1000.0) * (wavelength / 1000.0);
+    return match self {
+      Dispersion::Cauchy { a, b } => a + b / l2,
+      Dispersion::Sellmeier { b, c } => {
+        let sum: f32 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+        (1.0 + sum).max(1.0).sqrt()
+      }
+    };
+  }
+}
+
+#[test]
+fn test_spectral_round_trip() {
+  // Averaged over many sets of wavelengths, a colour comes back as itself.
+  let colour = Colour::RGB(0.8, 0.3, 0.1);
+  let count = 3000;
+  let mut total = Colour::new();
+  for i in 0..count {
+    let wavelengths = Wavelengths::sample((i as f32 + 0.5) / count as f32);
+    total = total + wavelengths.to_rgb(wavelengths.upsample(colour));
+  }
+  let total = total * (1.0 / count as f32);
+  assert!((total.r() - 0.8).abs() < 0.01, "{:?}", total);
+  assert!((total.g() - 0.3).abs() < 0.01, "{:?}", total);
+  assert!((total.b() - 0.1).abs() < 0.01, "{:?}", total);
+
+  // BK7, from its Sellmeier coefficients, and a Cauchy fit to it.
+  let bk7 = Dispersion::Sellmeier {
+    b: [1.039_612_1, 0.231_792_34, 1.010_469_5],
+    c: [0.006_000_699, 0.020_017_914, 103.560_65],
+  };
+  assert!((bk7.ior(D_LINE) - 1.5168).abs() < 0.001);
+  let fit = Dispersion::from_abbe(1.5168, 64.17);
+  assert!((fit.ior(D_LINE) - 1.5168).abs() < 0.0001);
+  assert!((fit.ior(F_LINE) - bk7.ior(F_LINE)).abs() < 0.001);
+  assert!(fit.ior(400.0) > fit.ior(700.0));
+}
+
+#[test]
+fn test_blackbody() {
+  // Warm light is orange, and hot stars are blue. White is equal energy at
+  // every wavelength, which a black body is closest to around 5500K.
+  let lamp = Blackbody::new(2700.0).colour();
+  assert!(lamp.r() > lamp.g() && lamp.g() > lamp.b(), "{:?}", lamp);
+  assert!((lamp.luminance() - 1.0).abs() < 0.001);
+  let white = Blackbody::new(5500.0).colour();
+  assert!((white.r() / white.b() - 1.0).abs() < 0.1, "{:?}", white);
+  let star = Blackbody::new(15000.0).colour();
+  assert!(star.b() > star.r(), "{:?}", star);
+
+  // The spectrum averages to the colour.
+  let count = 3000;
+  let mut total = Colour::new();
+  for i in 0..count {
+    let wavelengths = Wavelengths::sample((i as f32 + 0.5) / count as f32);
+    total = total + wavelengths.to_rgb(Blackbody::new(2700.0).spectrum(&wavelengths));
+  }
+  let total = total * (1.0 / count as f32);
+  assert!((total.r() - lamp.r()).abs() < 0.01, "{:?} {:?}", total, lamp);
+  assert!((total.b() - lamp.b()).abs() < 0.01, "{:?} {:?}", total, lamp);
+
+  // An incandescent filament manages about 15 lumens per watt, the sun 90.
+  let efficacy = Blackbody::new(2700.0).luminous_efficacy();
+  assert!(efficacy > 12.0 && efficacy < 17.0, "{}", efficacy);
+  let efficacy = Blackbody::new(5800.0).luminous_efficacy();
+  assert!(efficacy > 85.0 && efficacy < 100.0, "{}", efficacy);
+}
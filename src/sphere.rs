@@ -1,5 +1,6 @@
 use crate::fragment::Fragment;
-use crate::photon_map::random;
+use crate::sampling::uniform_sphere;
+use crate::light::Emitter;
 use crate::light::LightSample;
 use crate::shader::Shadable;
 use crate::collision::Collision;
@@ -14,8 +15,9 @@ use crate::intersectable::*;
 use crate::vectors::Vector;
 use crate::vectors::VectorType;
 use crate::vectors::Vec2d;
+use std::f32::consts::PI;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Sphere {
   position: Point,
   radius: f32,
@@ -33,7 +35,6 @@ impl HasBoundingBox for Sphere {
 }
 
 impl Sphere {
-  #[allow(dead_code)]
   pub fn new(position: Point, radius: f32, material: MaterialIdx) -> Self {
     return Sphere {
       position,
@@ -55,10 +56,14 @@ impl Sphere {
 
     let collision_to_tangent = (self.radius * self.radius - radius_at_tangent_point * radius_at_tangent_point).sqrt();
 
-    let true_distance = if origin_to_center.length() < self.radius {
-      distance_to_tangent_point + collision_to_tangent
+    // Take the far intersection whenever the near one is out of range, rather
+    // than deciding from the origin, which is ambiguous for rays that start on
+    // the surface.
+    let near_distance = distance_to_tangent_point - collision_to_tangent;
+    let true_distance = if near_distance >= min {
+      near_distance
     } else {
-      distance_to_tangent_point - collision_to_tangent
+      distance_to_tangent_point + collision_to_tangent
     };
 
     if true_distance < min {
@@ -103,22 +108,23 @@ impl Shadable for Sphere {
       dpdv,
       dpdu,
       view: ray.direction,
+      ray_context: ray.ray_context.clone(),
     };
   }
 }
 impl Light for Sphere {
   fn get_area(&self) -> f32 {
-    return 4.0 * 3.1412 * self.radius * self.radius;
+    return 4.0 * PI * self.radius * self.radius;
+  }
+
+  fn copy_surface(&self) -> Option<(Box<Light>, BoundingBox)> {
+    return Some((Box::new(self.clone()), self.bounds()));
   }
 
   fn get_samples(&self, count: usize, scene: &Scene) -> Vec<LightSample> {
     let mut result = vec![];
     while result.len() < count {
-      let light_dir = {
-        let u = random(0.0, 1.0);
-        let v = 2.0 * 3.14127 * random(0.0, 1.0);
-        Vector::vector(v.cos() * u.sqrt(), -(1.0 - u).sqrt(), v.sin() * u.sqrt())
-      };
+      let light_dir = uniform_sphere();
 
       let position = self.position + light_dir * self.radius;
 
@@ -128,6 +134,9 @@ impl Light for Sphere {
 
       let material = scene.get_material(fragment.material);
       let surface = material.compute_surface_properties(scene, &ray, &fragment);
+      let radiance = Vector::from(surface.emitted_colour().unwrap());
+      let mut emission = surface.emissive_colour.unwrap();
+      emission.diffuse *= self.get_area();
 
       result.push(LightSample {
         position: position,
@@ -135,9 +144,11 @@ impl Light for Sphere {
         specular: Vector::from(surface.specular_colour),
         diffuse: Vector::from(surface.diffuse_colour),
         ambient: Vector::from(surface.ambient_colour),
-        emission: surface.emissive_colour.unwrap(),
+        emission,
+        radiance,
+        area: self.get_area() / count as f32,
         weight: (1.0 / count as f32),
-        power: 1.0,
+        emitter: Emitter::Area,
       });
     }
     return result;
@@ -145,10 +156,10 @@ impl Light for Sphere {
 }
 
 impl Intersectable for Sphere {
-  fn get_lights<'a>(&'a self, _s: &Scene) -> Vec<&'a Light> {
-    // if s.get_material(self.material).is_light() {
-    //   return vec![self];
-    // }
+  fn get_lights<'a>(&'a self, s: &Scene) -> Vec<&'a Light> {
+    if s.get_material(self.material).is_light() {
+      return vec![self];
+    }
     return vec![];
   }
   fn intersect<'a>(&'a self, ray: &Ray, _: HitMode, min: f32, max: f32) -> Option<(Collision, &'a Shadable)> {
@@ -1,5 +1,6 @@
 use crate::light::Light;
 use crate::light::LightSample;
+use crate::light::Emitter;
 use crate::photon_map::random;
 use crate::bounding_box::*;
 use crate::collision::Collision;
@@ -35,6 +36,9 @@ impl Light for Triangle {
   fn get_area(&self) -> f32 {
     return self.edges[0].cross(self.edges[1]).length() / 2.0;
   }
+  fn copy_surface(&self) -> Option<(Box<Light>, BoundingBox)> {
+    return Some((Box::new(*self), self.bounds()));
+  }
   fn get_samples(&self, count: usize, scene: &Scene) -> Vec<LightSample> {
     let mut lights: Vec<LightSample> = vec![];
     while lights.len() < count {
@@ -66,6 +70,7 @@ impl Light for Triangle {
 
       let material = scene.get_material(fragment.material);
       let surface = material.compute_surface_properties(scene, &ray, &fragment);
+      let radiance = Vector::from(surface.emitted_colour().unwrap());
       let mut emission = surface.emissive_colour.unwrap();
       emission.diffuse *= self.get_area();
       let sample = LightSample {
@@ -75,8 +80,10 @@ impl Light for Triangle {
         diffuse: Vector::from(surface.diffuse_colour),
         ambient: Vector::from(surface.ambient_colour),
         emission,
+        radiance,
+        area: self.get_area() / count as f32,
         weight: (1.0 / count as f32),
-        power: 1.0,
+        emitter: Emitter::Area,
       };
       lights.push(sample);
     }
@@ -167,6 +174,7 @@ impl Shadable for Triangle {
       dpdv: dpdv,
       view: r.direction,
       material: self.material,
+      ray_context: r.ray_context.clone(),
     };
   }
 }
@@ -5,20 +5,30 @@ use crate::scene::SceneSettings;
 use crate::colour::Colour;
 use crate::fragment::Fragment;
 use genmesh::*;
+use crate::bsdf::{fresnel_schlick, Bsdf, Lobe};
 use crate::material::MaterialCollisionInfo;
+use crate::microfacet::roughness_from_exponent;
 use crate::material::{Material, Transparency};
+use crate::media::AbsorbingMedia;
 use obj::{IndexTuple, Obj};
 use crate::objects::Mesh;
-use crate::ray::Ray;
+use crate::ray::{Interior, Ray};
+use crate::scene::MediaIdx;
 use crate::scene::NormalIdx;
 use crate::scene::Scene;
 use crate::scene::TextureIdx;
+use std::collections::HashMap;
 use std::path::Path;
 use crate::texture::TextureCoordinateIdx;
 use crate::triangle::Triangle;
+use crate::spectrum::Dispersion;
 use crate::sphere::Sphere;
 use crate::vectors::*;
 
+// MTL files don't describe dispersion, so in spectral mode glass with an
+// index of refraction disperses like a light flint glass.
+const DEFAULT_ABBE_NUMBER: f32 = 40.0;
+
 trait RawSurfaceValue: Clone + Clone + Copy {
   type RawType;
   fn empty() -> Self;
@@ -53,6 +63,8 @@ impl RawSurfaceValue for EmissionCoefficients {
       ambient: 0.0,
       diffuse: 0.0,
       specular: 0.0,
+      blackbody: None,
+      colour: None,
     };
   }
   fn from_array(array: Option<[f32; 3]>) -> Option<Self> {
@@ -64,6 +76,8 @@ impl RawSurfaceValue for EmissionCoefficients {
         ambient: a,
         diffuse: d,
         specular: s,
+        blackbody: None,
+        colour: None,
       });
     }
     return None;
@@ -72,6 +86,24 @@ impl RawSurfaceValue for EmissionCoefficients {
     return self.diffuse.max(self.ambient.max(self.specular));
   }
 }
+impl RawSurfaceValue for f32 {
+  type RawType = Self;
+  fn empty() -> Self {
+    return 0.0;
+  }
+  fn from_array(array: Option<[f32; 3]>) -> Option<Self> {
+    if let Some([value, _, _]) = array {
+      if value == 0.0 {
+        return None;
+      }
+      return Some(value);
+    }
+    return None;
+  }
+  fn max_value(&self) -> f32 {
+    return *self;
+  }
+}
 /*
 impl<T: RawSurfaceValue> RawSurfaceValue for Option<T> {
   type RawType = T;
@@ -103,6 +135,8 @@ impl TextureSurfaceValue<EmissionCoefficients> for TextureIdx {
       ambient: colour.r(),
       diffuse: colour.g(),
       specular: colour.b(),
+      blackbody: None,
+      colour: None,
     };
   }
   fn gradient(&self, s: &Scene, uv: Vec2d) -> (f64, f64) {
@@ -110,6 +144,17 @@ impl TextureSurfaceValue<EmissionCoefficients> for TextureIdx {
   }
 }
 
+// Scalar maps such as roughness are greyscale, so the channels are averaged.
+impl TextureSurfaceValue<f32> for TextureIdx {
+  fn raw_for_fragment(&self, s: &Scene, f: &Fragment) -> f32 {
+    let colour = s.get_texture(*self).sample(f.uv);
+    return (colour.r() + colour.g() + colour.b()) / 3.0;
+  }
+  fn gradient(&self, s: &Scene, uv: Vec2d) -> (f64, f64) {
+    return s.get_texture(*self).gradient(uv);
+  }
+}
+
 #[derive(Debug, Copy, Clone)]
 enum WFSurfaceProperty<Raw: Copy + RawSurfaceValue, Texture: Copy + TextureSurfaceValue<Raw>> {
   None,
@@ -136,10 +181,18 @@ impl MergeValues for EmissionCoefficients {
       ambient: self.ambient * other.ambient,
       diffuse: self.diffuse * other.diffuse,
       specular: self.specular * other.specular,
+      blackbody: self.blackbody.or(other.blackbody),
+      colour: self.colour.or(other.colour),
     }
   }
 }
 
+impl MergeValues for f32 {
+  fn merge(&self, other: Self) -> Self {
+    return self * other;
+  }
+}
+
 impl<Raw: Copy + RawSurfaceValue + MergeValues, Texture: Copy + TextureSurfaceValue<Raw>>
   WFSurfaceProperty<Raw, Texture>
 {
@@ -183,6 +236,9 @@ pub struct WFMaterial {
   sharpness: Option<f32>,
   index_of_refraction: Option<f32>, // Ni
   illumination_model: usize,
+  // Glass that Tf tints is filled with a medium that absorbs the light
+  // passing through it.
+  media: Option<MediaIdx>,
 }
 
 fn perturb_normal(bump: Option<TextureIdx>, f: &Fragment, s: &Scene) -> Vector {
@@ -227,19 +283,56 @@ impl Material for WFMaterial {
       emissive_colour: self.emissive_colour.option_for_fragment(s, f),
       normal: normal,
       position: f.position,
-      reflectivity: None,
-      transparent_colour: None,
-      index_of_refraction: None,
-      media_transition: None,
+      interior: None,
+      bsdf: Bsdf::new(normal, f.true_normal),
     };
 
     if self.illumination_model == 5 {
-      result.reflectivity = Some((1.0, result.specular_colour));
+      result.bsdf = result.bsdf.with_lobe(Lobe::Mirror(result.specular_colour));
       return result;
     }
-    if let Transparency::Opaque = self.transparency {
-      result.index_of_refraction = self.index_of_refraction.map(|ior| (ior, 1.0));
-      result.transparent_colour = self.transparent_colour;
+    let transparent_colour = match self.transparency {
+      Transparency::Opaque => self.transparent_colour,
+      _ => None,
+    };
+    // Transparent materials transmit one minus Tf, and without an index of
+    // refraction light passes straight through them. When they're filled
+    // with a medium, that does the tinting instead of the surface.
+    if let Some(transparent_colour) = transparent_colour {
+      let transmission = match self.media {
+        Some(_) => Colour::RGB(1.0, 1.0, 1.0),
+        None => Colour::RGB(1.0, 1.0, 1.0) - transparent_colour,
+      };
+      let ior = match (self.index_of_refraction, f.ray_context.wavelengths) {
+        (Some(ior), Some(wavelengths)) => Some(Dispersion::from_abbe(ior, DEFAULT_ABBE_NUMBER).ior(wavelengths.hero())),
+        (ior, _) => ior,
+      };
+      let interior = Interior {
+        material: f.material,
+        priority: 0,
+        ior,
+        media: self.media,
+      };
+      let (inside_ior, outside_ior) = f.ray_context.interface_iors(interior);
+      result.interior = Some(interior);
+      result.bsdf = result.bsdf.with_lobe(Lobe::Dielectric {
+        reflection: result.specular_colour,
+        transmission,
+        inside_ior,
+        outside_ior,
+      });
+      if ior.is_some() {
+        result.bsdf = result.bsdf.with_dispersion();
+      }
+      return result;
+    }
+    result.bsdf = result.bsdf.with_lobe(Lobe::Diffuse(result.diffuse_colour));
+    // Highlights are a GGX lobe as wide as the Phong lobe of the exponent.
+    if self.illumination_model >= 2 {
+      if let Some(exponent) = self.specular_exponent {
+        let lobe = Lobe::Glossy(result.specular_colour, roughness_from_exponent(exponent));
+        result.bsdf = result.bsdf.with_lobe(lobe);
+      }
     }
     return result;
   }
@@ -300,6 +393,21 @@ impl WFMaterial {
     let (emission, f3) = load_surface(scene, mat.ke, &mat.map_ke, f2);
     let (bump_map, _) = load_bumpmap(scene, &mat.map_bump, f3);
 
+    // One minus Tf is taken as the fraction of light left after a unit of
+    // distance through the material, so that thick glass and water tint
+    // more than thin. Fully clear and fully opaque materials need no medium.
+    let media = match (colour_from_slice(mat.tf), mat.d) {
+      (Some(transparent_colour), None) => {
+        let transmission = Colour::RGB(1.0, 1.0, 1.0) - transparent_colour;
+        if transmission.max_value() > 0.0 && transparent_colour.max_value() > 0.0 {
+          Some(scene.add_media(Box::new(AbsorbingMedia::from_transmission(transmission))))
+        } else {
+          None
+        }
+      }
+      _ => None,
+    };
+
     WFMaterial {
       ambient_colour: ambient,
       diffuse_colour: diffuse,
@@ -316,6 +424,296 @@ impl WFMaterial {
       },
       sharpness: Some(1.),
       illumination_model: mat.illum.unwrap_or(4) as usize,
+      media,
+    }
+  }
+}
+
+// The physically based extensions to MTL, from
+// http://exocortex.com/blog/extending_wavefront_mtl_to_support_pbr, which
+// the obj crate doesn't read.
+#[derive(Debug, Clone, Default)]
+struct PbrExtensions {
+  roughness: Option<f32>,           // Pr
+  metallic: Option<f32>,            // Pm
+  sheen: Option<f32>,               // Ps
+  clearcoat: Option<f32>,           // Pc
+  clearcoat_roughness: Option<f32>, // Pcr
+  map_roughness: Option<String>,
+  map_metallic: Option<String>,
+  map_sheen: Option<String>,
+  map_clearcoat: Option<String>,
+  map_clearcoat_roughness: Option<String>,
+  map_transmission: Option<String>, // map_Tf
+}
+
+// Reads the extensions of every material that has any from the MTL files,
+// by material name.
+fn load_pbr_extensions(directory: &Path, libraries: &[String]) -> HashMap<String, PbrExtensions> {
+  let mut result: HashMap<String, PbrExtensions> = HashMap::new();
+  for library in libraries {
+    let path = directory.join(library.replace("\\", "/"));
+    let text = match std::fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(msg) => panic!("Failed to read {:?} with error: {}", path, msg),
+    };
+    let mut material: Option<String> = None;
+    for line in text.lines() {
+      let mut words = line.split_whitespace();
+      let key = match words.next() {
+        Some(key) => key,
+        None => continue,
+      };
+      let rest = words.collect::<Vec<&str>>().join(" ");
+      if key == "newmtl" {
+        material = Some(rest);
+        continue;
+      }
+      let name = match &material {
+        Some(name) => name.clone(),
+        None => continue,
+      };
+      let value = rest.parse::<f32>().ok();
+      let map = Some(rest.clone());
+      let extensions = || result.entry(name).or_insert_with(PbrExtensions::default);
+      match key {
+        "Pr" => extensions().roughness = value,
+        "Pm" => extensions().metallic = value,
+        "Ps" => extensions().sheen = value,
+        "Pc" => extensions().clearcoat = value,
+        "Pcr" => extensions().clearcoat_roughness = value,
+        "map_Pr" => extensions().map_roughness = map,
+        "map_Pm" => extensions().map_metallic = map,
+        "map_Ps" => extensions().map_sheen = map,
+        "map_Pc" => extensions().map_clearcoat = map,
+        "map_Pcr" => extensions().map_clearcoat_roughness = map,
+        "map_Tf" => extensions().map_transmission = map,
+        _ => {}
+      }
+    }
+  }
+  return result;
+}
+
+fn average(value: Option<[f32; 3]>) -> Option<f32> {
+  return value.map(|[r, g, b]| (r + g + b) / 3.0);
+}
+
+fn load_scalar<F: FnMut(&mut Scene, &str, bool) -> Option<TextureIdx>>(
+  scene: &mut Scene,
+  value: Option<f32>,
+  default: f32,
+  texture: &Option<String>,
+  texture_loader: F,
+) -> (WFSurfaceProperty<f32, TextureIdx>, F) {
+  let value = value.unwrap_or(default);
+  return load_surface(scene, Some([value, value, value]), texture, texture_loader);
+}
+
+/// A Disney style principled material, for assets authored with metallic
+/// and roughness parameters. The base colour is diffuse for dielectrics and
+/// tints the reflection of metals, and transmission fades the diffuse base
+/// into smooth glass of the same colour. Clearcoat adds a second, clear,
+/// highlight over the top, and sheen a grazing glow for cloth. Every
+/// parameter can be textured when loaded from an MTL file.
+///
+/// Parameters range from 0 to 1, except for the index of refraction of the
+/// transmitted part.
+#[derive(Debug, Clone, Copy)]
+pub struct PrincipledMaterial {
+  base_colour: WFSurfaceProperty<Colour, TextureIdx>,      // Kd
+  metallic: WFSurfaceProperty<f32, TextureIdx>,            // Pm
+  roughness: WFSurfaceProperty<f32, TextureIdx>,           // Pr
+  specular: WFSurfaceProperty<f32, TextureIdx>,            // Ks
+  clearcoat: WFSurfaceProperty<f32, TextureIdx>,           // Pc
+  clearcoat_roughness: WFSurfaceProperty<f32, TextureIdx>, // Pcr
+  sheen: WFSurfaceProperty<f32, TextureIdx>,               // Ps
+  transmission: WFSurfaceProperty<f32, TextureIdx>,        // Tf
+  emissive_colour: WFSurfaceProperty<Colour, TextureIdx>,  // Ke
+  bump_map: Option<TextureIdx>,
+  index_of_refraction: f32, // Ni
+}
+
+impl PrincipledMaterial {
+  /// A rough dielectric of the given colour, with the default specular of
+  /// 0.5, which reflects 4% at normal incidence.
+  pub fn new(base_colour: Colour) -> PrincipledMaterial {
+    PrincipledMaterial {
+      base_colour: WFSurfaceProperty::Single(base_colour),
+      metallic: WFSurfaceProperty::None,
+      roughness: WFSurfaceProperty::Single(0.5),
+      specular: WFSurfaceProperty::Single(0.5),
+      clearcoat: WFSurfaceProperty::None,
+      clearcoat_roughness: WFSurfaceProperty::Single(0.03),
+      sheen: WFSurfaceProperty::None,
+      transmission: WFSurfaceProperty::None,
+      emissive_colour: WFSurfaceProperty::None,
+      bump_map: None,
+      index_of_refraction: 1.5,
+    }
+  }
+
+  pub fn with_metallic(mut self, metallic: f32) -> Self {
+    self.metallic = WFSurfaceProperty::Single(metallic);
+    return self;
+  }
+
+  pub fn with_roughness(mut self, roughness: f32) -> Self {
+    self.roughness = WFSurfaceProperty::Single(roughness);
+    return self;
+  }
+
+  pub fn with_specular(mut self, specular: f32) -> Self {
+    self.specular = WFSurfaceProperty::Single(specular);
+    return self;
+  }
+
+  pub fn with_clearcoat(mut self, clearcoat: f32, roughness: f32) -> Self {
+    self.clearcoat = WFSurfaceProperty::Single(clearcoat);
+    self.clearcoat_roughness = WFSurfaceProperty::Single(roughness);
+    return self;
+  }
+
+  pub fn with_sheen(mut self, sheen: f32) -> Self {
+    self.sheen = WFSurfaceProperty::Single(sheen);
+    return self;
+  }
+
+  pub fn with_transmission(mut self, transmission: f32, index_of_refraction: f32) -> Self {
+    self.transmission = WFSurfaceProperty::Single(transmission);
+    self.index_of_refraction = index_of_refraction;
+    return self;
+  }
+
+  // Follows Blender's reading of the extensions: Ks is the specular level
+  // and Tf the amount of transmission.
+  fn from_mtl<F: FnMut(&mut Scene, &str, bool) -> Option<TextureIdx>>(
+    scene: &mut Scene,
+    mat: &obj::Material,
+    pbr: &PbrExtensions,
+    texture_loader: F,
+  ) -> PrincipledMaterial {
+    let (base_colour, f) = load_surface(scene, mat.kd, &mat.map_kd, texture_loader);
+    let (metallic, f) = load_scalar(scene, pbr.metallic, 0.0, &pbr.map_metallic, f);
+    let (roughness, f) = load_scalar(scene, pbr.roughness, 0.5, &pbr.map_roughness, f);
+    let (specular, f) = load_scalar(scene, average(mat.ks), 0.5, &mat.map_ks, f);
+    let (clearcoat, f) = load_scalar(scene, pbr.clearcoat, 0.0, &pbr.map_clearcoat, f);
+    let (clearcoat_roughness, f) = load_scalar(scene, pbr.clearcoat_roughness, 0.03, &pbr.map_clearcoat_roughness, f);
+    let (sheen, f) = load_scalar(scene, pbr.sheen, 0.0, &pbr.map_sheen, f);
+    let (transmission, f) = load_scalar(scene, average(mat.tf), 0.0, &pbr.map_transmission, f);
+    let (emission, f) = load_surface(scene, mat.ke, &mat.map_ke, f);
+    let (bump_map, _) = load_bumpmap(scene, &mat.map_bump, f);
+
+    PrincipledMaterial {
+      base_colour,
+      metallic,
+      roughness,
+      specular,
+      clearcoat,
+      clearcoat_roughness,
+      sheen,
+      transmission,
+      emissive_colour: emission,
+      bump_map,
+      index_of_refraction: mat.ni.unwrap_or(1.5),
+    }
+  }
+}
+
+impl Material for PrincipledMaterial {
+  fn is_light(&self) -> bool {
+    match self.emissive_colour {
+      WFSurfaceProperty::None => false,
+      _ => true,
+    }
+  }
+
+  fn compute_surface_properties(&self, s: &Scene, _: &Ray, f: &Fragment) -> MaterialCollisionInfo {
+    let normal = perturb_normal(self.bump_map, f, s);
+    let parameter = |property: &WFSurfaceProperty<f32, TextureIdx>| property.raw_for_fragment(s, f).max(0.0).min(1.0);
+    let base_colour = self.base_colour.raw_for_fragment(s, f);
+    let metallic = parameter(&self.metallic);
+    let roughness = parameter(&self.roughness);
+    let clearcoat = parameter(&self.clearcoat);
+    let clearcoat_roughness = parameter(&self.clearcoat_roughness);
+    let transmission = parameter(&self.transmission);
+    let white = Colour::RGB(1.0, 1.0, 1.0);
+
+    let dielectric = 1.0 - metallic;
+    // The reflectance at normal incidence, which for dielectrics is 8% of
+    // the specular level. Schlick's approximation is linear in it, so the
+    // blend between dielectric and metal carries through the Fresnel term.
+    let dielectric_f0 = white * (0.08 * parameter(&self.specular));
+    let specular_f0 = dielectric_f0 * dielectric + base_colour * metallic;
+    let clearcoat_f0 = white * 0.04;
+    // Light reflected by the clearcoat and then the specular layer never
+    // reaches the layers below, so they only see what the Fresnel terms at
+    // the viewing angle leave behind.
+    let cos_view = f.view.normalize().dot(normal).abs();
+    let coat_transmittance = 1.0 - clearcoat * fresnel_schlick(cos_view, clearcoat_f0).max_value();
+    let base_transmittance = (1.0 - fresnel_schlick(cos_view, dielectric_f0).max_value()) * coat_transmittance;
+    let diffuse_colour = base_colour * (dielectric * (1.0 - transmission) * base_transmittance);
+    // Sheen is tinted half way towards the base colour.
+    let sheen_colour = (white + base_colour) * (0.5 * parameter(&self.sheen));
+    let emission = self.emissive_colour.option_for_fragment(s, f);
+    // The glossy lobe stands in for the reflection off the glass, which is
+    // as rough as the rest of the surface. The transmission lobe applies the
+    // exact Fresnel term itself, so only the clearcoat is taken out here.
+    let transmission_colour = base_colour * (dielectric * transmission * coat_transmittance);
+    let interior = Interior {
+      material: f.material,
+      priority: 0,
+      ior: Some(self.index_of_refraction),
+      media: None,
+    };
+    let (inside_ior, outside_ior) = f.ray_context.interface_iors(interior);
+    let transmission_lobe = if roughness > 0.0 {
+      Lobe::RoughDielectric {
+        reflection: Colour::new(),
+        transmission: transmission_colour,
+        inside_ior,
+        outside_ior,
+        alpha: roughness * roughness,
+      }
+    } else {
+      Lobe::Dielectric {
+        reflection: Colour::new(),
+        transmission: transmission_colour,
+        inside_ior,
+        outside_ior,
+      }
+    };
+
+    MaterialCollisionInfo {
+      ambient_colour: diffuse_colour,
+      diffuse_colour: base_colour,
+      specular_colour: specular_f0,
+      emissive_colour: emission.map(|colour| EmissionCoefficients {
+        ambient: 0.0,
+        diffuse: 0.0,
+        specular: 0.0,
+        blackbody: None,
+        colour: Some(colour),
+      }),
+      normal,
+      position: f.position,
+      interior: if transmission > 0.0 { Some(interior) } else { None },
+      bsdf: Bsdf::new(normal, f.true_normal)
+        .with_lobe(Lobe::Diffuse(diffuse_colour))
+        .with_lobe(Lobe::Sheen(
+          sheen_colour * (dielectric * (1.0 - transmission) * base_transmittance),
+        ))
+        .with_lobe(Lobe::SchlickGlossy {
+          f0: specular_f0,
+          weight: coat_transmittance,
+          alpha: roughness * roughness,
+        })
+        .with_lobe(transmission_lobe)
+        .with_lobe(Lobe::SchlickGlossy {
+          f0: clearcoat_f0,
+          weight: clearcoat,
+          alpha: clearcoat_roughness * clearcoat_roughness,
+        }),
     }
   }
 }
@@ -326,10 +724,26 @@ fn vecf32_to_point(v: [f32; 3]) -> Point {
 
 pub fn load_scene(settings: &SceneSettings) -> Scene {
   let mut scn = Scene::new(settings);
-  let mut obj = Obj::<Polygon<IndexTuple>>::load(&Path::new(&settings.scene_file)).unwrap();
+  load_obj(&mut scn, Path::new(&settings.scene_file), None);
+  scn.finalize();
+  return scn;
+}
+
+/// Loads the OBJ file at `path` into an existing scene. Normal and texture
+/// coordinate indices are offset past anything already in the scene, and
+/// textures are resolved relative to the OBJ file rather than the scene, so
+/// several files can be combined. If `material_override` is provided every
+/// triangle uses it instead of the materials from the MTL file.
+pub fn load_obj(scn: &mut Scene, path: &Path, material_override: Option<MaterialIdx>) {
+  let mut obj = Obj::<Polygon<IndexTuple>>::load(path).unwrap();
 
   obj.load_mtls().unwrap();
 
+  let obj_path = path.canonicalize().unwrap();
+  let obj_directory = obj_path.parent().unwrap().to_owned();
+  let normal_offset = scn.normals.len();
+  let texture_offset = scn.texture_coords.len();
+
   for [x, y, z] in obj.position.iter() {
     scn.positions.push(Vector::point(*x as f64, *y as f64, *z as f64));
   }
@@ -344,6 +758,7 @@ pub fn load_scene(settings: &SceneSettings) -> Scene {
   for [u, v] in obj.texture.iter() {
     scn.texture_coords.push(Vec2d(*u as f64, *v as f64));
   }
+  let pbr_extensions = load_pbr_extensions(&obj_directory, &obj.material_libs);
   let max_tex: usize = scn.texture_coords.len();
   let default_material = scn.default_material();
   let object_count = obj.objects.len();
@@ -370,12 +785,23 @@ pub fn load_scene(settings: &SceneSettings) -> Scene {
 
     for group_index in 0..group_count {
       let ref group = &object.groups[group_index];
-      let material_index = if let Some(ref mat) = group.material {
+      let material_index = if material_override.is_some() {
+        material_override
+      } else if let Some(ref mat) = group.material {
         let material: &obj::Material = &**mat;
-        let mat = scn.get_or_create_material(&material.name, |scene| {
-          return Some(Box::new(WFMaterial::new(scene, mat, |scene, file, need_bumpmap| {
-            scene.load_texture(file, need_bumpmap)
-          })));
+        // Different files frequently reuse the same material names, so scope them to the file.
+        let material_name = format!("{}:{}", obj_path.display(), material.name);
+        let mat = scn.get_or_create_material(&material_name, |scene| {
+          let texture_loader = |scene: &mut Scene, file: &str, need_bumpmap| {
+            let texture_path = obj_directory.join(file.replace("\\", "/"));
+            scene.load_texture(texture_path.to_str().unwrap(), need_bumpmap)
+          };
+          // Materials that use any of the PBR extensions are principled.
+          let material: Box<Material> = match pbr_extensions.get(&material.name) {
+            Some(pbr) => Box::new(PrincipledMaterial::from_mtl(scene, material, pbr, texture_loader)),
+            None => Box::new(WFMaterial::new(scene, material, texture_loader)),
+          };
+          return Some(material);
         });
         Some(mat.0)
       } else {
@@ -388,6 +814,7 @@ pub fn load_scene(settings: &SceneSettings) -> Scene {
         .vertex(|IndexTuple(p, t, n)| {
           let n_idx: Option<NormalIdx> = match n {
             Some(idx) => {
+              let idx = idx + normal_offset;
               let normal = scn.get_normal(idx as u32);
               if normal.dot(normal) != 0.0 {
                 Some(NormalIdx(idx as u32))
@@ -399,6 +826,7 @@ pub fn load_scene(settings: &SceneSettings) -> Scene {
           };
           let t_idx: Option<TextureCoordinateIdx> = match t {
             Some(idx) => {
+              let idx = idx + texture_offset;
               assert!(idx < max_tex);
               Some(TextureCoordinateIdx(idx))
             }
@@ -439,6 +867,80 @@ pub fn load_scene(settings: &SceneSettings) -> Scene {
     let new_object = Box::new(Mesh::new(&object_triangles));
     scn.add_object(new_object);
   }
-  scn.finalize();
-  return scn;
+}
+
+#[test]
+fn test_load_pbr_extensions() {
+  let directory = std::env::temp_dir();
+  let library = "test_load_pbr_extensions.mtl".to_string();
+  let text = "newmtl plain\nKd 0.5 0.5 0.5\n\nnewmtl metal\nKd 1 0.8 0.3\nPm 1\nPr 0.25\nmap_Pr rough.png\n";
+  std::fs::write(directory.join(&library), text).unwrap();
+  let extensions = load_pbr_extensions(&directory, &[library]);
+  // Only materials that use the extensions are principled.
+  assert_eq!(extensions.len(), 1);
+  let metal = &extensions["metal"];
+  assert_eq!(metal.metallic, Some(1.0));
+  assert_eq!(metal.roughness, Some(0.25));
+  assert_eq!(metal.map_roughness, Some("rough.png".to_string()));
+  assert_eq!(metal.clearcoat, None);
+}
+
+#[test]
+fn test_principled_energy() {
+  let mut settings = SceneSettings::new();
+  settings.scene_file = std::env::temp_dir().to_string_lossy().to_string();
+  let scene = Scene::new(&settings);
+  let white = Colour::RGB(1.0, 1.0, 1.0);
+  let normal = Vector::vector(0.0, 0.0, 1.0);
+  let materials = [
+    PrincipledMaterial::new(white),
+    PrincipledMaterial::new(white).with_specular(1.0).with_roughness(0.5),
+    PrincipledMaterial::new(white).with_clearcoat(1.0, 0.1).with_sheen(1.0),
+    PrincipledMaterial::new(white)
+      .with_metallic(0.5)
+      .with_roughness(0.2)
+      .with_clearcoat(1.0, 0.0),
+    PrincipledMaterial::new(white)
+      .with_transmission(1.0, 1.5)
+      .with_clearcoat(1.0, 0.3),
+  ];
+  // In a white furnace the light a white surface reflects, averaged over the
+  // directions it arrives from, can't exceed the light reaching it.
+  let count = 20000;
+  for material in &materials {
+    for cos_view in [1.0f64, 0.7, 0.3, 0.05] {
+      let wo = Vector::vector((1.0 - cos_view * cos_view).sqrt(), 0.0, cos_view);
+      let fragment = Fragment {
+        position: Vector::point(0.0, 0.0, 0.0),
+        normal,
+        true_normal: normal,
+        uv: Vec2d(0.0, 0.0),
+        dpdu: Vector::vector(1.0, 0.0, 0.0),
+        dpdv: Vector::vector(0.0, 1.0, 0.0),
+        view: -wo,
+        material: MaterialIdx(0),
+        ray_context: crate::ray::RayContext::new(),
+      };
+      let ray = Ray::new(fragment.position - fragment.view, fragment.view, None);
+      let bsdf = material.compute_surface_properties(&scene, &ray, &fragment).bsdf;
+      let mut albedo = 0.0;
+      for index in 0..count {
+        let u = (
+          (index as f32 + 0.5) / count as f32,
+          ((index * 7919) % count) as f32 / count as f32,
+        );
+        if let Some(sample) = bsdf.sample(wo, u) {
+          albedo += sample.weight.max_value();
+        }
+      }
+      albedo /= count as f32;
+      assert!(
+        albedo <= 1.01,
+        "albedo {} of {:?} at cosine {}",
+        albedo,
+        material,
+        cos_view
+      );
+    }
+  }
 }